@@ -2,6 +2,7 @@ extern crate capnpc;
 
 fn main() {
     capnpc::CompilerCommand::new()
+        .src_prefix("protos")
         .file("protos/predictor.capnp")
         .run()
         .unwrap();
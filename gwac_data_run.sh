@@ -23,7 +23,7 @@ function safe_call {
     fi
 
     # fragment should cut time to run by x
-    RUST_BACKTRACE=1 cargo run $opt -- \
+    RUST_BACKTRACE=1 cargo run $opt -- run \
                   --input ${DATA} \
                   --templates-file ${TEMPS} \
                   --noise .06 \
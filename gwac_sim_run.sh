@@ -16,7 +16,7 @@ function safe_call {
     fi
 
     # fragment should cut time to run by x
-    RUST_BACKTRACE=0 cargo run $opt --\
+    RUST_BACKTRACE=0 cargo run $opt -- live \
                   --gwac-file $3 \
                   --templates-file ${TEMPS} \
                   --noise .06 \
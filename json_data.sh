@@ -17,7 +17,7 @@ function safe_call {
         opt="--$1"
     fi
 
-    RUST_BACKTRACE=1 cargo run $opt --\
+    RUST_BACKTRACE=1 cargo run $opt -- run \
                   --input $3 \
                   --templates-file ${TEMPS} \
                   --noise .06 \
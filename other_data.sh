@@ -13,7 +13,7 @@ function safe_call {
         opt="--$1"
     fi
 
-    RUST_BACKTRACE=1 cargo run $opt --\
+    RUST_BACKTRACE=1 cargo run $opt -- run \
                   --input /data/star_extra_data/star_dataset/data/threshold \
                   --templates-file ${DATA} \
                   --noise .06 \
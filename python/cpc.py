@@ -5,24 +5,27 @@ log.basicConfig(level=log.DEBUG)
 
 import capnp
 capnp.remove_import_hook()
-predictor = capnp.load('../protos/predictor.capnp')
 
 import os
-import zmq
+import sys
 import time
-import lstm
 import socket
 from inspect import signature
 import importlib
 from pathlib import Path
 
+# NOTE started by the detector (src/python.rs) as
+#      cpc.py <plugin directory> <unix socket path>
+SCHEMA_PATH = Path(__file__).resolve().parent.parent / 'protos' / 'predictor.capnp'
+predictor = capnp.load(str(SCHEMA_PATH))
+
 def load_plugin(mod_path):
     # should we make PredictorWrapper manditory as a well of checking
     # if people implement the right predict function ??? NOTE
     def build_predictor_signature(**kwargs):
         pass
 
-    module = importlib.import_module(str(mod_path))
+    module = importlib.import_module(Path(mod_path).stem)
     plugin = module.PredictorPlugin
     plug_name = plugin.plugin_name
     bp_func = plugin.build_predictor
@@ -37,20 +40,24 @@ def scan_for_plugins(plugins_path):
     plugins_path = Path(plugins_path)
 
     plugins = []
-    candidates = set(str(item) for item in plugins_path.iterdir())
+    # NOTE plugins are the modules and packages of the directory
+    sys.path.insert(0, str(plugins_path.resolve()))
+    candidates = set(str(item) for item in plugins_path.iterdir()
+                     if item.suffix == '.py' or (item / '__init__.py').exists())
     for item in candidates:
         try:
             log.debug("Checking file (for plugin)... {}".format(item))
             plug = load_plugin(item)
             plugins.append(plug)
-        except:
-            pass
+        except Exception as e:
+            log.debug("Not a plugin... {}: {}".format(item, e))
     return dict(plugins)
 
 def map_to_dict(mapping):
     return { ent.key: ent.val for ent in mapping.entries }
 
-PLUGINS = scan_for_plugins('.')
+PLUGIN_DIR, SOCKET_PATH = sys.argv[1], sys.argv[2]
+PLUGINS = scan_for_plugins(PLUGIN_DIR)
 
 @dc.dataclass
 class PersistantState:
@@ -81,38 +88,32 @@ class NullPredictor(predictor.Predictor.Server):
         log.debug('Called Predictor.init with: '\
                   + str((str(predictor), str(args), str(kwargs))))
 
-        ps.init_number += 1
-        log.info('Predictor.init init_number: {}'.format(ps.init_number))
+        # NOTE unknown predictors are an error for the caller, the
+        #      detector asks each plugin directory in turn
+        if predictor not in PLUGINS:
+            raise KeyError('Unknown predictor: {}'.format(predictor))
 
+        # NOTE only count predictors that were built, the detector replays
+        #      the inits after a restart expecting the same uids
         args = map_to_dict(args)
-        ps.state_map[ps.init_number] = \
-            PLUGINS[predictor].build_predictor(**args)
+        built = PLUGINS[predictor].build_predictor(**args)
+        ps.init_number += 1
+        ps.state_map[ps.init_number] = built
+        log.info('Predictor.init init_number: {}'.format(ps.init_number))
         log.debug('Predictor.init state_map len: {}'.format(
             len(ps.state_map)))
 
         res = ps.init_number
         return res
 
-def restore(ref):
-    assert ref.as_text() == 'predictor'
-    return NullPredictor()
-
-#zmq_context = zmq.Context()
-#socket = zmq_context.socket(zmq.REP)
-#socket.bind('ipc://test-server')
-#msg = socket.recv(0)
-
-os.unlink('testing2')
+# NOTE the detector is the only client, once it disconnects
+#      (done or restarting the plugin) the host exits
+if os.path.exists(SOCKET_PATH):
+    os.unlink(SOCKET_PATH)
 s = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
-s.bind('testing2')
-s.listen(1000)
+s.bind(SOCKET_PATH)
+s.listen(1)
 c, _ = s.accept()
 
-s_addr = '127.0.0.1:12345'
-s_addr = c
-server = capnp.TwoPartyServer(s_addr, restore)
-
-#server.on_disconnect().wait()
-#server.run_forever()
-
-capnp.lib.capnp.wait_forever()
+server = capnp.TwoPartyServer(c, bootstrap=NullPredictor())
+server.on_disconnect().wait()
@@ -1,3 +1,4 @@
+import sys
 import time
 import zmq
 import socket
@@ -12,11 +13,12 @@ predictor = capnp.load('../protos/predictor.capnp')
 #socket.send(b'test')
 
 s = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
-s.connect('testing2')
+# NOTE start the host with: python3 cpc.py . testing2
+s.connect(sys.argv[1] if len(sys.argv) > 1 else 'testing2')
 sock = '127.0.0.1:12345'
 sock = s
 client = capnp.TwoPartyClient(sock)
-pred = client.ez_restore('predictor').cast_as(predictor.Predictor)
+pred = client.bootstrap().cast_as(predictor.Predictor)
 
 for i in range(0, 1):
     rq = pred.init_request()
@@ -6,7 +6,7 @@ source src_env.sh
 TEMPS="data/templates__nfd_def.toml"
 DATA="~/Data/preflare2.db"
 CMD=$(cat <<EOF
-./target/release/match_filter
+./target/release/match_filter run
           --input ${DATA} --templates-file ${TEMPS} --noise .06 --rho 4.0
           --window-length $1 --skip-delta 15 --fragment 1
           --tartan-test true
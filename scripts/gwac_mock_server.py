@@ -1,21 +1,55 @@
 import os
 import click
 import time
+import socket
 import toml
 import msgpack
 import sqlite3 as sq3
 from pathlib import Path
 
+# NOTE seconds between the frames of the GWAC cameras
+SAMPLE_RATE = 15.0
+
 class Star:
     def __init__(self, uid, samples):
         self.uid = uid
         self.samples = samples
 
 class TX:
+    # NOTE filename follows the detector --gwac-file sources
+    #      unix://path, tcp://host:port or a fifo path (fifo: prefix optional)
     def __init__(self, filename, nfd_flip):
-        os.mkfifo(filename)
-        self.pipe = open(filename, 'w')
-        self._filename = filename
+        self._filename = None
+        self._sock = None
+        if filename.startswith('unix://'):
+            path = filename[len('unix://'):]
+            if os.path.exists(path):
+                os.unlink(path)
+            self._sock = socket.socket(socket.AF_UNIX, socket.SOCK_STREAM)
+            self._sock.bind(path)
+            self._filename = path
+        elif filename.startswith('tcp://'):
+            host, port = filename[len('tcp://'):].rsplit(':', 1)
+            self._sock = socket.socket(socket.AF_INET, socket.SOCK_STREAM)
+            self._sock.setsockopt(socket.SOL_SOCKET, socket.SO_REUSEADDR, 1)
+            self._sock.bind((host, int(port)))
+        else:
+            if filename.startswith('fifo:'):
+                filename = filename[len('fifo:'):]
+            os.mkfifo(filename)
+            self._filename = filename
+
+        if self._sock is not None:
+            self._sock.listen(1)
+            print('Waiting for detector to connect...')
+            conn, _ = self._sock.accept()
+            self.pipe = conn.makefile('w')
+        else:
+            self.pipe = open(filename, 'w')
+
+        # NOTE frames are sent as fast as possible but timed as if they
+        #      were taken every SAMPLE_RATE seconds starting now
+        self._start_time = time.time()
 
         # -1 is because NFD GWAC data is upside down
         if nfd_flip:
@@ -28,15 +62,21 @@ class TX:
     def __enter__(self):
         return self
     def __exit__(self, exc_type, exc_value, traceback):
-        os.unlink(self._filename)
+        self.pipe.close()
+        if self._sock is not None:
+            self._sock.close()
+        if self._filename is not None:
+            os.unlink(self._filename)
     def start_frame(self):
         self.pipe.write("start\n")
-    def file_name(self):
+    def sample_time(self, sample_point):
+        return self._start_time + sample_point * SAMPLE_RATE
+    def file_name(self, sample_point):
         #self.pipe.write(str(time.process_time())+'_gwac.gwac.star\n')
         # NOTE unsure what first three for but last
         #      three are for H:M:S time code (from what I understand of the code)
-        cur_time = time.gmtime()
-        self.pipe.write('{}_{}_{}_{}_{}_{}'.format(
+        cur_time = time.gmtime(self.sample_time(sample_point))
+        self.pipe.write('{}_{}_{}_{}_{}_{}\n'.format(
             'gwac', 'gwac', 'gwac', cur_time.tm_hour, cur_time.tm_min, cur_time.tm_sec))
     def end_frame(self):
         self.pipe.write("end\n")
@@ -49,8 +89,7 @@ class TX:
             'zone_defense', # zone {string}
             star.uid, # star id {string}
             str(float(star.samples[sample_point]) * self._scale), # mag {float}
-            str(sample_point), # NOTE changed to logical-time for testing purposes (not real-time)
-            #str(sample_point*15.0), # timestamp {float} [15 second sampling]
+            '{:.3f}'.format(self.sample_time(sample_point)), # timestamp {float} [epoch seconds]
             str(59.6), # elliptticity {float}
             'rnd_ccd', # ccd_num {string}
         ]) + '\n'
@@ -58,8 +97,7 @@ class TX:
         self.pipe.write(msg)
 
 @click.command()
-@click.argument('gwac_filename',
-                type=click.Path(dir_okay=True, writable=True, readable=True))
+@click.argument('gwac_filename', type=str)
 @click.argument('data_dir', type=click.Path(readable=True))
 @click.option('--skip-confirm', type=bool, default=False)
 @click.option('--start-time', type=int, default=-1) # for nfd (or other history using methods)
@@ -129,7 +167,7 @@ def main(gwac_filename, data_dir, skip_confirm, **kwargs):
     with TX(gwac_filename, nfd_flip) as tx:
         for i in range(start_tm, max_len):
             tx.start_frame()
-            tx.file_name()
+            tx.file_name(i)
             for star in stars:
                 if i < len(star.samples):
                     tx.star(star, i)
@@ -0,0 +1,176 @@
+use crate::star::{StarModel, StarModelErr};
+use std::collections::HashMap;
+use std::fs;
+
+/// ARIMA(p, d, q) baseline model of a star.
+///
+/// Read from the star's `arima_model_file` (TOML), ex. an ARIMA(1, 1, 1)
+///
+/// ```toml
+/// constant = 0.0
+/// ar = [0.4]
+/// d = 1
+/// ma = [-0.2]
+/// ```
+#[derive(Debug, Default, Deserialize)]
+pub struct ArimaModel {
+    #[serde(default)]
+    pub constant: f32,
+    /// AR coefficients (lag 1 first)
+    #[serde(default)]
+    pub ar: Vec<f32>,
+    /// times the samples are differenced
+    #[serde(default)]
+    pub d: usize,
+    /// MA coefficients (lag 1 first)
+    #[serde(default)]
+    pub ma: Vec<f32>,
+}
+
+impl ArimaModel {
+    pub fn from_file(model_file: &str) -> ArimaModel {
+        let contents = fs::read_to_string(model_file)
+            .unwrap_or_else(|_| panic!("Failed to read ARIMA model file: {}", model_file));
+
+        toml::from_str(&contents)
+            .unwrap_or_else(|_| panic!("Failed to parse ARIMA model file: {}", model_file))
+    }
+
+    /// One step ahead prediction of every sample from the samples before it.
+    ///
+    /// Samples without enough history to predict (the first d + max(p, q))
+    /// are "predicted" as themselves, so their residual is zero.
+    pub fn one_step(&self, samples: &[f32]) -> Vec<f32> {
+        let diffed = difference(samples, self.d);
+        let warm_up = std::cmp::max(self.ar.len(), self.ma.len());
+        let mut preds = samples.to_vec();
+        let mut errs = vec![0.0; diffed.len()];
+
+        for k in 0..diffed.len() {
+            let mut pred = self.constant;
+            for (i, phi) in self.ar.iter().enumerate().filter(|(i, _)| *i < k) {
+                pred += phi * diffed[k - 1 - i];
+            }
+            for (j, theta) in self.ma.iter().enumerate().filter(|(j, _)| *j < k) {
+                pred += theta * errs[k - 1 - j];
+            }
+            errs[k] = diffed[k] - pred;
+
+            if k >= warm_up {
+                let t = k + self.d;
+                // NOTE undo the differencing with the observed samples before t
+                preds[t] = (1..=self.d).fold(pred, |pred, lag| {
+                    let sign = if lag % 2 == 0 { 1.0 } else { -1.0 };
+                    pred - sign * binomial(self.d, lag) * samples[t - lag]
+                });
+            }
+        }
+
+        preds
+    }
+}
+
+impl StarModel for ArimaModel {
+    fn init(&self, _args: HashMap<String, String>) -> StarModelErr {
+        Ok(())
+    }
+
+    // NOTE predicts the sample after the (first) look back, None if the
+    //      look back is shorter than the warm up (d + max(p, q))
+    fn predict(&self, look_backs: Vec<Vec<f32>>, _times: Vec<f32>) -> Option<f32> {
+        let mut samples = look_backs.into_iter().next()?;
+        let warm_up = self.d + std::cmp::max(self.ar.len(), self.ma.len());
+        if samples.len() < warm_up {
+            return None;
+        }
+        // NOTE a prediction never depends on its own sample
+        samples.push(0.0);
+
+        self.one_step(&samples).last().copied()
+    }
+
+    fn baseline(&self, window: &[f32]) -> Option<Vec<f32>> {
+        Some(self.one_step(window))
+    }
+}
+
+fn difference(samples: &[f32], d: usize) -> Vec<f32> {
+    (0..d).fold(samples.to_vec(), |diffed, _| {
+        diffed.windows(2).map(|pair| pair[1] - pair[0]).collect()
+    })
+}
+
+fn binomial(n: usize, k: usize) -> f32 {
+    (0..k).fold(1.0, |acc, i| acc * (n - i) as f32 / (i + 1) as f32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ar() {
+        let model = ArimaModel {
+            ar: vec![0.5],
+            ..Default::default()
+        };
+
+        assert_eq!(model.one_step(&[2.0, 4.0, 6.0]), vec![2.0, 1.0, 2.0]);
+        assert_abs_diff_eq!(model.predict(vec![vec![2.0, 4.0, 6.0]], vec![]).unwrap(), 3.0);
+    }
+
+    #[test]
+    fn test_predict_warm_up() {
+        let model = ArimaModel {
+            ar: vec![0.5, 0.25],
+            d: 1,
+            ..Default::default()
+        };
+
+        assert_eq!(model.predict(vec![vec![1.0, 2.0]], vec![]), None);
+        assert_eq!(model.predict(vec![], vec![]), None);
+        // NOTE diffed look back [1.0, 2.0] predicts 0.5 * 2.0 + 0.25 * 1.0
+        assert_abs_diff_eq!(
+            model.predict(vec![vec![1.0, 2.0, 4.0]], vec![]).unwrap(),
+            5.25
+        );
+    }
+
+    #[test]
+    fn test_ma() {
+        let model = ArimaModel {
+            ma: vec![0.5],
+            ..Default::default()
+        };
+
+        assert_eq!(model.one_step(&[1.0, 2.0, 3.0]), vec![1.0, 0.5, 0.75]);
+    }
+
+    #[test]
+    fn test_differencing() {
+        // NOTE a random walk predicts the last sample
+        let model = ArimaModel {
+            d: 1,
+            ..Default::default()
+        };
+        assert_eq!(model.one_step(&[1.0, 3.0, 6.0]), vec![1.0, 1.0, 3.0]);
+
+        // NOTE twice differenced a line has no residual
+        let model = ArimaModel {
+            d: 2,
+            ..Default::default()
+        };
+        assert_eq!(model.one_step(&[1.0, 2.0, 3.0, 4.0]), vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(model.one_step(&[1.0]), vec![1.0]);
+    }
+
+    #[test]
+    fn test_from_toml() {
+        let model: ArimaModel = toml::from_str("ar = [0.4]\nd = 1\nma = [-0.2]").unwrap();
+
+        assert_eq!(model.ar, vec![0.4]);
+        assert_eq!(model.d, 1);
+        assert_eq!(model.ma, vec![-0.2]);
+        assert_abs_diff_eq!(model.constant, 0.0);
+    }
+}
@@ -1,75 +1,49 @@
-use tokio::sync::mpsc::{channel, Receiver, Sender};
-
-use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::sync::watch;
+
+/// Resolves once the shutdown signal is set (or its sender is gone).
+pub async fn shutdown_signal(mut sd_rx: watch::Receiver<bool>) {
+    loop {
+        if *sd_rx.get_ref() {
+            return;
+        }
 
-enum Side {
-    SideA,
-    SideB,
+        match sd_rx.recv().await {
+            Some(true) | None => return,
+            Some(false) => (),
+        }
+    }
 }
 
-pub struct TwinBarrier {
-    tx_go: RefCell<Sender<bool>>,
-    rx_go: RefCell<Receiver<bool>>,
-    side: Side,
+/// Future that gives up (with `None`) when the program is shutting down.
+///
+/// Used around every wait of the pipeline so Ctrl-C never leaves
+/// a task hanging on a peer that already stopped.
+pub struct Cancellable<F: Future> {
+    fut: Pin<Box<F>>,
+    shutdown: Pin<Box<dyn Future<Output = ()> + Send>>,
 }
 
-impl TwinBarrier {
-    async fn tx_go(&self) -> Result<(), &'static str> {
-        match self.tx_go.borrow_mut().send(true).await {
-            Ok(_) => Ok(()),
-            _ => Err("Twin barrier locking down."),
-        }
-    }
+impl<F: Future> Future for Cancellable<F> {
+    type Output = Option<F::Output>;
 
-    async fn rx_go(&self) -> Result<(), &'static str> {
-        match self.rx_go.borrow_mut().recv().await {
-            Some(_) => Ok(()),
-            None => Err("Twin barrier locking down."),
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if let Poll::Ready(()) = self.shutdown.as_mut().poll(cx) {
+            return Poll::Ready(None);
         }
-    }
 
-    // NOTE this will serve as explanation of other barriers
-    // -- order is really important
-    //
-    // 1) block waiting for main to send
-    // 2) main blocks waiting for tick to send
-    // 3) tick sends after unblock, freeing main
-    // -- thus we have a barrier
-    //
-    // oxo---
-    // \/     o = send, x = block, - = unblocked
-    // xo----
-    pub async fn wait(&self) -> Result<(), &'static str> {
-        match self.side {
-            Side::SideA => {
-                self.tx_go().await?;
-                self.rx_go().await?;
-            }
-            Side::SideB => {
-                self.rx_go().await?;
-                self.tx_go().await?;
-            }
-        }
-
-        Ok(())
+        self.fut.as_mut().poll(cx).map(Some)
     }
 }
 
-pub fn twin_barrier() -> (TwinBarrier, TwinBarrier) {
-    let (tx_a, rx_b) = channel(1);
-    let (tx_b, rx_a) = channel(1);
-
-    let side_a = TwinBarrier {
-        tx_go: RefCell::new(tx_a),
-        rx_go: RefCell::new(rx_a),
-        side: Side::SideA,
-    };
-
-    let side_b = TwinBarrier {
-        tx_go: RefCell::new(tx_b),
-        rx_go: RefCell::new(rx_b),
-        side: Side::SideB,
-    };
-
-    (side_a, side_b)
+pub fn with_shutdown<F: Future>(
+    fut: F,
+    sd_rx: watch::Receiver<bool>,
+) -> Cancellable<F> {
+    Cancellable {
+        fut: Box::pin(fut),
+        shutdown: Box::pin(shutdown_signal(sd_rx)),
+    }
 }
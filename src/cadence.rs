@@ -0,0 +1,133 @@
+/// Seconds between samples of the GWAC cameras, used when
+/// the data does not carry its own timing.
+pub const GWAC_SAMPLE_RATE: i32 = 15;
+
+/// Relative difference allowed between a star and the template bank cadence.
+const CADENCE_TOLERANCE: f32 = 0.1;
+
+arg_enum! {
+    /// What to do with stars sampled at a different cadence than the templates.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum CadenceMismatch {
+        // do not filter the star (offline runs stop)
+        Refuse,
+        // linearly interpolate the star onto the template cadence
+        Resample,
+    }
+}
+
+/// Estimates the cadence from sample times (median time step).
+///
+/// Steps that do not move forward (repeated or out of order times) are ignored.
+pub fn estimate_cadence(times: &[f32]) -> Option<f32> {
+    let mut steps = times
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .filter(|step| *step > 0.0)
+        .collect::<Vec<f32>>();
+
+    if steps.is_empty() {
+        return None;
+    }
+
+    steps.sort_unstable_by(|a, b| a.partial_cmp(b).expect("Invalid sample time."));
+
+    Some(steps[steps.len() / 2])
+}
+
+pub fn cadences_match(star_rate: f32, template_rate: f32) -> bool {
+    (star_rate - template_rate).abs() <= CADENCE_TOLERANCE * template_rate
+}
+
+/// Resamples samples taken every `from` seconds onto a grid every `to` seconds.
+pub fn resample(samples: &[f32], from: f32, to: f32) -> Vec<f32> {
+    let mut resampler = Resampler::new(to);
+
+    samples
+        .iter()
+        .enumerate()
+        .flat_map(|(i, val)| resampler.push(i as f32 * from, *val))
+        .collect()
+}
+
+/// Linear interpolation of a (live) sample stream onto a fixed cadence grid
+/// starting at the first sample.
+pub struct Resampler {
+    cadence: f32,
+    last: Option<(f32, f32)>,
+    next_time: f32,
+}
+
+impl Resampler {
+    pub fn new(cadence: f32) -> Resampler {
+        Resampler {
+            cadence,
+            last: None,
+            next_time: 0.0,
+        }
+    }
+
+    /// Returns the grid samples up to (and including) `time`.
+    pub fn push(&mut self, time: f32, val: f32) -> Vec<f32> {
+        let (last_time, last_val) = match self.last {
+            Some(last) => last,
+            None => {
+                self.last = Some((time, val));
+                self.next_time = time + self.cadence;
+                return vec![val];
+            }
+        };
+
+        // NOTE out of order samples can not be interpolated
+        if time <= last_time {
+            return Vec::new();
+        }
+
+        let mut grid = Vec::new();
+        while self.next_time <= time {
+            let frac = (self.next_time - last_time) / (time - last_time);
+            grid.push(last_val + frac * (val - last_val));
+            self.next_time += self.cadence;
+        }
+        self.last = Some((time, val));
+
+        grid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate_cadence() {
+        assert_eq!(estimate_cadence(&[0.0]), None);
+        assert_eq!(estimate_cadence(&[0.0, 15.0, 30.0, 30.0, 46.0, 60.0]), Some(15.0));
+        assert_eq!(estimate_cadence(&[3.0, 3.0]), None);
+    }
+
+    #[test]
+    fn test_cadences_match() {
+        assert!(cadences_match(15.5, 15.0));
+        assert!(!cadences_match(10.0, 15.0));
+    }
+
+    #[test]
+    fn test_resample() {
+        let samples = (0..7).map(|i| i as f32).collect::<Vec<f32>>();
+
+        // NOTE a line stays a line
+        assert_eq!(resample(&samples, 10.0, 15.0), vec![0.0, 1.5, 3.0, 4.5, 6.0]);
+        assert_eq!(resample(&samples, 10.0, 5.0).len(), 13);
+    }
+
+    #[test]
+    fn test_resampler_out_of_order() {
+        let mut resampler = Resampler::new(15.0);
+
+        assert_eq!(resampler.push(0.0, 1.0), vec![1.0]);
+        assert!(resampler.push(10.0, 2.0).is_empty());
+        assert!(resampler.push(5.0, 9.0).is_empty());
+        assert_eq!(resampler.push(20.0, 3.0), vec![2.5]);
+    }
+}
@@ -1,23 +1,34 @@
+use crate::cadence::{cadences_match, resample, CadenceMismatch};
+use crate::convert::{self, StarFormat};
 use crate::dat_star;
 use crate::filter_utils::WindowFunc;
-use crate::gwac_reader::GWACReader;
+use crate::frame_queue::BufferPolicy;
+use crate::gwac_reader::GWACReaders;
 use crate::json_star;
+use crate::log;
+use crate::mf_config::{self, ArgDefaults, MFConfig};
+use crate::python;
+use crate::run_file::{Run, RunFile};
 use crate::star::*;
-use crate::sw_star::SWStar;
+use crate::sw_star::{window_scales, MissingFramePolicy, SWStar};
 use crate::template::*;
 use crate::tester::*;
 use crate::detector_utils as DU;
 use crate::toml_star;
 use crate::sqlite_stars;
-use clap::{App, Arg};
+use crate::veto;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::str::FromStr;
 use std::path::Path;
+use std::sync::Arc;
 
 pub struct RunInfo {
-    pub templates: Templates,
+    // NOTE shared by the runs of an experiment (see DataCache)
+    pub templates: Arc<Templates>,
     pub stars: Vec<SWStar>,
-    pub gwac_reader: Option<GWACReader>,
+    pub gwac_reader: Option<GWACReaders>,
     // [ ] TODO used for noise
     //  - should actually apply noise
     //    in generation of star data
@@ -26,9 +37,139 @@ pub struct RunInfo {
     pub log_opts: LogOpts,
     pub tester: Box<dyn Tester>,
     pub detector_trigger: Box<dyn DU::DetectorTrigger>,
+    pub veto: Box<dyn veto::Veto>,
+    /// star database the results are written back to (see --write-results)
+    pub results_db: Option<String>,
 }
 
+/// Parsed command line, the subcommand and the global configuration.
+pub struct Cli {
+    pub mf_config: MFConfig,
+    pub command: Command,
+}
+
+pub enum Command {
+    /// run, live and sweep
+    Detect(RunPlan),
+    InspectTemplates(InspectTemplatesOpts),
+    InspectStars(InspectStarsOpts),
+    PruneTemplates(PruneTemplatesOpts),
+    Convert(ConvertOpts),
+}
+
+/// Where the stars of a detector run come from.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RunMode {
+    /// star files (input)
+    Offline,
+    /// GWAC frames (gwac_file)
+    Live,
+}
+
+/// Runs of an invocation, several for a run file matrix (in order).
+pub struct RunPlan {
+    arg_matches: ArgMatches<'static>,
+    pub mf_config: MFConfig,
+    pub mode: RunMode,
+    pub runs: Vec<Run>,
+    /// Some for the sweep subcommand
+    pub experiment: Option<ExperimentOpts>,
+}
+
+pub struct ExperimentOpts {
+    /// aggregated CSV of the runs
+    pub output: String,
+}
+
+pub struct InspectTemplatesOpts {
+    pub templates_file: String,
+    pub template_group_sz: usize,
+    pub dc_norm: DCNorm,
+    /// star group size the device memory is estimated for
+    pub star_group_sz: usize,
+    /// template pairs matching at least this are near-duplicates
+    pub duplicate_match: f32,
+}
+
+pub struct PruneTemplatesOpts {
+    pub templates_file: String,
+    pub template_group_sz: usize,
+    pub dc_norm: DCNorm,
+    /// every template matches a kept one by at least this
+    pub min_match: f32,
+    /// pruned template TOML file
+    pub output: String,
+    /// reports the rank of the SVD-reduced basis keeping this energy
+    pub basis_energy: Option<f32>,
+}
+
+pub struct InspectStarsOpts {
+    pub input_dirs: Vec<String>,
+    pub selection: StarSelection,
+}
+
+pub struct ConvertOpts {
+    pub input_dirs: Vec<String>,
+    /// directory (or database) the converted stars are written to
+    pub output: String,
+    pub format: StarFormat,
+    pub selection: StarSelection,
+}
+
+/// Stars of the inputs a command reads (--where, --limit, --ids,
+/// --ids-file, --sample and --seed), the default selects every star.
+///
+/// NOTE --where and --sample only select from SQLite star databases
+#[derive(Clone, Debug, Default)]
+pub struct StarSelection {
+    /// SQL condition on the columns of the stars table (see sqlite_stars)
+    pub where_clause: Option<String>,
+    /// only the stars with these ids (None selects all)
+    pub ids: Option<HashSet<String>>,
+    /// random sample of this many stars (of each database)
+    pub sample: Option<usize>,
+    pub seed: u64,
+    /// most stars read (of all inputs)
+    pub limit: Option<usize>,
+}
+
+impl StarSelection {
+    pub fn has_id(&self, id: &str) -> bool {
+        match self.ids {
+            Some(ref ids) => ids.contains(id),
+            None => true,
+        }
+    }
+}
+
+/// Stars and template banks shared by the runs of an experiment, so the
+/// stars are read and the templates FFT'd once.
+///
+/// NOTE the stars are cloned for every run (kept next to the run's copy)
+#[derive(Default)]
+pub struct DataCache {
+    stars: Option<Vec<Star>>,
+    // NOTE the templates depend on dc_norm and template_basis_energy
+    templates: HashMap<String, Arc<Templates>>,
+}
+
+/// Run file keys an experiment can not sweep, the stars and templates are
+/// only read once.
+const EXPERIMENT_FIXED_ARGS: &[&str] = &[
+    "input_dir",
+    "gwac_file",
+    "templates_file",
+    "template_group_sz",
+    "where",
+    "limit",
+    "ids",
+    "ids_file",
+    "sample",
+    "seed",
+];
+
 arg_enum! {
+    #[derive(Debug)]
     pub enum SortOpt {
         None,
         Increasing,
@@ -37,7 +178,21 @@ arg_enum! {
 }
 
 arg_enum! {
-    #[derive(Clone, Copy)]
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    /// What an offline run writes back into its SQLite star database
+    /// (the runs, events and scores tables, see sqlite_stars)
+    ///
+    /// EventsAndScores
+    /// - also the best score of every star at every tick it is filtered
+    pub enum WriteResults {
+        None,
+        Events,
+        EventsAndScores,
+    }
+}
+
+arg_enum! {
+    #[derive(Clone, Copy, Debug)]
     // [ ] TODO verify that the logic is correctly spread into filter.rs and template.rs
     ///
     /// DCNorm carries information about which types of normalization should be applied to
@@ -94,22 +249,66 @@ arg_enum! {
     }
 }
 
+#[derive(Debug)]
 pub struct LogOpts {
     pub sort: SortOpt,
     pub plot: bool,
+    pub write_results: WriteResults,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug)]
 pub struct DetectorOpts {
     pub _rho: f32,
     pub noise_stddev: f32,
     pub window_length: (usize, usize),
+    /// window lengths each star is filtered at (every tick)
+    pub window_scales: Vec<usize>,
     pub fragment: u32,
     pub skip_delta: u32,
     pub alert_threshold: f32,
     pub window_func: WindowFunc,
     pub dc_norm: DCNorm,
     pub star_group_sz: usize,
+    /// number of threads filtering star groups at the same time
+    pub cpu_workers: usize,
+    /// None alerts on every class of event
+    pub alert_classes: Option<Vec<DU::EventClass>>,
+    /// what to do with live frames when the detector falls behind
+    pub buffer_policy: BufferPolicy,
+    /// most live frames (of all cameras) held in memory
+    pub max_queued_frames: usize,
+    /// frames behind (for a single camera) before warning
+    pub lag_warn_frames: usize,
+    /// what live stars do for frames they are missing from
+    pub missing_frame_policy: MissingFramePolicy,
+    /// frames a live star can be missing for before it is dropped (None keeps it forever)
+    pub evict_after: Option<usize>,
+    /// what to do with stars not sampled at the template cadence
+    pub cadence_mismatch: CadenceMismatch,
+    /// seconds between template samples (None skips the cadence checks)
+    pub template_sample_rate: Option<i32>,
+    /// ticks the ticker can be ahead of the detector
+    pub max_lag: usize,
+    /// best score a star is filtered every tick at (None keeps skip_delta)
+    pub watch_level: Option<f32>,
+    /// standard deviation of the newest min window samples a star is filtered every tick at
+    pub watch_variability: Option<f32>,
+    /// most filter operations (star windows) per tick (None is unlimited)
+    pub filter_budget: Option<usize>,
+    /// filter the residual of the stars (observed minus their model's baseline)
+    pub residual: bool,
+}
+
+/// Star of a star file (by extension), None for other files.
+fn parse_star_path(path: &Path) -> Option<Star> {
+    let star_file = path.to_str().expect("Problem converting star file name to string");
+
+    match path.extension() {
+        Some(ext) if ext == "toml" => Some(toml_star::parse_star_file(star_file)),
+        Some(ext) if ext == "dat" => Some(dat_star::parse_star_file(star_file)),
+        Some(ext) if ext == "json" => json_star::parse_star_file(star_file),
+        _ => None,
+    }
 }
 
 fn unwrap_parse_star_files(
@@ -119,24 +318,7 @@ fn unwrap_parse_star_files(
         Ok(file) => match file.file_type() {
             Ok(file_type) => {
                 if file_type.is_file() {
-                    match file.path().extension() {
-                        Some(ext) if ext == "toml" => {
-                            Some(toml_star::parse_star_file(
-                                file.path().as_path().to_str().expect("Problem converting toml file name to string"),
-                            ))
-                        }
-                        Some(ext) if ext == "dat" => {
-                            Some(dat_star::parse_star_file(
-                                file.path().as_path().to_str().expect("Problem converting dat file name to string"),
-                            ))
-                        }
-                        Some(ext) if ext == "json" => {
-                            json_star::parse_star_file(
-                                file.path().as_path().to_str().expect("Problem converting json file name to string"),
-                            )
-                        }
-                        _ => None,
-                    }
+                    parse_star_path(&file.path())
                 } else {
                     None
                 }
@@ -147,39 +329,126 @@ fn unwrap_parse_star_files(
     }
 }
 
-fn parse_star_files(
-    input_dirs: &[&str],
-    detector_opts: &DetectorOpts,
-) -> Vec<SWStar> {
-    let input_dirs: Vec<String> =
-        input_dirs.iter().map(|s| s.to_string()).collect();
-    // FIXME only doing one directory for now
-    let input_dir = &input_dirs[0];
+/// Brings an offline star to the cadence the template bank was built for.
+fn match_template_cadence(
+    mut star: Star,
+    template_rate: i32,
+    policy: CadenceMismatch,
+) -> Star {
+    if cadences_match(star.sample_rate as f32, template_rate as f32) {
+        return star;
+    }
+
+    match policy {
+        CadenceMismatch::Refuse => panic!(
+            "Star {} is sampled every {}s but the templates every {}s (see --cadence-mismatch).",
+            star.uid, star.sample_rate, template_rate
+        ),
+        CadenceMismatch::Resample => {
+            star.samples = star.samples.as_ref().map(|samples| {
+                resample(samples, star.sample_rate as f32, template_rate as f32)
+            });
+            star.sample_rate = template_rate;
+            star
+        }
+    }
+}
+
+pub fn is_star_db(input: &str) -> bool {
+    Path::new(input).extension().map_or(false, |ext| ext == "db")
+}
+
+/// Calls `f` with every selected star of the inputs (directories of star
+/// files, SQLite databases or single star files), one star at a time so
+/// large inputs are never held in memory.
+pub fn for_each_star(input_dirs: &[String], selection: &StarSelection, f: &mut dyn FnMut(Star)) {
+    if input_dirs.iter().any(|input| !is_star_db(input))
+        && (selection.where_clause.is_some() || selection.sample.is_some())
+    {
+        panic!("--where and --sample select from SQLite star databases (.db) only");
+    }
+
+    let mut num_selected = 0;
+    let is_full = |num_selected: usize| selection.limit.map_or(false, |limit| num_selected >= limit);
+
+    for input_dir in input_dirs.iter() {
+        if is_full(num_selected) {
+            break;
+        }
 
-    let stars: Vec<Star> = {
         match fs::metadata(&input_dir) {
-            Ok(ref file_type) if file_type.is_dir() => fs::read_dir(&input_dir)
-                .expect("Problem reading star input directory.")
-                .collect::<Vec<std::io::Result<fs::DirEntry>>>()
-                .into_iter()
-                .filter_map(unwrap_parse_star_files)
-                .collect(),
+            Ok(ref file_type) if file_type.is_dir() => {
+                let stars = fs::read_dir(&input_dir)
+                    .expect("Problem reading star input directory.")
+                    .filter_map(unwrap_parse_star_files);
+                for star in stars {
+                    if is_full(num_selected) {
+                        break;
+                    }
+                    if selection.has_id(&star.id) {
+                        f(star);
+                        num_selected += 1;
+                    }
+                }
+            }
             Ok(ref file_type) if file_type.is_file() => {
-                match Path::new(input_dir).extension() {
-                    Some(ext) if ext == "db" => {
-                        sqlite_stars::parse_star_files(input_dir)
+                if is_star_db(input_dir) {
+                    // NOTE the database only reads what is left of the limit
+                    let db_selection = StarSelection {
+                        limit: selection.limit.map(|limit| limit - num_selected),
+                        ..selection.clone()
+                    };
+                    sqlite_stars::for_each_star(input_dir, &db_selection, &mut |star| {
+                        f(star);
+                        num_selected += 1;
+                    });
+                } else {
+                    match parse_star_path(Path::new(input_dir)) {
+                        Some(star) => {
+                            if selection.has_id(&star.id) {
+                                f(star);
+                                num_selected += 1;
+                            }
+                        }
+                        None => panic!("Error in reading input file {}", input_dir),
                     }
-                    _ => panic!("Error in reading input_db")
                 }
             }
             _ => panic!("Error in reading input_dir"),
         }
-    };
+    }
+}
+
+pub fn parse_star_files(input_dirs: &[String], selection: &StarSelection) -> Vec<Star> {
+    let mut stars = Vec::new();
+    for_each_star(input_dirs, selection, &mut |star| stars.push(star));
 
+    stars
+}
+
+fn sw_stars(stars: Vec<Star>, detector_opts: &DetectorOpts) -> Vec<SWStar> {
     stars
         .into_iter()
         .zip((0..detector_opts.fragment).cycle())
         .map(|(star, fragment)| {
+            // NOTE plugin predictors only predict the next sample, there
+            //      is no baseline of the window to take the residual of
+            if let (true, StarModelType::Plugin) = (detector_opts.residual, star.model_type) {
+                panic!(
+                    "Star {} is modelled by a plugin predictor, residual filtering needs model_type = \"arima\"",
+                    star.uid
+                );
+            }
+
+            let star = match detector_opts.template_sample_rate {
+                Some(template_rate) => match_template_cadence(
+                    star,
+                    template_rate,
+                    detector_opts.cadence_mismatch,
+                ),
+                None => star,
+            };
+
             SWStar::new()
                 .set_star(star)
                 .set_availables(fragment, detector_opts.skip_delta)
@@ -193,369 +462,1128 @@ fn parse_star_files(
         .collect::<Vec<SWStar>>()
 }
 
-pub fn parse_args() -> RunInfo {
-    let matches = App::new("Matched Filter")
+/// Command line arguments with the run file and mf_config.toml
+/// defaults filled in.
+///
+/// NOTE arguments given on the command line win over the run file, the
+///      run file over the config and the config over the clap defaults
+struct ArgValues<'a> {
+    matches: &'a ArgMatches<'a>,
+    // NOTE highest priority first
+    layers: Vec<&'a ArgDefaults>,
+}
+
+impl<'a> ArgValues<'a> {
+    /// The command line alone (no run file or config defaults).
+    fn of(matches: &'a ArgMatches<'a>) -> ArgValues<'a> {
+        ArgValues {
+            matches,
+            layers: Vec::new(),
+        }
+    }
+
+    /// Value of the first default layer setting the argument (None when
+    /// the command line decides).
+    fn layer_value(&self, name: &str) -> Option<&'a [String]> {
+        let conflicts = mf_config::arg_conflicts(name);
+        let on_command_line = |arg: &str| self.matches.occurrences_of(arg) > 0;
+        if on_command_line(name) || conflicts.iter().any(|arg| on_command_line(arg)) {
+            return None;
+        }
+
+        for &layer in self.layers.iter() {
+            if let Some(vals) = layer.get(name) {
+                return Some(vals.as_slice());
+            }
+            // NOTE a conflicting argument of a higher layer hides the lower ones
+            if conflicts.iter().any(|arg| layer.contains_key(arg)) {
+                return None;
+            }
+        }
+
+        None
+    }
+
+    fn value_of(&self, name: &str) -> Option<&'a str> {
+        match self.layer_value(name) {
+            Some(vals) => vals.first().map(|val| val.as_str()),
+            None => self.matches.value_of(name),
+        }
+    }
+
+    fn values_of(&self, name: &str) -> Option<std::vec::IntoIter<&'a str>> {
+        match self.layer_value(name) {
+            Some(vals) => Some(
+                vals.iter()
+                    .map(|val| val.as_str())
+                    .collect::<Vec<&str>>()
+                    .into_iter(),
+            ),
+            None => self
+                .matches
+                .values_of(name)
+                .map(|vals| vals.collect::<Vec<&str>>().into_iter()),
+        }
+    }
+}
+
+/// Value of a flag on the command line before it is parsed by clap.
+///
+/// NOTE the config and run file decide which arguments are required,
+///      so they have to be read first
+fn early_arg(flag: &str) -> Option<String> {
+    let mut args = std::env::args().skip(1);
+    let flag_eq = format!("{}=", flag);
+    while let Some(arg) = args.next() {
+        if arg == flag {
+            return args.next();
+        }
+        if arg.starts_with(&flag_eq) {
+            return Some(arg[flag_eq.len()..].to_string());
+        }
+    }
+    None
+}
+
+/// Defaults of the live only options, offline runs fill their
+/// `DetectorOpts` with these.
+const LIVE_DEFAULTS: &[(&str, &str)] = &[
+    ("buffer_policy", "Block"),
+    ("max_queued_frames", "4"),
+    ("lag_warn_frames", "2"),
+    ("missing_frames", "Hold"),
+    ("evict_after", "20"),
+];
+
+fn live_default(arg: &str) -> &'static str {
+    LIVE_DEFAULTS
+        .iter()
+        .find(|(name, _)| *name == arg)
+        .map(|(_, default)| *default)
+        .expect("Live option without a default.")
+}
+
+fn input_arg(has_default: &dyn Fn(&str) -> bool) -> Arg<'static, 'static> {
+    Arg::with_name("input_dir")
+        .short("i")
+        .long("input")
+        .help("Directory/file containing the star data information.")
+        .number_of_values(1)
+        .multiple(true)
+        .takes_value(true)
+        .required(!has_default("input_dir"))
+}
+
+fn gwac_file_arg(has_default: &dyn Fn(&str) -> bool) -> Arg<'static, 'static> {
+    Arg::with_name("gwac_file")
+        .long("gwac-file")
+        .help("GWAC source for processing live data: unix://path (Unix Domain Socket), tcp://host:port, fifo:path (named pipe) or a plain file path. Sockets and named pipes are reconnected when the upstream drops. Give once per camera to process several cameras in one detector.")
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .required(!has_default("gwac_file"))
+}
+
+fn run_file_arg(required: bool) -> Arg<'static, 'static> {
+    Arg::with_name("run_file")
+        .long("run-file")
+        .help("Run file (TOML) with the input, templates, detector, trigger, tester and log options of a run. A [matrix] table of option variations runs every combination in sequence. Command line options override the run file.")
+        .takes_value(true)
+        .required(required)
+}
+
+fn template_args(has_default: &dyn Fn(&str) -> bool) -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("templates_file")
+            .short("t")
+            .long("templates-file")
+            .help("File containing the template information.")
+            .takes_value(true)
+            .required(!has_default("templates_file")),
+        Arg::with_name("template_group_sz")
+            .long("template_group_sz")
+            .help("Specifies how many templates to consider for each GPU operation (matrix multiply).")
+            .takes_value(true)
+            .default_value("1024"),
+        /*
+        Arg::with_name("template_norm")
+            .long("template-norm")
+            .help("Specifies which (if any) template normalizations are applied.")
+            .takes_value(true)
+            .default_value("none")
+            .possible_values(&TemplateNorm::variants())
+            .case_insensitive(true),
+        */
+    ]
+}
+
+fn template_basis_arg() -> Arg<'static, 'static> {
+    Arg::with_name("template_basis_energy")
+        .long("template-basis-energy")
+        .help("Filter against an SVD-reduced basis of every template group keeping this fraction (ex. 0.999) of the group's energy, the per-template scores are reconstructed from the basis projections. Pays off for large groups of similar templates.")
+        .takes_value(true)
+}
+
+fn detector_args(has_default: &dyn Fn(&str) -> bool) -> Vec<Arg<'static, 'static>> {
+    let has_window_default = has_default("window_length")
+        || (has_default("min_window_length") && has_default("max_window_length"));
+
+    vec![
+        Arg::with_name("rho")
+            .short("p")
+            .long("rho")
+            .help("<<For now not used>>")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("noise")
+            .short("n")
+            .long("noise")
+            .help("<<For now not used>>")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("window_length")
+            .short("w")
+            .long("window-length")
+            .help("Fixed window length used in filtering star data. See min_/max_window_length for variable window lengths.")
+            .takes_value(true)
+            .conflicts_with_all(&["min_window_length", "max_window_length"])
+            .required_unless_one(&["min_window_length", "max_window_length"])
+            .required(!has_window_default),
+        Arg::with_name("min_window_length")
+            .long("min-window-length")
+            .help("Minimum window size before we start filtering star data.")
+            .requires("max_window_length")
+            .required_unless("window_length")
+            .required(!has_window_default)
+            .takes_value(true),
+        Arg::with_name("max_window_length")
+            .long("max-window-length")
+            .help("Maximum window size for star data.")
+            .requires("min_window_length")
+            .required_unless("window_length")
+            .required(!has_window_default)
+            .takes_value(true),
+        Arg::with_name("window_scales")
+            .long("window-scales")
+            .help("Number of window lengths (evenly spread from min_ to max_window_length) each star is filtered at. The best scoring window length is reported.")
+            .takes_value(true)
+            .validator(|val| match val.parse::<usize>() {
+                Ok(num) if num > 0 => Ok(()),
+                _ => Err("window-scales must be a whole number greater than 0".to_string()),
+            })
+            .default_value("1"),
+        Arg::with_name("skip_delta")
+            .long("skip-delta")
+            .help("How many data points we skip before executing another matched filtering operation. Happens per star.")
+            .takes_value(true)
+            .required(!has_default("skip_delta")),
+        Arg::with_name("alert_threshold")
+            .short("a")
+            .long("alert-threshold")
+            .help("Threshold value at which we trigger an anomaly.")
+            .takes_value(true)
+            .required(!has_default("alert_threshold")),
+        Arg::with_name("fragment")
+            .long("fragment")
+            .help("Number of fragments to split stars into. (How we delay stars to group them (star A starts at 1, star B at 2, etc.))")
+            .takes_value(true)
+            .required(!has_default("fragment")),
+        Arg::with_name("window_function")
+            .long("window-func")
+            .help("Window function used to adjust the star windows.")
+            .takes_value(true)
+            .default_value("rectangle")
+            .possible_values(&WindowFunc::variants())
+            .case_insensitive(true),
+        dc_norm_arg(),
+        Arg::with_name("alert_classes")
+            .long("alert-classes")
+            .help("Event classes (from the template bank) that are alerted on. Defaults to every class.")
+            .takes_value(true)
+            .multiple(true)
+            .possible_values(&DU::EventClass::variants())
+            .case_insensitive(true),
+        Arg::with_name("star_group_sz")
+            .long("star_group_sz")
+            .help("Specifies how many stars to consider for each GPU operation (matrix multiply).")
+            .takes_value(true)
+            .default_value("1024"),
+        Arg::with_name("cpu_workers")
+            .long("cpu-workers")
+            .help("Specifies how many star groups are filtered at the same time (each in its own thread). Preprocessing of the next group overlaps with the filtering.")
+            .takes_value(true)
+            .default_value("1"),
+        Arg::with_name("cadence_mismatch")
+            .long("cadence-mismatch")
+            .help("What to do with stars not sampled at the cadence the template bank was built for (its sample_rate): refuse them (offline runs stop, live stars are not filtered) or resample them onto the template cadence.")
+            .takes_value(true)
+            .default_value("Refuse")
+            .possible_values(&CadenceMismatch::variants())
+            .case_insensitive(true),
+        Arg::with_name("max_lag")
+            .long("max-lag")
+            .help("Number of ticks the star ticking can run ahead of the filtering (window snapshots held in memory).")
+            .takes_value(true)
+            .default_value("2"),
+        Arg::with_name("watch_level")
+            .long("watch-level")
+            .help("Score above which a star is filtered every tick instead of every skip_delta ticks (until its score drops again).")
+            .takes_value(true),
+        Arg::with_name("watch_variability")
+            .long("watch-variability")
+            .help("Standard deviation of the newest (min window length) samples above which a star is filtered every tick.")
+            .takes_value(true),
+        Arg::with_name("filter_budget")
+            .long("filter-budget")
+            .help("Most star windows filtered per tick, watched and then the longest waiting stars go first (0 is unlimited).")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("residual")
+            .long("residual")
+            .help("Filter the residual (observed minus predicted baseline) of stars with a baseline model (ex. model_type = \"arima\" in their star file), so intrinsic variability is not matched. Stars without a model are filtered as is, stars modelled by a plugin predictor (no baseline) are refused.")
+            .takes_value(true)
+            .default_value("false")
+            .possible_values(&["true", "false"])
+            .case_insensitive(true),
+    ]
+}
+
+/// Options of live (GWAC) runs only.
+fn live_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("buffer_policy")
+            .long("buffer-policy")
+            .help("What to do with live GWAC frames when the detector falls behind: block the readers, drop the oldest queued frame or keep ticking the stars but skip filtering while lagging.")
+            .takes_value(true)
+            .default_value(live_default("buffer_policy"))
+            .possible_values(&BufferPolicy::variants())
+            .case_insensitive(true),
+        Arg::with_name("max_queued_frames")
+            .long("max-queued-frames")
+            .help("Most live GWAC frames (of all cameras together) held in memory waiting for the detector.")
+            .takes_value(true)
+            .default_value(live_default("max_queued_frames")),
+        Arg::with_name("lag_warn_frames")
+            .long("lag-warn-frames")
+            .help("Warn when the detector falls more than this many frames behind a GWAC camera.")
+            .takes_value(true)
+            .default_value(live_default("lag_warn_frames")),
+        Arg::with_name("missing_frames")
+            .long("missing-frames")
+            .help("What a live star does for a frame it is missing from: repeat its last sample, push a NaN (not filtered until the gap leaves the window) or reset its window.")
+            .takes_value(true)
+            .default_value(live_default("missing_frames"))
+            .possible_values(&MissingFramePolicy::variants())
+            .case_insensitive(true),
+        Arg::with_name("evict_after")
+            .long("evict-after")
+            .help("Number of frames a live star can be missing for before it is dropped (0 keeps stars forever).")
+            .takes_value(true)
+            .default_value(live_default("evict_after")),
+    ]
+}
+
+fn trigger_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("detector_trigger")
+            .long("detector-trigger")
+            .help("Specifies which detector trigger to use for detection results.")
+            .takes_value(true)
+            .default_value("ThresholdTrigger")
+            .possible_values(&DU::DetectorTriggerImps::variants())
+            .case_insensitive(true),
+        Arg::with_name("trigger_k")
+            .long("trigger-k")
+            .help("Number of the last trigger-n filter values that must be above the alert threshold for KOfNTrigger.")
+            .takes_value(true)
+            .default_value("3"),
+        Arg::with_name("trigger_n")
+            .long("trigger-n")
+            .help("Number of most recent filter values considered by KOfNTrigger.")
+            .takes_value(true)
+            .default_value("3"),
+        Arg::with_name("disarm_threshold")
+            .long("disarm-threshold")
+            .help("Value a star must fall below after triggering before it can trigger again. If not given, a star only ever triggers once.")
+            .takes_value(true),
+        Arg::with_name("trigger_cooldown")
+            .long("trigger-cooldown")
+            .help("Minimum number of sample ticks between two triggers of the same star.")
+            .takes_value(true)
+            .default_value("0"),
+        Arg::with_name("curve_history")
+            .long("curve-history")
+            .help("Number of most recent filter values per star whose shape is checked by CurveTrigger.")
+            .takes_value(true)
+            .default_value("6"),
+        Arg::with_name("curve_min_rise")
+            .long("curve-min-rise")
+            .help("Fraction of the steps in the CurveTrigger history that must be increasing.")
+            .takes_value(true)
+            .default_value("0.6"),
+        Arg::with_name("curve_max_step")
+            .long("curve-max-step")
+            .help("Largest fraction of the total rise a single step may account for in CurveTrigger (rejects flare like steps).")
+            .takes_value(true)
+            .default_value("0.5"),
+        Arg::with_name("veto")
+            .long("veto")
+            .help("Specifies which veto stage (flare and artifact rejection) is applied before the detector trigger.")
+            .takes_value(true)
+            .default_value("NoneVeto")
+            .possible_values(&veto::VetoImps::variants())
+            .case_insensitive(true),
+        Arg::with_name("veto_spike_sigma")
+            .long("veto-spike-sigma")
+            .help("Size (in noise sigmas) of a single frame jump that is vetoed as an artifact.")
+            .takes_value(true)
+            .default_value("8.0"),
+        Arg::with_name("veto_flare_sigma")
+            .long("veto-flare-sigma")
+            .help("Size (in noise sigmas) of a single step that is vetoed as a flare rise.")
+            .takes_value(true)
+            .default_value("5.0"),
+        Arg::with_name("veto_flare_step")
+            .long("veto-flare-step")
+            .help("Fraction of the window range a single step must account for to be vetoed as a flare rise.")
+            .takes_value(true)
+            .default_value("0.5"),
+    ]
+}
+
+fn tester_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("tartan_test")
+            .long("tartan-test")
+            .help("Specifies to use or not use Tartan data parsing to determine true positive, false positive, etc.")
+            .takes_value(true)
+            .default_value("false")
+            .possible_values(&["true", "false"])
+            .case_insensitive(true),
+        Arg::with_name("tartan_test_file")
+            .long("tartan-test-file")
+            .help("File specifying the Tartan generated data parameters.")
+            .takes_value(true)
+            .required_if("tartan_test", "true"),
+    ]
+}
+
+fn log_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("sort")
+            .long("sort")
+            .help("How we want to sort the output data for a local test data run.")
+            .takes_value(true)
+            .default_value("none")
+            .possible_values(&SortOpt::variants())
+            .case_insensitive(true),
+        Arg::with_name("plot")
+            .long("plot")
+            .help("Do we want to plot the data for a local test data run? Also, removes reporting of data statistics avg, stddev, etc. when false for memory performance reasons.")
+            .takes_value(true)
+            .default_value("true")
+            .possible_values(&["true", "false"])
+            .case_insensitive(true),
+    ]
+}
+
+fn write_results_arg() -> Arg<'static, 'static> {
+    Arg::with_name("write_results")
+        .long("write-results")
+        .help("Writes the run's events (and the best score of every star at every tick) back into the SQLite star database the stars were read from, a runs row per run. Needs a single .db input.")
+        .takes_value(true)
+        .default_value("none")
+        .possible_values(&WriteResults::variants())
+        .case_insensitive(true)
+}
+
+fn selection_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("where")
+            .long("where")
+            .help("SQL condition selecting the stars of SQLite star databases, ex. \"star_type = 'variable' AND num_samples > 1000\" (columns: id, star_type, sample_rate, metadata, encoding, num_samples).")
+            .takes_value(true),
+        Arg::with_name("limit")
+            .long("limit")
+            .help("Most stars read (of all inputs).")
+            .takes_value(true),
+        Arg::with_name("ids")
+            .long("ids")
+            .help("Only the stars with these ids.")
+            .takes_value(true)
+            .multiple(true),
+        Arg::with_name("ids_file")
+            .long("ids-file")
+            .help("Only the stars with the ids in this file (one per line).")
+            .takes_value(true),
+        Arg::with_name("sample")
+            .long("sample")
+            .help("Random sample of this many stars of each SQLite star database (after --where and the ids, the same --seed selects the same stars).")
+            .takes_value(true),
+        Arg::with_name("seed")
+            .long("seed")
+            .help("Seed of --sample.")
+            .takes_value(true)
+            .default_value("0"),
+    ]
+}
+
+fn dc_norm_arg() -> Arg<'static, 'static> {
+    Arg::with_name("dc_norm")
+        .long("dc-norm")
+        .help("Specifies which (if any) DC normalization should be applied.")
+        .takes_value(true)
+        .default_value("none")
+        .possible_values(&DCNorm::variants())
+        .case_insensitive(true)
+}
+
+pub fn parse_args() -> Cli {
+    // NOTE loaded first as config and run file defaults make arguments optional
+    let mf_config = mf_config::load_mfconfig(early_arg("--config").as_deref());
+    let run_file = early_arg("--run-file").map(|run_file| RunFile::from_file(&run_file));
+    let runs = match run_file {
+        Some(ref run_file) => run_file.runs(),
+        None => vec![Run {
+            label: "run".to_string(),
+            params: Vec::new(),
+            args: ArgDefaults::new(),
+        }],
+    };
+
+    let has_default = |arg: &str| {
+        mf_config.has_default(arg) || runs.iter().all(|run| run.args.contains_key(arg))
+    };
+    let has_default: &dyn Fn(&str) -> bool = &has_default;
+
+    let arg_matches = App::new("Matched Filter")
         .version(crate_version!())
+        // NOTE the banner is only shown for --version (and --license)
+        .long_version(concat!(crate_version!(), "\n\n", include_str!("../COPYRIGHT")))
         .author("Austin C. Minor (米诺) <austin.chase.m@gmail.com>")
-        .about("TODO")
-        .arg(
-            Arg::with_name("input_dir")
-                .short("i")
-                .long("input")
-                .help("Directory/file containing the star data information.")
-                .number_of_values(1)
-                .multiple(true)
-                .takes_value(true)
-                .required_unless_one(&["gwac_file", "license"])
-                .conflicts_with_all(&["gwac_file", "license"]),
-        )
-        .arg(
-            Arg::with_name("templates_file")
-                .short("t")
-                .long("templates-file")
-                .help("File containing the template information.")
-                .takes_value(true)
-                .conflicts_with("license")
-                .required_unless("license")
-                .required(true),
-        )
-        .arg(
-            Arg::with_name("rho")
-                .short("p")
-                .long("rho")
-                .help("<<For now not used>>")
-                .takes_value(true)
-                .conflicts_with("license")
-                .required_unless("license")
-                .required(true),
-        )
-        .arg(
-            Arg::with_name("noise")
-                .short("n")
-                .long("noise")
-                .help("<<For now not used>>")
-                .takes_value(true)
-                .conflicts_with("license")
-                .required_unless("license")
-                .required(true),
-        )
-        .arg(
-            Arg::with_name("window_length")
-                .short("w")
-                .long("window-length")
-                .help("Fixed window length used in filtering star data. See min_/max_window_length for variable window lengths.")
-                .takes_value(true)
-                .conflicts_with_all(&[
-                    "min_window_length",
-                    "max_window_length",
-                    "license",
-                ])
-                .required_unless_one(&[
-                    "min_window_length",
-                    "max_window_length",
-                    "license",
-                ]),
-        )
-        .arg(
-            Arg::with_name("min_window_length")
-                .long("min-window-length")
-                .help("Minimum window size before we start filtering star data.")
-                .requires("max_window_length")
-                .required_unless_one(&["window_length", "license"])
-                .conflicts_with("license")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("max_window_length")
-                .long("max-window-length")
-                .help("Maximum window size for star data.")
-                .requires("min_window_length")
-                .required_unless_one(&["window_length", "license"])
-                .conflicts_with("license")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("skip_delta")
-                .long("skip-delta")
-                .help("How many data points we skip before executing another matched filtering operation. Happens per star.")
-                .takes_value(true)
-                .conflicts_with("license")
-                .required_unless("license")
-                .required(true),
-        )
-        .arg(
-            Arg::with_name("alert_threshold")
-                .short("a")
-                .long("alert-threshold")
-                .help("Threshold value at which we trigger an anomaly.")
-                .takes_value(true)
-                .conflicts_with("license")
-                .required_unless("license")
-                .required(true),
-        )
-        .arg(
-            Arg::with_name("fragment")
-                .long("fragment")
-                .help("Number of fragments to split stars into. (How we delay stars to group them (star A starts at 1, star B at 2, etc.))")
-                .takes_value(true)
-                .conflicts_with("license")
-                .required_unless("license")
-                .required(true),
-        )
-        .arg(
-            Arg::with_name("gwac_file")
-                .long("gwac-file")
-                .help("GWAC Unix Domain Sockets file for processing live data.")
-                .takes_value(true)
-                .required_unless_one(&["input_dir", "license"])
-                .conflicts_with_all(&["input_dir", "license"]),
-        )
-        .arg(
-            Arg::with_name("window_function")
-                .long("window-func")
-                .help("Window function used to adjust the star windows.")
-                .takes_value(true)
-                .default_value("rectangle")
-                .possible_values(&WindowFunc::variants())
-                .case_insensitive(true)
-        )
-        .arg(
-            Arg::with_name("sort")
-                .long("sort")
-                .help("How we want to sort the output data for a local test data run.")
-                .takes_value(true)
-                .default_value("none")
-                .possible_values(&SortOpt::variants())
-                .case_insensitive(true)
-        )
+        .about("Matched filtering of star light curves against a template bank.")
         .arg(
-            Arg::with_name("plot")
-                .long("plot")
-                .help("Do we want to plot the data for a local test data run? Also, removes reporting of data statistics avg, stddev, etc. when false for memory performance reasons.")
+            Arg::with_name("config")
+                .long("config")
+                .help("Global configuration file (plugin and data directories, option defaults). Otherwise mf_config.toml is searched for in the current directory and then in $XDG_CONFIG_HOME/match_filter. Command line options override the config.")
                 .takes_value(true)
-                .default_value("true")
-                .possible_values(&["true", "false"])
-                .case_insensitive(true)
+                .global(true),
         )
         .arg(
-            Arg::with_name("tartan_test")
-                .long("tartan-test")
-                .help("Specifies to use or not use Tartan data parsing to determine true positive, false positive, etc.")
-                .takes_value(true)
-                .default_value("false")
-                .possible_values(&["true", "false"])
-                .case_insensitive(true)
+            Arg::with_name("license")
+                .long("license")
+                .help("Display license and attribution information."),
         )
-        .arg(
-            Arg::with_name("tartan_test_file")
-                .long("tartan-test-file")
-                .help("File specifying the Tartan generated data parameters.")
-                .takes_value(true)
-                .required_if("tartan_test", "true")
+        .subcommand(
+            SubCommand::with_name("run")
+                .about("Runs the detector over offline star data.")
+                .arg(input_arg(has_default))
+                .args(&selection_args())
+                .args(&template_args(has_default))
+                .arg(template_basis_arg())
+                .args(&detector_args(has_default))
+                .args(&trigger_args())
+                .args(&tester_args())
+                .args(&log_args())
+                .arg(write_results_arg())
+                .arg(run_file_arg(false)),
         )
-        .arg(
-            Arg::with_name("dc_norm")
-                .long("dc-norm")
-                .help("Specifies which (if any) DC normalization should be applied.")
-                .takes_value(true)
-                .default_value("none")
-                .possible_values(&DCNorm::variants())
-                .case_insensitive(true)
+        .subcommand(
+            SubCommand::with_name("live")
+                .about("Runs the detector over live GWAC data.")
+                .arg(gwac_file_arg(has_default))
+                .args(&template_args(has_default))
+                .arg(template_basis_arg())
+                .args(&detector_args(has_default))
+                .args(&live_args())
+                .args(&trigger_args())
+                .args(&log_args())
+                .arg(run_file_arg(false)),
         )
-        /*
-        .arg(
-            Arg::with_name("template_norm")
-                .long("template-norm")
-                .help("Specifies which (if any) template normalizations are applied.")
-                .takes_value(true)
-                .default_value("none")
-                .possible_values(&TemplateNorm::variants())
-                .case_insensitive(true)
+        .subcommand(
+            SubCommand::with_name("sweep")
+                .alias("experiment")
+                .about("Runs every combination of a run file's [matrix] (the grid) over the same stars and templates, read once, and writes one CSV row of detection metrics and ADP stats per combination.")
+                .arg(input_arg(has_default))
+                .args(&selection_args())
+                .args(&template_args(has_default))
+                .arg(template_basis_arg())
+                .args(&detector_args(has_default))
+                .args(&trigger_args())
+                .args(&tester_args())
+                .args(&log_args())
+                .arg(write_results_arg())
+                .arg(run_file_arg(true))
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Aggregated CSV of the sweep (a row per grid combination).")
+                        .takes_value(true)
+                        .default_value("experiment.csv"),
+                ),
         )
-        */
-        .arg(
-            Arg::with_name("detector_trigger")
-                .long("detector-trigger")
-                .help("Specifies which detector trigger to use for detection results.")
-                .takes_value(true)
-                .default_value("ThresholdTrigger")
-                .possible_values(&DU::DetectorTriggerImps::variants())
-                .case_insensitive(true)
+        .subcommand(
+            SubCommand::with_name("inspect-templates")
+                .about("Reports on a template bank: template counts, length distribution, FFT length, group layout, estimated device memory and the pairwise match of the templates (flagging near-duplicates).")
+                .args(&template_args(&|_| false))
+                .arg(dc_norm_arg())
+                .arg(
+                    Arg::with_name("star_group_sz")
+                        .long("star_group_sz")
+                        .help("Star group size (see run) the device memory of a filter operation is estimated for.")
+                        .takes_value(true)
+                        .default_value("1024"),
+                )
+                .arg(
+                    Arg::with_name("duplicate_match")
+                        .long("duplicate-match")
+                        .help("Match (normalized inner product of the template spectra) at or above which two templates are flagged as near-duplicates.")
+                        .takes_value(true)
+                        .default_value("0.99"),
+                ),
         )
-        .arg(
-            Arg::with_name("template_group_sz")
-                .long("template_group_sz")
-                .help("Specifies how many templates to consider for each GPU operation (matrix multiply).")
-                .takes_value(true)
-                .default_value("1024")
+        .subcommand(
+            SubCommand::with_name("prune-templates")
+                .about("Greedily prunes a template bank until every template matches a kept one by at least --min-match and writes the pruned bank.")
+                .args(&template_args(&|_| false))
+                .arg(dc_norm_arg())
+                .arg(
+                    Arg::with_name("min_match")
+                        .long("min-match")
+                        .help("Minimal match (normalized inner product of the template spectra) of every template with a kept template.")
+                        .takes_value(true)
+                        .default_value("0.97"),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Pruned template TOML file, the templates are written next to it.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("basis_energy")
+                        .long("basis-energy")
+                        .help("Also report the rank of the SVD-reduced basis (see run --template-basis-energy) of every pruned template group keeping this fraction of its energy.")
+                        .takes_value(true),
+                ),
         )
-        .arg(
-            Arg::with_name("star_group_sz")
-                .long("star_group_sz")
-                .help("Specifies how many stars to consider for each GPU operation (matrix multiply).")
-                .takes_value(true)
-                .default_value("1024")
+        .subcommand(
+            SubCommand::with_name("inspect-stars")
+                .about("Reports on the stars of offline star data.")
+                .arg(input_arg(&|_| false))
+                .args(&selection_args()),
         )
-        .arg(
-            Arg::with_name("license")
-                .long("license")
-                .help("Display license and attribution information."),
+        .subcommand(
+            SubCommand::with_name("convert")
+                .about("Converts star data between the star storage formats (star files, SQLite databases, GWAC JSON and DAT files), streaming one star at a time. Legacy StarEntry databases are converted to the current schema.")
+                .arg(input_arg(&|_| false))
+                .args(&selection_args())
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("Directory (or SQLite database) the stars are written to.")
+                        .takes_value(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("to")
+                        .long("to")
+                        .help("Format the stars are written in. Defaults to Sqlite for .db outputs and Toml (.toml and .mpk files) otherwise.")
+                        .takes_value(true)
+                        .possible_values(&StarFormat::variants())
+                        .case_insensitive(true),
+                ),
         )
         .get_matches();
 
-    println!("{}\n\n", include_str!("../COPYRIGHT"));
-
-    if matches.is_present("license") {
+    if arg_matches.is_present("license") {
         println!(
-            "{}\n\n{}",
+            "{}\n\n{}\n\n{}",
+            include_str!("../COPYRIGHT"),
             include_str!("../LICENSE"),
             include_str!("../CREDITS")
         );
         std::process::exit(0);
     }
 
-    let dc_norm = value_t_or_exit!(matches, "dc_norm", DCNorm);
-
-    let window_length = {
-        match matches.value_of("window_length") {
-            Some(win_len) => {
-                let win_len = usize::from_str(win_len)
-                    .expect("Trouble parsing window_length");
-                (win_len, win_len)
-            }
-            None => {
-                let min_len = matches
-                    .value_of("min_window_len")
-                    .expect("Must have window_length or min_window_len")
-                    .parse::<usize>()
-                    .expect("Trouble parsing min_window_len");
-                let max_len = matches
-                    .value_of("max_window_len")
-                    .expect("Must have window_length or max_window_len")
-                    .parse::<usize>()
-                    .expect("Trouble parsing max_window_len");
-
-                if max_len < min_len {
-                    panic!(
-                        "max_window_len must be greater than min_window_len"
-                    );
+    let detect = |sub_matches: &ArgMatches<'static>, mode, experiment: Option<ExperimentOpts>| {
+        if let Some(ref run_file) = run_file {
+            for arg in run_file.matrix_args() {
+                if sub_matches.occurrences_of(arg) > 0 {
+                    panic!("{} is swept by the run file matrix, it can not also be given on the command line", arg);
+                }
+                if experiment.is_some() && EXPERIMENT_FIXED_ARGS.contains(&arg) {
+                    panic!("A sweep reads its stars and templates once, {} can not be in the matrix", arg);
                 }
-
-                (min_len, max_len)
             }
         }
-    };
 
-    let detector_opts = DetectorOpts {
-        _rho: f32::from_str(
-            matches.value_of("rho").expect("Problem reading rho."),
-        )
-        .expect("Problem parsing rho."),
-        noise_stddev: f32::from_str(
-            matches.value_of("noise").expect("Problem reading noise"),
-        )
-        .expect("Problem parsing noise"),
-        window_length,
-        skip_delta: matches
-            .value_of("skip_delta")
-            .expect("Problem reading skip_delta")
-            .parse::<u32>()
-            .expect("Problem parsing skip_delta"),
-        alert_threshold: f32::from_str(
-            matches
-                .value_of("alert_threshold")
-                .expect("Problem reading alert_threshold"),
-        )
-        .expect("Problem parsing alert_threshold"),
-        // TODO
-        // - make plural
-        // - add check for greater than 0
-        fragment: matches
-            .value_of("fragment")
-            .expect("Problem reading fragment")
-            .parse::<u32>()
-            .expect("Problem parsing fragment"),
-        window_func: value_t_or_exit!(matches, "window_function", WindowFunc),
-        dc_norm,
-        star_group_sz: value_t_or_exit!(matches, "star_group_sz", usize),
+        Command::Detect(RunPlan::new(sub_matches, &mf_config, runs, mode, experiment))
     };
 
-    let log_opts = LogOpts {
-        sort: value_t_or_exit!(matches, "sort", SortOpt),
-        plot: value_t_or_exit!(matches, "plot", bool),
-    };
+    let command = match arg_matches.subcommand() {
+        ("run", Some(sub_matches)) => detect(sub_matches, RunMode::Offline, None),
+        ("live", Some(sub_matches)) => detect(sub_matches, RunMode::Live, None),
+        ("sweep", Some(sub_matches)) => {
+            let experiment = ExperimentOpts {
+                output: sub_matches
+                    .value_of("output")
+                    .expect("Problem reading output")
+                    .to_string(),
+            };
+            detect(sub_matches, RunMode::Offline, Some(experiment))
+        }
+        ("inspect-templates", Some(sub_matches)) => {
+            Command::InspectTemplates(InspectTemplatesOpts {
+                templates_file: mf_config.find_data_path(
+                    sub_matches
+                        .value_of("templates_file")
+                        .expect("Problem reading templates_file"),
+                ),
+                template_group_sz: value_t_or_exit!(sub_matches, "template_group_sz", usize),
+                dc_norm: value_t_or_exit!(sub_matches, "dc_norm", DCNorm),
+                star_group_sz: value_t_or_exit!(sub_matches, "star_group_sz", usize),
+                duplicate_match: value_t_or_exit!(sub_matches, "duplicate_match", f32),
+            })
+        }
+        ("prune-templates", Some(sub_matches)) => {
+            Command::PruneTemplates(PruneTemplatesOpts {
+                templates_file: mf_config.find_data_path(
+                    sub_matches
+                        .value_of("templates_file")
+                        .expect("Problem reading templates_file"),
+                ),
+                template_group_sz: value_t_or_exit!(sub_matches, "template_group_sz", usize),
+                dc_norm: value_t_or_exit!(sub_matches, "dc_norm", DCNorm),
+                min_match: value_t_or_exit!(sub_matches, "min_match", f32),
+                output: sub_matches
+                    .value_of("output")
+                    .expect("Problem reading output")
+                    .to_string(),
+                basis_energy: match sub_matches.value_of("basis_energy") {
+                    Some(_) => Some(value_t_or_exit!(sub_matches, "basis_energy", f32)),
+                    None => None,
+                },
+            })
+        }
+        ("inspect-stars", Some(sub_matches)) => Command::InspectStars(InspectStarsOpts {
+            input_dirs: input_dirs(sub_matches, &mf_config),
+            selection: star_selection(&ArgValues::of(sub_matches)),
+        }),
+        ("convert", Some(sub_matches)) => {
+            let output = sub_matches
+                .value_of("output")
+                .expect("Problem reading output")
+                .to_string();
 
-    let templates = parse_template_file(
-        matches
-            .value_of("templates_file")
-            .expect("Problem reading templates_file")
-            .to_string(),
-        value_t_or_exit!(matches, "template_group_sz", usize),
-        dc_norm,
-        // (Since not worked on throughly) [i.e. do not want in help documentation for Master's
-        // tagged release].
-        TemplateNorm::None, //value_t_or_exit!(matches, "template_norm", TemplateNorm)
-    );
-
-    let tester: Box<dyn Tester> = match value_t!(matches, "tartan_test", bool) {
-        Ok(val) if val => {
-            println!("Using the TARTAN.");
-            Box::new(TartanTester::new(&value_t_or_exit!(
-                matches,
-                "tartan_test_file",
-                String
-            )))
+            Command::Convert(ConvertOpts {
+                input_dirs: input_dirs(sub_matches, &mf_config),
+                format: match sub_matches.value_of("to") {
+                    Some(_) => value_t_or_exit!(sub_matches, "to", StarFormat),
+                    None => convert::output_format(&output),
+                },
+                output,
+                selection: star_selection(&ArgValues::of(sub_matches)),
+            })
+        }
+        _ => {
+            println!("{}", arg_matches.usage());
+            std::process::exit(1);
         }
-        _ => Box::new(NFDTester {}),
     };
 
-    let detector_trigger: Box<dyn DU::DetectorTrigger> =
-        match value_t_or_exit!(matches, "detector_trigger", DU::DetectorTriggerImps) {
-            DU::DetectorTriggerImps::NoneTrigger => {
-                Box::new(DU::NoneTrigger{})
+    Cli { mf_config, command }
+}
+
+fn star_selection(matches: &ArgValues) -> StarSelection {
+    StarSelection {
+        where_clause: matches.value_of("where").map(|where_clause| where_clause.to_string()),
+        ids: id_list(matches),
+        sample: match matches.value_of("sample") {
+            Some(_) => Some(value_t_or_exit!(matches, "sample", usize)),
+            None => None,
+        },
+        seed: value_t_or_exit!(matches, "seed", u64),
+        limit: match matches.value_of("limit") {
+            Some(_) => Some(value_t_or_exit!(matches, "limit", usize)),
+            None => None,
+        },
+    }
+}
+
+/// Ids of --ids and --ids-file (None when neither is given).
+fn id_list(matches: &ArgValues) -> Option<HashSet<String>> {
+    let mut ids: Option<HashSet<String>> = None;
+
+    if let Some(vals) = matches.values_of("ids") {
+        ids.get_or_insert_with(HashSet::new)
+            .extend(vals.map(|id| id.to_string()));
+    }
+
+    if let Some(ids_file) = matches.value_of("ids_file") {
+        let contents = fs::read_to_string(ids_file)
+            .unwrap_or_else(|_| panic!("Failed to read ids file {}", ids_file));
+        ids.get_or_insert_with(HashSet::new).extend(
+            contents
+                .lines()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty())
+                .map(|line| line.to_string()),
+        );
+    }
+
+    ids
+}
+
+fn input_dirs(matches: &ArgMatches, mf_config: &MFConfig) -> Vec<String> {
+    matches
+        .values_of("input_dir")
+        .expect("Problem reading input")
+        .map(|input_dir| mf_config.find_data_path(input_dir))
+        .collect()
+}
+
+impl RunPlan {
+    fn new(
+        arg_matches: &ArgMatches<'static>,
+        mf_config: &MFConfig,
+        runs: Vec<Run>,
+        mode: RunMode,
+        experiment: Option<ExperimentOpts>,
+    ) -> RunPlan {
+        RunPlan {
+            arg_matches: arg_matches.clone(),
+            mf_config: mf_config.clone(),
+            mode,
+            runs,
+            experiment,
+        }
+    }
+
+    /// Reads the stars and templates of a run.
+    ///
+    /// NOTE done per run so only one run's data is held in memory
+    pub fn run_info(&self, run: &Run) -> RunInfo {
+        self.build_run_info(run, None)
+    }
+
+    /// Run of an experiment, the stars and templates are read once into
+    /// the cache and then reused.
+    pub fn cached_run_info(&self, run: &Run, cache: &mut DataCache) -> RunInfo {
+        self.build_run_info(run, Some(cache))
+    }
+
+    fn build_run_info(&self, run: &Run, mut cache: Option<&mut DataCache>) -> RunInfo {
+        let mf_config = &self.mf_config;
+        let live_defaults: ArgDefaults = LIVE_DEFAULTS
+            .iter()
+            .map(|&(arg, default)| (arg, vec![default.to_string()]))
+            .collect();
+        let mut layers = vec![&run.args, mf_config.arg_defaults()];
+        // NOTE offline runs do not have the live options
+        if self.mode == RunMode::Offline {
+            layers.push(&live_defaults);
+        }
+        let matches = ArgValues {
+            matches: &self.arg_matches,
+            layers,
+        };
+
+        let dc_norm = value_t_or_exit!(matches, "dc_norm", DCNorm);
+
+        let window_length = {
+            match matches.value_of("window_length") {
+                Some(win_len) => {
+                    let win_len = usize::from_str(win_len)
+                        .expect("Trouble parsing window_length");
+                    (win_len, win_len)
+                }
+                None => {
+                    let min_len = matches
+                        .value_of("min_window_length")
+                        .expect("Must have window_length or min_window_length")
+                        .parse::<usize>()
+                        .expect("Trouble parsing min_window_length");
+                    let max_len = matches
+                        .value_of("max_window_length")
+                        .expect("Must have window_length or max_window_length")
+                        .parse::<usize>()
+                        .expect("Trouble parsing max_window_length");
+
+                    if max_len < min_len {
+                        panic!(
+                            "max_window_length must be greater than min_window_length"
+                        );
+                    }
+
+                    (min_len, max_len)
+                }
             }
-            DU::DetectorTriggerImps::ThresholdTrigger => {
-                Box::new(DU::ThresholdTrigger::new())
+        };
+
+        let mut detector_opts = DetectorOpts {
+            _rho: f32::from_str(
+                matches.value_of("rho").expect("Problem reading rho."),
+            )
+            .expect("Problem parsing rho."),
+            noise_stddev: f32::from_str(
+                matches.value_of("noise").expect("Problem reading noise"),
+            )
+            .expect("Problem parsing noise"),
+            window_length,
+            window_scales: window_scales(
+                window_length.0,
+                window_length.1,
+                value_t_or_exit!(matches, "window_scales", usize),
+            ),
+            skip_delta: matches
+                .value_of("skip_delta")
+                .expect("Problem reading skip_delta")
+                .parse::<u32>()
+                .expect("Problem parsing skip_delta"),
+            alert_threshold: f32::from_str(
+                matches
+                    .value_of("alert_threshold")
+                    .expect("Problem reading alert_threshold"),
+            )
+            .expect("Problem parsing alert_threshold"),
+            // TODO
+            // - make plural
+            // - add check for greater than 0
+            fragment: matches
+                .value_of("fragment")
+                .expect("Problem reading fragment")
+                .parse::<u32>()
+                .expect("Problem parsing fragment"),
+            window_func: value_t_or_exit!(matches, "window_function", WindowFunc),
+            dc_norm,
+            star_group_sz: value_t_or_exit!(matches, "star_group_sz", usize),
+            cpu_workers: value_t_or_exit!(matches, "cpu_workers", usize),
+            alert_classes: match matches.values_of("alert_classes") {
+                Some(_) => Some(values_t_or_exit!(matches, "alert_classes", DU::EventClass)),
+                None => None,
+            },
+            buffer_policy: value_t_or_exit!(matches, "buffer_policy", BufferPolicy),
+            max_queued_frames: value_t_or_exit!(matches, "max_queued_frames", usize),
+            lag_warn_frames: value_t_or_exit!(matches, "lag_warn_frames", usize),
+            missing_frame_policy: value_t_or_exit!(matches, "missing_frames", MissingFramePolicy),
+            evict_after: match value_t_or_exit!(matches, "evict_after", usize) {
+                0 => None,
+                frames => Some(frames),
+            },
+            cadence_mismatch: value_t_or_exit!(matches, "cadence_mismatch", CadenceMismatch),
+            // NOTE filled in once the templates are read
+            template_sample_rate: None,
+            max_lag: value_t_or_exit!(matches, "max_lag", usize),
+            watch_level: match matches.value_of("watch_level") {
+                Some(_) => Some(value_t_or_exit!(matches, "watch_level", f32)),
+                None => None,
+            },
+            watch_variability: match matches.value_of("watch_variability") {
+                Some(_) => Some(value_t_or_exit!(matches, "watch_variability", f32)),
+                None => None,
+            },
+            filter_budget: match value_t_or_exit!(matches, "filter_budget", usize) {
+                0 => None,
+                budget => Some(budget),
+            },
+            residual: value_t_or_exit!(matches, "residual", bool),
+        };
+
+        if detector_opts.max_lag == 0 {
+            panic!("max-lag must be greater than 0");
+        }
+
+        let log_opts = LogOpts {
+            sort: value_t_or_exit!(matches, "sort", SortOpt),
+            plot: value_t_or_exit!(matches, "plot", bool),
+            // NOTE live runs have no star database to write back to
+            write_results: match self.mode {
+                RunMode::Offline => value_t_or_exit!(matches, "write_results", WriteResults),
+                RunMode::Live => WriteResults::None,
+            },
+        };
+
+        let templates_file = mf_config.find_data_path(
+            matches
+                .value_of("templates_file")
+                .expect("Problem reading templates_file"),
+        );
+        let template_group_sz = value_t_or_exit!(matches, "template_group_sz", usize);
+        let template_basis_energy = match matches.value_of("template_basis_energy") {
+            Some(_) => Some(value_t_or_exit!(matches, "template_basis_energy", f32)),
+            None => None,
+        };
+        let read_templates = || {
+            let templates = parse_template_file(
+                templates_file.clone(),
+                template_group_sz,
+                dc_norm,
+                // (Since not worked on throughly) [i.e. do not want in help documentation for Master's
+                // tagged release].
+                TemplateNorm::None, //value_t_or_exit!(matches, "template_norm", TemplateNorm)
+            );
+
+            Arc::new(match template_basis_energy {
+                Some(energy) => templates.reduce_to_basis(energy),
+                None => templates,
+            })
+        };
+        let templates = match cache {
+            Some(ref mut cache) => cache
+                .templates
+                .entry(format!("{} {:?}", dc_norm, template_basis_energy))
+                .or_insert_with(read_templates)
+                .clone(),
+            None => read_templates(),
+        };
+        detector_opts.template_sample_rate = templates.sample_rate;
+        if templates.sample_rate.is_none() {
+            // NOTE star cadences can only be matched to a bank that has one
+            let log = log::get_root_logger();
+            warn!(log, "Template bank has no sample_rate, the cadence check is skipped.";
+                  "templates_file"=>&templates_file);
+        }
+
+        let tester: Box<dyn Tester> = match value_t!(matches, "tartan_test", bool) {
+            Ok(val) if val => {
+                println!("Using the TARTAN.");
+                Box::new(TartanTester::new(&value_t_or_exit!(
+                    matches,
+                    "tartan_test_file",
+                    String
+                )))
             }
-            DU::DetectorTriggerImps::ThreeInARowTrigger => {
-                Box::new(DU::ThreeInARowTrigger::new())
+            _ => Box::new(NFDTester {}),
+        };
+
+        let trigger_opts = DU::TriggerOpts {
+            k: value_t_or_exit!(matches, "trigger_k", usize),
+            n: value_t_or_exit!(matches, "trigger_n", usize),
+            disarm_threshold: match matches.value_of("disarm_threshold") {
+                Some(_) => Some(value_t_or_exit!(matches, "disarm_threshold", f32)),
+                None => None,
+            },
+            cooldown: value_t_or_exit!(matches, "trigger_cooldown", usize),
+        };
+
+        if trigger_opts.k == 0 || trigger_opts.k > trigger_opts.n {
+            panic!("trigger-k must be greater than 0 and less than or equal to trigger-n");
+        }
+
+        if let Some(disarm_threshold) = trigger_opts.disarm_threshold {
+            if disarm_threshold > detector_opts.alert_threshold {
+                panic!("disarm-threshold must be less than or equal to alert-threshold");
             }
+        }
+
+        let curve_trigger_opts = DU::CurveTriggerOpts {
+            history_len: value_t_or_exit!(matches, "curve_history", usize),
+            min_rise_fraction: value_t_or_exit!(matches, "curve_min_rise", f32),
+            max_step_fraction: value_t_or_exit!(matches, "curve_max_step", f32),
         };
 
-    // NOTE for simplicity do not allow offline and gwac_files
-    //      to be on at same time
-    if let Some(input_dirs) = matches.values_of("input_dir") {
-        let stars = parse_star_files(
-            &input_dirs.collect::<Vec<&str>>(),
-            &detector_opts,
-        );
+        if curve_trigger_opts.history_len < 3 {
+            panic!("curve-history must be at least 3");
+        }
 
-        return RunInfo {
-            templates,
-            stars,
-            gwac_reader: None,
-            // [ ] TODO see earlier fixme
-            detector_opts,
-            log_opts,
-            tester,
-            detector_trigger,
+        let detector_trigger: Box<dyn DU::DetectorTrigger> =
+            match value_t_or_exit!(matches, "detector_trigger", DU::DetectorTriggerImps) {
+                DU::DetectorTriggerImps::NoneTrigger => {
+                    Box::new(DU::NoneTrigger{})
+                }
+                DU::DetectorTriggerImps::ThresholdTrigger => {
+                    Box::new(DU::ThresholdTrigger::new(trigger_opts))
+                }
+                DU::DetectorTriggerImps::ThreeInARowTrigger => {
+                    Box::new(DU::KOfNTrigger::new(DU::TriggerOpts {
+                        k: 3,
+                        n: 3,
+                        ..trigger_opts
+                    }))
+                }
+                DU::DetectorTriggerImps::KOfNTrigger => {
+                    Box::new(DU::KOfNTrigger::new(trigger_opts))
+                }
+                DU::DetectorTriggerImps::CurveTrigger => {
+                    Box::new(DU::CurveTrigger::new(trigger_opts, curve_trigger_opts))
+                }
+            };
+
+        let veto_opts = veto::VetoOpts {
+            spike_sigma: value_t_or_exit!(matches, "veto_spike_sigma", f32),
+            flare_sigma: value_t_or_exit!(matches, "veto_flare_sigma", f32),
+            flare_step_fraction: value_t_or_exit!(matches, "veto_flare_step", f32),
+            ..Default::default()
+        };
+
+        let veto: Box<dyn veto::Veto> =
+            match value_t_or_exit!(matches, "veto", veto::VetoImps) {
+                veto::VetoImps::NoneVeto => Box::new(veto::NoneVeto {}),
+                veto::VetoImps::DerivativeVeto => {
+                    Box::new(veto::DerivativeVeto::new(veto_opts))
+                }
+            };
+
+        let (stars, gwac_reader, results_db) = match self.mode {
+            RunMode::Offline => {
+                let input_dirs: Vec<String> = matches
+                    .values_of("input_dir")
+                    .expect("Problem reading input")
+                    .map(|input_dir| mf_config.find_data_path(input_dir))
+                    .collect();
+                let selection = star_selection(&matches);
+                let read_stars = || {
+                    // NOTE star models can be plugin predictors, so the
+                    //      plugins have to be up before the stars are read
+                    python::start_plugins(&mf_config.python_plugin_directories);
+
+                    parse_star_files(&input_dirs, &selection)
+                };
+                let stars = match cache {
+                    Some(ref mut cache) => cache.stars.get_or_insert_with(read_stars).clone(),
+                    None => read_stars(),
+                };
+
+                let results_db = match log_opts.write_results {
+                    WriteResults::None => None,
+                    _ if input_dirs.len() == 1 && is_star_db(&input_dirs[0]) => {
+                        Some(input_dirs[0].clone())
+                    }
+                    _ => panic!("--write-results writes back into the star database, the input must be a single SQLite star database (.db)"),
+                };
+
+                (sw_stars(stars, &detector_opts), None, results_db)
+            }
+            RunMode::Live => {
+                if cache.is_some() {
+                    panic!("Sweeps run on offline stars (input) only");
+                }
+
+                let gwac_files = matches
+                    .values_of("gwac_file")
+                    .expect("Problem reading gwac_file")
+                    .collect::<Vec<&str>>();
+
+                (Vec::new(), Some(GWACReaders::new(&gwac_files)), None)
+            }
         };
-    }
 
-    // NOTE for simplicity do not allow offline and gwac_files
-    //      to be on at same time
-    if let Some(gwac_file) = matches.value_of("gwac_file") {
-        return RunInfo {
+        RunInfo {
             templates,
-            stars: Vec::new(),
-            gwac_reader: Some(GWACReader::new(gwac_file)),
+            stars,
+            gwac_reader,
             // [ ] TODO see earlier fixme
             detector_opts,
             log_opts,
             tester,
             detector_trigger,
-        };
+            veto,
+            results_db,
+        }
     }
-
-    panic!("Should never make it here");
 }
@@ -0,0 +1,140 @@
+use crate::cli::{for_each_star, ConvertOpts};
+use crate::dat_star;
+use crate::json_star;
+use crate::sqlite_stars::{self, StarWriter};
+use crate::star::Star;
+use crate::toml_star;
+use crate::utils;
+use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
+
+arg_enum! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    /// Star storage formats
+    ///
+    /// Toml
+    /// - a star file (.toml) and its msgpack samples (.mpk) per star
+    ///
+    /// Sqlite
+    /// - a stars row per star in a SQLite star database (.db, see sqlite_stars)
+    ///
+    /// Json
+    /// - a GWAC style JSON file per star
+    ///
+    /// Dat
+    /// - a (time, f(t)) text file per star
+    pub enum StarFormat {
+        Toml,
+        Sqlite,
+        Json,
+        Dat,
+    }
+}
+
+/// Format of an output when none is given, databases (.db) are SQLite.
+pub fn output_format(output: &str) -> StarFormat {
+    if output.ends_with(".db") {
+        StarFormat::Sqlite
+    } else {
+        StarFormat::Toml
+    }
+}
+
+trait StarSink {
+    fn write(&mut self, star: &Star);
+    fn finish(self: Box<Self>) {}
+}
+
+/// A file per star in a directory.
+struct DirSink {
+    dir: PathBuf,
+    write_star_file: fn(&Star, &std::path::Path),
+    /// file names (see utils::star_file_name) of the stars written so far
+    names: HashSet<String>,
+}
+
+impl StarSink for DirSink {
+    fn write(&mut self, star: &Star) {
+        // NOTE ids are not unique across inputs, a star would overwrite
+        //      the file of an earlier one
+        if !self.names.insert(utils::star_file_name(&star.id)) {
+            panic!(
+                "Star {} ({}) has the file name of an earlier star in {}",
+                star.id,
+                star.uid,
+                self.dir.display()
+            );
+        }
+
+        (self.write_star_file)(star, &self.dir);
+    }
+}
+
+impl<'c> StarSink for StarWriter<'c> {
+    fn write(&mut self, star: &Star) {
+        StarWriter::write(self, star);
+    }
+
+    fn finish(self: Box<Self>) {
+        StarWriter::finish(*self);
+    }
+}
+
+// NOTE a SQLite sink writes through the connection (see sqlite_stars::open_stars)
+fn star_sink<'c>(
+    output: &str,
+    format: StarFormat,
+    connection: Option<&'c sqlite::Connection>,
+) -> Box<dyn StarSink + 'c> {
+    let write_star_file: fn(&Star, &std::path::Path) = match format {
+        StarFormat::Sqlite => {
+            let connection = connection.expect("SQLite star sink without a connection");
+            return Box::new(StarWriter::new(connection, output));
+        }
+        StarFormat::Toml => toml_star::write_star_file,
+        StarFormat::Json => json_star::write_star_file,
+        StarFormat::Dat => dat_star::write_star_file,
+    };
+
+    fs::create_dir_all(output)
+        .unwrap_or_else(|_| panic!("Failed to create output directory {}", output));
+
+    Box::new(DirSink {
+        dir: PathBuf::from(output),
+        write_star_file,
+        names: HashSet::new(),
+    })
+}
+
+/// Converts offline star data (convert), the stars are streamed from the
+/// inputs into the output one at a time.
+///
+/// NOTE ids and sample rates are kept, star models are not converted
+pub fn convert(opts: &ConvertOpts) {
+    let connection = match opts.format {
+        StarFormat::Sqlite => Some(sqlite_stars::open_stars(&opts.output)),
+        _ => None,
+    };
+    let mut sink = star_sink(&opts.output, opts.format, connection.as_ref());
+
+    let mut num_written = 0;
+    for_each_star(&opts.input_dirs, &opts.selection, &mut |star| {
+        sink.write(&star);
+        num_written += 1;
+    });
+    sink.finish();
+
+    println!("Converted {} stars into {} ({})", num_written, opts.output, opts.format);
+
+    let selection = &opts.selection;
+    // NOTE the other selections can leave ids of the list out on purpose
+    if let Some(ref ids) = selection.ids {
+        let ids_only = selection.where_clause.is_none()
+            && selection.sample.is_none()
+            && selection.limit.is_none();
+        if ids_only && num_written < ids.len() {
+            println!("{} ids of the id list were not found", ids.len() - num_written);
+        }
+    }
+}
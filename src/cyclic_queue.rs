@@ -109,6 +109,89 @@ impl<T> CyclicQueueInterface for CyclicQueue<T> {
     }
 }
 
+/// Cyclic queue whose values (front to back) are always one contiguous slice.
+///
+/// Every value is stored twice, `cap` apart, so the `len` values starting
+/// at `front` never wrap around the end of the storage.
+#[derive(Debug)]
+pub struct ContiguousCyclicQueue<T> {
+    data: Vec<T>,
+    len: usize,
+    cap: usize,
+    front: usize,
+}
+
+impl<T: Default + Clone> ContiguousCyclicQueue<T> {
+    pub fn new(cap: usize) -> ContiguousCyclicQueue<T> {
+        ContiguousCyclicQueue {
+            data: vec![T::default(); 2 * cap],
+            len: 0,
+            cap,
+            front: 0,
+        }
+    }
+}
+
+impl<T> ContiguousCyclicQueue<T> {
+    /// Front (oldest) to back (newest) values.
+    pub fn as_slice(&self) -> &[T] {
+        &self.data[self.front..self.front + self.len]
+    }
+
+    /// The newest `n` values (all of them if there are fewer).
+    pub fn last_n(&self, n: usize) -> &[T] {
+        let slice = self.as_slice();
+
+        &slice[slice.len().saturating_sub(n)..]
+    }
+
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.front = 0;
+    }
+}
+
+impl<T: Clone> CyclicQueueInterface for ContiguousCyclicQueue<T> {
+    type VAL_TYPE = T;
+
+    fn max_capacity(&self) -> usize {
+        self.cap
+    }
+
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get_relative(&self, i: usize) -> Option<&T> {
+        self.as_slice().get(i)
+    }
+
+    fn get_back(&self) -> Option<&T> {
+        self.as_slice().last()
+    }
+
+    fn push(&mut self, val: T) -> Option<T> {
+        if self.cap == 0 {
+            return Some(val);
+        }
+
+        let pos = (self.front + self.len) % self.cap;
+        self.data[pos + self.cap] = val.clone();
+
+        if self.len == self.cap {
+            // NOTE the back wrapped onto the front
+            self.front = (self.front + 1) % self.cap;
+
+            Some(mem::replace(&mut self.data[pos], val))
+        } else {
+            self.len += 1;
+            self.data[pos] = val;
+
+            None
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -343,4 +426,40 @@ mod tests {
         let val = queue.get_relative(6);
         assert_eq!(val, None);
     }
+
+    #[test]
+    fn test_contiguous_push() {
+        let mut queue = ContiguousCyclicQueue::new(3);
+
+        assert_eq!(queue.as_slice(), &[] as &[i32]);
+        assert_eq!(queue.push(1), None);
+        assert_eq!(queue.push(2), None);
+        assert_eq!(queue.as_slice(), &[1, 2]);
+        assert_eq!(queue.push(3), None);
+        assert_eq!(queue.push(4), Some(1));
+        assert_eq!(queue.as_slice(), &[2, 3, 4]);
+        assert_eq!(queue.push(5), Some(2));
+        assert_eq!(queue.push(6), Some(3));
+        assert_eq!(queue.push(7), Some(4));
+        assert_eq!(queue.as_slice(), &[5, 6, 7]);
+        assert_eq!(queue.last_n(2), &[6, 7]);
+        assert_eq!(queue.last_n(5), &[5, 6, 7]);
+        assert_eq!(queue.get_relative(0), Some(&5));
+        assert_eq!(queue.get_back(), Some(&7));
+        assert_eq!(queue.len(), 3);
+    }
+
+    #[test]
+    fn test_contiguous_clear() {
+        let mut queue = ContiguousCyclicQueue::new(2);
+
+        queue.push(1);
+        queue.push(2);
+        queue.push(3);
+        queue.clear();
+        assert_eq!(queue.len(), 0);
+        assert_eq!(queue.get_back(), None);
+        queue.push(4);
+        assert_eq!(queue.as_slice(), &[4]);
+    }
 }
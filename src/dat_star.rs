@@ -3,12 +3,17 @@
  * space separated data
  * (time, f(t), tE, start_col, end_col)
  * unsure what everything is but should only need second column
- * -- assumption that everything is sampled at 15 seconds
+ * -- the cadence is estimated from the time column (in seconds),
+ *    falling back to the GWAC 15 second sampling
  * --- For reading GWAC gen data
+ * --- the file stem is the star id
  */
 
+use crate::cadence::{estimate_cadence, GWAC_SAMPLE_RATE};
 use crate::star::{parse_model, Star, StarModelType, StarType};
+use crate::utils;
 use std::fs;
+use std::path::Path;
 
 pub fn parse_star_file(star_file: &str) -> Star {
     let contents =
@@ -23,16 +28,46 @@ pub fn parse_star_file(star_file: &str) -> Star {
         })
         .collect();
 
+    let times: Vec<f32> = contents
+        .lines()
+        .filter_map(|line| line.split_ascii_whitespace().next())
+        .filter_map(|time| time.parse::<f32>().ok())
+        .collect();
+    let sample_rate = match estimate_cadence(&times) {
+        Some(cadence) if cadence >= 1.0 => cadence.round() as i32,
+        _ => GWAC_SAMPLE_RATE,
+    };
+
     //crate::utils::debug_plt(&star_data, &star_file.to_string(), None);
 
     Star {
-        id: star_file.to_string(),
+        id: Path::new(star_file)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .unwrap_or(star_file)
+            .to_string(),
         uid: star_file.to_string(),
         samples: Some(star_data),
-        samples_tick_index: std::cell::RefCell::new(0),
+        samples_tick_index: std::sync::atomic::AtomicUsize::new(0),
+        position: None,
         star_type: StarType::Unknown,
         model_type: StarModelType::None,
         model: parse_model(StarModelType::None, "".to_string()),
-        sample_rate: 15,
+        sample_rate,
     }
 }
+
+/// Writes a star as a DAT file (`<id>.dat`) into the directory, a
+/// (time, f(t)) line per sample.
+pub fn write_star_file(star: &Star, dir: &Path) {
+    let samples = star.samples.as_ref().expect("Star has no samples to write");
+    let contents = samples
+        .iter()
+        .enumerate()
+        // NOTE f(t) is negated when read
+        .map(|(idx, sample)| format!("{} {}\n", idx as i64 * star.sample_rate as i64, -1.0 * sample))
+        .collect::<String>();
+
+    fs::write(dir.join(format!("{}.dat", utils::star_file_name(&star.id))), contents)
+        .unwrap_or_else(|_| panic!("Failed to write Star DAT file of {}", star.id));
+}
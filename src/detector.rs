@@ -1,29 +1,37 @@
-use crate::async_utils::TwinBarrier;
+use crate::async_utils::with_shutdown;
 use crate::cli::DetectorOpts;
 use crate::filter::inner_product;
+use crate::filter_scheduler::FilterScheduler;
+use crate::filter_utils::forget_historical_means;
 use crate::info_handler::InformationHandler;
 use crate::log;
-use crate::sw_star::SWStar;
+use crate::sqlite_stars::ResultsWriter;
 use crate::template::Templates;
 use crate::tester::Tester;
-use crate::detector_utils::DetectorTrigger;
+use crate::ticker::WindowSnapshot;
+use crate::detector_utils::{best_window_scales, ClassScores, DetectorTrigger};
+use crate::veto::Veto;
 
 use colored::*;
 use std::collections::HashMap;
 use std::panic;
 use std::sync::Arc;
-use tokio::sync::Lock;
+use tokio::sync::mpsc::Receiver;
 
 pub struct Detector {
-    tick_barrier: TwinBarrier,
-    computation_barrier: TwinBarrier,
+    snapshot_rx: Receiver<WindowSnapshot>,
     info_handler: Arc<InformationHandler>,
-    stars: Lock<Vec<SWStar>>,
-    templates: Templates,
+    // NOTE original star data (only kept for plotting)
+    original_data: HashMap<String, Vec<f32>>,
+    templates: Arc<Templates>,
     tester: Box<dyn Tester>,
     detector: Box<dyn DetectorTrigger>,
+    veto: Box<dyn Veto>,
+    scheduler: Arc<FilterScheduler>,
     detector_opts: DetectorOpts,
     should_plot: bool,
+    // NOTE Some when the run writes its results back into the star database
+    results: Option<ResultsWriter>,
 }
 
 impl Detector {
@@ -38,99 +46,58 @@ impl Detector {
     ) {
         let sd_rx = self.info_handler.get_shutdown_receiver();
         let log = log::get_root_logger();
-        let mut sample_time = 0;
         let mut true_events = 0;
         let mut false_events = 0;
 
         let mut data: HashMap<String, Vec<f32>> = HashMap::new();
-        let mut data2: HashMap<String, Vec<f32>> = HashMap::new();
+        let data2 = std::mem::replace(&mut self.original_data, HashMap::new());
         let mut adps: Vec<f32> = Vec::new();
 
-        {
-            let stars = self.stars.lock().await;
-            stars.iter().for_each(|sw| {
-                if let Some(samps) = sw.star.samples.as_ref() {
-                    // NOTE do not store original data if plot is off (for memory space reasons)
-                    if self.should_plot {
-                        data2.insert(sw.star.uid.clone(), samps.clone());
-                    }
-                };
-            });
-        }
-
-        match self.computation_barrier.wait().await {
-            Err(msg) => {
-                if *sd_rx.get_ref() {
-                    info!(log, "Received finished signal...");
+        loop {
+            // NOTE the ticker closing the channel (out of data) ends the run
+            //      after the queued snapshots, shutdown ends it right away
+            let snapshot = match with_shutdown(self.snapshot_rx.recv(), sd_rx.clone()).await {
+                Some(Some(snapshot)) => snapshot,
+                Some(None) => {
+                    info!(log, "Ticker finished, detector done...");
+                    self.finish_results();
                     return (data, data2, adps, true_events, false_events);
-                } else {
-                    panic!(msg);
                 }
-            }
-            _ => ()
-        }
-
-        loop {
-            // NOTE check for shutdown before locking
-            if *sd_rx.get_ref() {
-                info!(log, "Received finished signal...");
-                return (data, data2, adps, true_events, false_events);
-            }
-            match self.tick_barrier.wait().await {
-                Err(msg) => {
-                    if *sd_rx.get_ref() {
-                        info!(log, "Received finished signal...");
-                        return (data, data2, adps, true_events, false_events);
-                    } else {
-                        panic!(msg);
-                    }
+                None => {
+                    info!(log, "Detector received finished signal...");
+                    self.finish_results();
+                    return (data, data2, adps, true_events, false_events);
                 }
-                _ => ()
-            }
-
-            let (windows, window_names) = {
-                let stars = self.stars.lock().await;
-
-                let window_names = stars
-                    .iter()
-                    .filter_map(|sw| {
-                        if sw.is_ready() {
-                            Some(sw.star.uid.clone())
-                        } else {
-                            None
-                        }
-                    })
-                    .collect::<Vec<String>>();
-
-                let windows = stars
-                    .iter()
-                    .filter_map(|sw| sw.window())
-                    .collect::<Vec<Vec<f32>>>();
-
-                (windows, window_names)
             };
 
-            // NOTE check for shutdown before locking
-            if *sd_rx.get_ref() {
-                info!(log, "Detector received finished signal...");
-                return (data, data2, adps, true_events, false_events);
-            }
-            // NOTE signals can modify stars because now only
-            //      working with copied data and not refs
-            match self.computation_barrier.wait().await {
-                Err(msg) => {
-                    if *sd_rx.get_ref() {
-                        info!(log, "Received finished signal...");
-                        return (data, data2, adps, true_events, false_events);
-                    } else {
-                        panic!(msg);
-                    }
-                }
-                _ => ()
-            }
+            let WindowSnapshot {
+                tick: sample_time,
+                names: window_names,
+                windows,
+                window_lens,
+                positions,
+                predictions,
+                skip_filter,
+                evicted,
+            } = snapshot;
 
+            // NOTE per star state would otherwise grow for the whole of a
+            //      live run, an evicted star that comes back starts over
+            evicted.iter().for_each(|star| {
+                self.detector.forget(star);
+                self.veto.forget(star);
+                self.scheduler.forget(star);
+                forget_historical_means(star);
+                if !self.should_plot {
+                    data.remove(star);
+                }
+            });
 
-            sample_time += 1;
+            // NOTE the stars are still ticked, only the filtering of
+            //      a lagging live frame is skipped so the detector catches up
+            if skip_filter {
+                continue;
+            }
 
             // NOTE gracefully handle bugs in CUDA and NVIDIA drivers
             //      along with any other bugs in Arrayfire
@@ -144,17 +111,35 @@ impl Detector {
             // - NOTE should be fine to AssertUnwindSafe, main compile issues
             //   seem to come from being within an async context.
             //   - The actual inner_product and arguments should be fine.
+            // NOTE per star state of the filter (historical means) is kept
+            //      per window length, the longest (whole) window of a star
+            //      keeps the star name as with a single window scale
+            // - shorter scales have a fixed length, scales longer than the
+            //   window so far collapse into the whole window
+            let filter_names = (0..window_names.len())
+                .map(|i| {
+                    let name = &window_names[i];
+                    let is_whole_window = window_names.get(i + 1) != Some(name);
+                    if is_whole_window {
+                        name.clone()
+                    } else {
+                        format!("{}@{}", name, window_lens[i])
+                    }
+                })
+                .collect::<Vec<String>>();
+
             let ip = panic::catch_unwind(panic::AssertUnwindSafe(|| {
                 inner_product(
-                    &self.templates.templates[..],
+                    &self.templates,
                     &windows,
-                    &window_names,
+                    &filter_names,
                     sample_time,
                     self.detector_opts.noise_stddev,
                     true,
                     self.detector_opts.dc_norm,
                     self.detector_opts.window_func,
                     self.detector_opts.star_group_sz,
+                    self.detector_opts.cpu_workers,
                 )
             }));
 
@@ -163,12 +148,34 @@ impl Detector {
                 _ => continue,
             };
 
-            // TODO rework so that we first insert into data
-            //      then pass each data to a detection function
-            //      that takes a threshold, this allows us to
-            //      apply different things such as a flare remover
-            //      or glitch remover, etc.
-            ip.iter().zip(window_names).for_each(|(val, star)| {
+            // NOTE values are first inserted into data and then passed
+            //      through the veto stage (flare/glitch removal) before
+            //      reaching the detection function
+            // NOTE only the best window scale of each star carries on
+            let best = best_window_scales(&self.templates.classes, &ip, &window_names, &window_lens);
+            best.into_iter().for_each(|i| {
+                let star = window_names[i].clone();
+                let class_scores = &ip[i];
+                let window = &windows[i];
+                let window_len = window_lens[i];
+                let scores = ClassScores {
+                    classes: &self.templates.classes,
+                    scores: class_scores,
+                };
+                let val = scores.best_score();
+                self.scheduler.report_score(&star, val);
+                if let Some(ref mut results) = self.results {
+                    results.score(&star, sample_time, val);
+                }
+
+                if let Some(prediction) = predictions.get(&star) {
+                    debug!(log, "Star model prediction.";
+                           "time"=>sample_time.to_string(),
+                           "star"=>star.to_string(),
+                           "prediction"=>prediction.to_string(),
+                           "last"=>window.last().map(|val| val.to_string()).unwrap_or_default());
+                }
+
                 if !data.contains_key(&star) {
                     data.insert(star.clone(), Vec::new());
                 }
@@ -177,21 +184,52 @@ impl Detector {
                 if self.should_plot {
                     data.get_mut(&star)
                         .expect("Star should be in inner_product data map.")
-                        .push(*val);
+                        .push(val);
+                }
+
+                if let Some(reason) = self.veto.check(&star, window, val,
+                                                      self.detector_opts.alert_threshold) {
+                    warn!(log, "{}", "EVENT VETOED".on_yellow();
+                          "time"=>sample_time.to_string(),
+                          "star"=>star.to_string(),
+                          "val"=>val.to_string(),
+                          "window_len"=>window_len.to_string(),
+                          "reason"=>reason.to_string(),
+                    );
+                    return;
                 }
 
                 //let vals = data.get(&star).expect("Star should be in inner_product data map.");
-                match self.detector.detect(&star, *val, sample_time,
+                match self.detector.detect(&star, &scores, sample_time,
                                            self.detector_opts.alert_threshold) {
-                    Some(_detector_res) => {
+                    Some(detector_res) => {
+                        // NOTE route alerts by the guessed event type
+                        if let Some(alert_classes) = self.detector_opts.alert_classes.as_ref() {
+                            if !alert_classes.contains(&detector_res.class) {
+                                info!(log, "{}", "EVENT NOT ALERTED".on_yellow();
+                                      "time"=>sample_time.to_string(),
+                                      "star"=>star.to_string(),
+                                      "val"=>val.to_string(),
+                                      "window_len"=>window_len.to_string(),
+                                      "class"=>detector_res.class.to_string(),
+                                      "confidence"=>detector_res.confidence.to_string(),
+                                );
+                                return;
+                            }
+                        }
+
                         // compute values b/c tester is a valid tester
-                        if self.tester.is_valid() {
-                            if self.tester.is_true_positive(&star, sample_time) {
+                        let true_event = if self.tester.is_valid() {
+                            let is_true_positive = self.tester.is_true_positive(&star, sample_time);
+                            if is_true_positive {
                                 adps.push(self.tester.adp(&star, sample_time));
                                 crit!(log, "{}", "TRUE EVENT DETECTED".on_blue();
                                       "time"=>sample_time.to_string(),
                                       "star"=>star.to_string(),
                                       "val"=>val.to_string(),
+                                      "window_len"=>window_len.to_string(),
+                                      "class"=>detector_res.class.to_string(),
+                                      "confidence"=>detector_res.confidence.to_string(),
                                 );
                                 true_events += 1;
                             } else {
@@ -200,9 +238,43 @@ impl Detector {
                                       "time"=>sample_time.to_string(),
                                       "star"=>star.to_string(),
                                       "val"=>val.to_string(),
+                                      "window_len"=>window_len.to_string(),
+                                      "class"=>detector_res.class.to_string(),
+                                      "confidence"=>detector_res.confidence.to_string(),
                                 );
                                 false_events += 1;
                             }
+
+                            Some(is_true_positive)
+                        } else {
+                            None
+                        };
+
+                        if let Some(position) = positions.get(&star) {
+                            // NOTE live stars are alerted with where to look
+                            crit!(log, "{}", "EVENT DETECTED".on_blue();
+                                  "time"=>sample_time.to_string(),
+                                  "star"=>star.to_string(),
+                                  "val"=>val.to_string(),
+                                  "window_len"=>window_len.to_string(),
+                                  "class"=>detector_res.class.to_string(),
+                                  "confidence"=>detector_res.confidence.to_string(),
+                                  "ra"=>position.ra.to_string(),
+                                  "dec"=>position.dec.to_string(),
+                                  "xpix"=>position.xpix.to_string(),
+                                  "ypix"=>position.ypix.to_string(),
+                            );
+                        }
+
+                        if let Some(ref mut results) = self.results {
+                            results.event(
+                                &star,
+                                sample_time,
+                                val,
+                                &detector_res.class.to_string(),
+                                detector_res.confidence,
+                                true_event,
+                            );
                         }
                     }
                     None => {}
@@ -212,26 +284,35 @@ impl Detector {
     }
 
     pub fn new(
-        tick_barrier: TwinBarrier,
-        computation_barrier: TwinBarrier,
+        snapshot_rx: Receiver<WindowSnapshot>,
         info_handler: Arc<InformationHandler>,
-        stars: Lock<Vec<SWStar>>,
-        templates: Templates,
+        original_data: HashMap<String, Vec<f32>>,
+        templates: Arc<Templates>,
         tester: Box<dyn Tester>,
         detector: Box<dyn DetectorTrigger>,
+        veto: Box<dyn Veto>,
+        scheduler: Arc<FilterScheduler>,
         detector_opts: DetectorOpts,
         should_plot: bool,
+        results: Option<ResultsWriter>,
     ) -> Detector {
         Detector {
-            tick_barrier,
-            computation_barrier,
+            snapshot_rx,
             info_handler,
-            stars,
+            original_data,
             templates,
             tester,
             detector_opts,
             detector,
+            veto,
+            scheduler,
             should_plot,
+            results,
         }
     }
+
+    // NOTE the writer writes the rest of the results when dropped
+    fn finish_results(&mut self) {
+        self.results = None;
+    }
 }
@@ -1,4 +1,4 @@
-use std::collections::{HashSet, HashMap};
+use std::collections::HashMap;
 use crate::cyclic_queue::{CyclicQueue, CyclicQueueInterface};
 
 arg_enum! {
@@ -6,115 +6,626 @@ arg_enum! {
     pub enum DetectorTriggerImps {
         NoneTrigger,
         ThresholdTrigger,
+        // NOTE kept for older run scripts, same as KOfNTrigger with k=3 n=3
         ThreeInARowTrigger,
+        KOfNTrigger,
+        CurveTrigger,
     }
 }
 
-// NOTE allows for expansion to include detected (guess) type
-//      - flare, microlensing, etc. (positive, maybe positive, etc.)
-pub struct DetectorResult {}
+arg_enum! {
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    /// Type of event a template bank (or part of one) is built for.
+    pub enum EventClass {
+        Microlensing,
+        Flare,
+        Eclipse,
+        Nova,
+    }
+}
+
+/// Filter output of one star for each template class.
+///
+/// NOTE scores are in the same order as classes (see `Templates::classes`)
+pub struct ClassScores<'a> {
+    pub classes: &'a [EventClass],
+    pub scores: &'a [f32],
+}
+
+impl<'a> ClassScores<'a> {
+    /// Winning class and its score.
+    pub fn best(&self) -> (EventClass, f32) {
+        self.classes
+            .iter()
+            .cloned()
+            .zip(self.scores.iter().cloned())
+            .fold(None, |acc: Option<(EventClass, f32)>, (class, score)| match acc {
+                Some((_, best)) if best >= score => acc,
+                _ => Some((class, score)),
+            })
+            .expect("Should have at least one template class.")
+    }
+
+    /// Score the triggers compare against the threshold.
+    pub fn best_score(&self) -> f32 {
+        self.best().1
+    }
+
+    /// Margin of the winning class over the runner up relative to the winning score.
+    /// - 1.0 when there is only one class
+    pub fn confidence(&self) -> f32 {
+        if self.scores.len() < 2 {
+            return 1.0;
+        }
+
+        let mut sorted = self.scores.to_vec();
+        // NOTE total order so a NaN score does not panic
+        sorted.sort_unstable_by(|a, b| b.total_cmp(a));
+        let (best, runner_up) = (sorted[0], sorted[1]);
+
+        ((best - runner_up) / best.abs().max(std::f32::EPSILON)).min(1.0).max(0.0)
+    }
+
+    pub fn to_result(&self) -> DetectorResult {
+        DetectorResult {
+            class: self.best().0,
+            class_scores: self.scores.to_vec(),
+            confidence: self.confidence(),
+        }
+    }
+}
+
+/// Index of the best scoring window scale of every star.
+///
+/// The scales of a star are adjacent in `names` (see `WindowSnapshot`),
+/// ties go to the shorter (earlier) scale.
+///
+/// NOTE the inner product sums over the window, so scores are compared
+///      per square root of the window length (the growth of its noise)
+///      or the longest window would win on length alone
+pub fn best_window_scales(
+    classes: &[EventClass],
+    scores: &[Vec<f32>],
+    names: &[String],
+    window_lens: &[usize],
+) -> Vec<usize> {
+    let best_score = |i: usize| {
+        ClassScores { classes, scores: &scores[i] }.best_score() / (window_lens[i].max(1) as f32).sqrt()
+    };
+
+    let mut best: Vec<usize> = Vec::new();
+    for i in 0..names.len().min(scores.len()) {
+        match best.last_mut() {
+            Some(last) if names[*last] == names[i] => {
+                if best_score(i) > best_score(*last) {
+                    *last = i;
+                }
+            }
+            _ => best.push(i),
+        }
+    }
+
+    best
+}
+
+/// Detection (guess) including the type of event - flare, microlensing, etc.
+pub struct DetectorResult {
+    pub class: EventClass,
+    /// scores in the same order as `Templates::classes`
+    pub class_scores: Vec<f32>,
+    pub confidence: f32,
+}
 
 pub trait DetectorTrigger {
-    fn detect(&mut self, star: &str, val: f32, curren_time: usize, threshold: f32)
+    /// NOTE triggers compare the best class score against the threshold
+    fn detect(&mut self, star: &str, scores: &ClassScores, curren_time: usize, threshold: f32)
               -> Option<DetectorResult>;
+
+    /// Drops the state kept for a star that is gone (evicted live star).
+    fn forget(&mut self, star: &str);
+}
+
+/// Options shared by the triggers that are able to re-arm.
+///
+/// The alert threshold passed into `detect` is used as the arm threshold.
+#[derive(Clone, Copy, Debug)]
+pub struct TriggerOpts {
+    /// How many of the last `n` values must be above the arm threshold.
+    pub k: usize,
+    /// Number of most recent values considered by the K-of-N triggers.
+    pub n: usize,
+    /// Value a star must fall below after triggering before it can trigger again.
+    /// - None keeps the original latch forever behaviour
+    pub disarm_threshold: Option<f32>,
+    /// Minimum number of sample ticks between two triggers of the same star.
+    pub cooldown: usize,
+}
+
+impl Default for TriggerOpts {
+    fn default() -> TriggerOpts {
+        TriggerOpts {
+            k: 3,
+            n: 3,
+            disarm_threshold: None,
+            cooldown: 0,
+        }
+    }
 }
 
 #[allow(unused)]
 pub struct NoneTrigger {}
 
 impl DetectorTrigger for NoneTrigger {
-    fn detect(&mut self, _star: &str, _vals: f32, _current_time: usize, _threshold: f32)
+    fn detect(&mut self, _star: &str, _scores: &ClassScores, _current_time: usize, _threshold: f32)
               -> Option<DetectorResult> {
         None
     }
+
+    fn forget(&mut self, _star: &str) {}
 }
 
-/// Implements a simple threshold trigger
-/// mechanism that after the first successful trigger
-/// locks and does not trigger again.
+enum ArmState {
+    /// star has triggered (at the given time) and has not yet returned to quiescence
+    Fired(usize),
+    /// star has returned below the disarm threshold after triggering (at the given time)
+    Quiescent(usize),
+}
+
+/// Keeps track of which stars are allowed to trigger.
+///
+/// A star that has never triggered is always armed. After a trigger the star
+/// stays latched until a value below the disarm threshold is seen, and
+/// after that until the cooldown has passed since the last trigger.
+struct Rearm {
+    states: HashMap<String, ArmState>,
+    disarm_threshold: Option<f32>,
+    cooldown: usize,
+}
+
+impl Rearm {
+    fn new(opts: &TriggerOpts) -> Rearm {
+        Rearm {
+            states: HashMap::new(),
+            disarm_threshold: opts.disarm_threshold,
+            cooldown: opts.cooldown,
+        }
+    }
+
+    fn is_armed(&mut self, star: &str, val: f32, current_time: usize) -> bool {
+        let fired_at = match self.states.get(star) {
+            None => return true,
+            Some(ArmState::Fired(fired_at)) => {
+                let fired_at = *fired_at;
+                match self.disarm_threshold {
+                    Some(disarm_threshold) if val < disarm_threshold => {
+                        self.states.insert(star.to_string(), ArmState::Quiescent(fired_at));
+                        fired_at
+                    }
+                    _ => return false,
+                }
+            }
+            Some(ArmState::Quiescent(fired_at)) => *fired_at,
+        };
+
+        current_time >= fired_at + self.cooldown
+    }
+
+    fn fire(&mut self, star: &str, current_time: usize) {
+        self.states.insert(star.to_string(), ArmState::Fired(current_time));
+    }
+
+    fn forget(&mut self, star: &str) {
+        self.states.remove(star);
+    }
+}
+
+/// Implements a simple threshold trigger.
+///
+/// By default after the first successful trigger it
+/// locks and does not trigger again. With a disarm threshold
+/// the star re-arms once it returns to quiescence (see `TriggerOpts`).
 ///
 /// NOTE: This is probably slower than the other method which
 ///       removes the star from consideration (does not filter it anymore)
 ///       however in the live filtering case, we will set our values to the
 ///       worst case of having to filter every star every 10 seconds so these
 ///       kind of incremental speeds only matter for offline testing.
-/// NOTE: Their still is an additional speed penalty in using a hashset each time.
+/// NOTE: Their still is an additional speed penalty in using a hashmap each time.
 pub struct ThresholdTrigger {
-    already_detected_stars: HashSet<String>,
+    rearm: Rearm,
 }
 
 impl ThresholdTrigger {
-    pub fn new() -> ThresholdTrigger {
+    pub fn new(opts: TriggerOpts) -> ThresholdTrigger {
         ThresholdTrigger{
-            already_detected_stars: HashSet::new()
+            rearm: Rearm::new(&opts),
         }
     }
 }
 
 impl DetectorTrigger for ThresholdTrigger {
-    fn detect(&mut self, star: &str, val: f32, _current_time: usize, threshold: f32)
+    fn detect(&mut self, star: &str, scores: &ClassScores, current_time: usize, threshold: f32)
               -> Option<DetectorResult> {
-        if self.already_detected_stars.contains(star) {
+        let val = scores.best_score();
+        if !self.rearm.is_armed(star, val, current_time) {
             return None
         }
 
         if val > threshold {
-            self.already_detected_stars.insert(star.to_string());
-            Some(DetectorResult{})
+            self.rearm.fire(star, current_time);
+            Some(scores.to_result())
         } else {
             None
         }
     }
+
+    fn forget(&mut self, star: &str) {
+        self.rearm.forget(star);
+    }
 }
 
-pub struct ThreeInARowTrigger {
-    already_detected_stars: HashSet<String>,
+/// Triggers when at least k of the last n values of a star are above the threshold.
+///
+/// The current value must also be above the threshold so that a trigger
+/// is never reported on a falling edge.
+pub struct KOfNTrigger {
+    k: usize,
+    n: usize,
+    rearm: Rearm,
     star_data_windows: HashMap<String, CyclicQueue<f32>>,
 }
 
-impl ThreeInARowTrigger {
-    pub fn new() -> ThreeInARowTrigger {
-        ThreeInARowTrigger{
-            already_detected_stars: HashSet::new(),
+impl KOfNTrigger {
+    pub fn new(opts: TriggerOpts) -> KOfNTrigger {
+        assert!(opts.k > 0, "KOfNTrigger k must be greater than 0");
+        assert!(opts.k <= opts.n, "KOfNTrigger k must be less than or equal to n");
+
+        KOfNTrigger{
+            k: opts.k,
+            n: opts.n,
+            rearm: Rearm::new(&opts),
             star_data_windows: HashMap::new(),
         }
     }
 }
 
-impl DetectorTrigger for ThreeInARowTrigger {
-    fn detect(&mut self, star: &str, val: f32, _current_time: usize, threshold: f32)
+impl DetectorTrigger for KOfNTrigger {
+    fn detect(&mut self, star: &str, scores: &ClassScores, current_time: usize, threshold: f32)
               -> Option<DetectorResult> {
-        if self.already_detected_stars.contains(star) {
+        let val = scores.best_score();
+        let n = self.n;
+        self.star_data_windows.entry(star.to_string())
+            .or_insert_with(|| CyclicQueue::new(n))
+            .push(val);
+
+        if !self.rearm.is_armed(star, val, current_time) {
             return None
         }
 
-        if !self.star_data_windows.contains_key(star) {
-            self.star_data_windows.insert(star.to_string(), CyclicQueue::new(3));
+        if val <= threshold {
+            return None
         }
 
-        self.star_data_windows.get_mut(star)
-            .expect("Star must exist at this point.")
+        let window = &self.star_data_windows[star];
+        let num_above = (0..window.len())
+            .filter(|&i| {
+                *window.get_relative(i)
+                    .expect("length check and range should make this impossible") > threshold
+            })
+            .count();
+
+        if num_above >= self.k {
+            self.rearm.fire(star, current_time);
+            // NOTE start fresh so values from this event do not count towards the next
+            self.star_data_windows.insert(star.to_string(), CyclicQueue::new(n));
+            Some(scores.to_result())
+        } else {
+            None
+        }
+    }
+
+    fn forget(&mut self, star: &str) {
+        self.rearm.forget(star);
+        self.star_data_windows.remove(star);
+    }
+}
+
+/// Options for the shape of the filter output that `CurveTrigger` accepts.
+#[derive(Clone, Copy, Debug)]
+pub struct CurveTriggerOpts {
+    /// Number of most recent filter values kept per star.
+    pub history_len: usize,
+    /// Fraction of the steps in the history that must be increasing.
+    pub min_rise_fraction: f32,
+    /// Largest fraction of the total rise that a single step may account for.
+    pub max_step_fraction: f32,
+}
+
+impl Default for CurveTriggerOpts {
+    fn default() -> CurveTriggerOpts {
+        CurveTriggerOpts {
+            history_len: 6,
+            min_rise_fraction: 0.6,
+            max_step_fraction: 0.5,
+        }
+    }
+}
+
+/// Checks if a filter output history looks like a matched microlensing event.
+///
+/// As the window slides over a microlensing event the filter output rises
+/// gradually through the threshold over several filter operations.
+/// Flares and other glitches instead show up as
+/// - a single high value (spike) which is rejected by requiring the
+///   last two values to be above the threshold
+/// - a step (sharp rise followed by a plateau or decay) which is rejected
+///   by limiting how much of the total rise a single step can account for
+///   and by requiring the history to start below the threshold
+///
+/// NOTE history is ordered oldest to newest
+pub fn is_rise_profile(history: &[f32], threshold: f32, opts: &CurveTriggerOpts) -> bool {
+    let len = history.len();
+    if len < 3 {
+        return false;
+    }
+
+    if history[len - 1] <= threshold || history[len - 2] <= threshold {
+        return false;
+    }
+
+    if history[0] > threshold {
+        return false;
+    }
+
+    let min = history.iter().cloned().fold(std::f32::INFINITY, f32::min);
+    let rise = history[len - 1] - min;
+    if rise <= 0.0 {
+        return false;
+    }
+
+    let diffs = history
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .collect::<Vec<f32>>();
+
+    let num_rising = diffs.iter().filter(|&&diff| diff > 0.0).count();
+    if (num_rising as f32) / (diffs.len() as f32) < opts.min_rise_fraction {
+        return false;
+    }
+
+    let max_step = diffs.iter().cloned().fold(std::f32::NEG_INFINITY, f32::max);
+
+    max_step / rise <= opts.max_step_fraction
+}
+
+/// Trigger that observes the filter output curve of each star
+/// instead of single values (see `is_rise_profile`).
+pub struct CurveTrigger {
+    curve_opts: CurveTriggerOpts,
+    rearm: Rearm,
+    star_histories: HashMap<String, CyclicQueue<f32>>,
+}
+
+impl CurveTrigger {
+    pub fn new(opts: TriggerOpts, curve_opts: CurveTriggerOpts) -> CurveTrigger {
+        assert!(curve_opts.history_len >= 3, "CurveTrigger history must be at least 3 long");
+
+        CurveTrigger {
+            curve_opts,
+            rearm: Rearm::new(&opts),
+            star_histories: HashMap::new(),
+        }
+    }
+}
+
+impl DetectorTrigger for CurveTrigger {
+    fn detect(&mut self, star: &str, scores: &ClassScores, current_time: usize, threshold: f32)
+              -> Option<DetectorResult> {
+        let val = scores.best_score();
+        let history_len = self.curve_opts.history_len;
+        self.star_histories.entry(star.to_string())
+            .or_insert_with(|| CyclicQueue::new(history_len))
             .push(val);
 
-        if val > threshold && self.star_data_windows[star].len() == 3 {
-            let mut is_good = true;
-            // NOTE last three elements
-            for i in 0..3 {
-                is_good &= *self.star_data_windows[star]
-                    .get_relative(i)
-                    .expect("length check and range should make this impossible") > threshold;
-            }
+        if !self.rearm.is_armed(star, val, current_time) {
+            return None
+        }
 
-            if is_good {
-                self.already_detected_stars.insert(star.to_string());
-                Some(DetectorResult{})
-            } else {
-                None
-            }
+        let history = &self.star_histories[star];
+        // NOTE only judge the shape once we have seen the whole history
+        if history.len() != history_len {
+            return None
+        }
+
+        let history = (0..history.len())
+            .map(|i| {
+                *history.get_relative(i)
+                    .expect("length check and range should make this impossible")
+            })
+            .collect::<Vec<f32>>();
+
+        if is_rise_profile(&history, threshold, &self.curve_opts) {
+            self.rearm.fire(star, current_time);
+            Some(scores.to_result())
         } else {
             None
         }
     }
+
+    fn forget(&mut self, star: &str) {
+        self.rearm.forget(star);
+        self.star_histories.remove(star);
+    }
 }
 
-// TODO write a trigger that observes curve as outputted to get better result
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fire_times(trigger: &mut dyn DetectorTrigger, vals: &[f32], threshold: f32) -> Vec<usize> {
+        vals.iter()
+            .enumerate()
+            .filter_map(|(time, &val)| {
+                let scores = ClassScores {
+                    classes: &[EventClass::Microlensing],
+                    scores: &[val],
+                };
+                trigger.detect("star", &scores, time, threshold).map(|_| time)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_threshold_latches_by_default() {
+        let mut trigger = ThresholdTrigger::new(TriggerOpts::default());
+        let vals = [0.0, 2.0, 0.0, 0.0, 2.0, 2.0];
+
+        assert_eq!(fire_times(&mut trigger, &vals, 1.0), vec![1]);
+    }
+
+    #[test]
+    fn test_threshold_rearms_with_hysteresis() {
+        let opts = TriggerOpts {
+            disarm_threshold: Some(0.5),
+            ..Default::default()
+        };
+        let mut trigger = ThresholdTrigger::new(opts);
+        // 0.8 is between disarm and arm so does not re-arm the star
+        let vals = [2.0, 0.8, 2.0, 0.1, 2.0];
+
+        assert_eq!(fire_times(&mut trigger, &vals, 1.0), vec![0, 4]);
+    }
+
+    #[test]
+    fn test_threshold_cooldown() {
+        let opts = TriggerOpts {
+            disarm_threshold: Some(0.5),
+            cooldown: 4,
+            ..Default::default()
+        };
+        let mut trigger = ThresholdTrigger::new(opts);
+        let vals = [2.0, 0.0, 2.0, 2.0, 2.0, 2.0];
+
+        assert_eq!(fire_times(&mut trigger, &vals, 1.0), vec![0, 4]);
+    }
+
+    #[test]
+    fn test_forget_rearms() {
+        let mut trigger = KOfNTrigger::new(TriggerOpts::default());
+        let vals = [2.0, 2.0, 2.0, 2.0];
+        assert_eq!(fire_times(&mut trigger, &vals, 1.0), vec![2]);
+        assert_eq!(trigger.rearm.states.len(), 1);
+
+        // NOTE an evicted star that comes back starts over
+        trigger.forget("star");
+        assert!(trigger.rearm.states.is_empty());
+        assert!(trigger.star_data_windows.is_empty());
+        assert_eq!(fire_times(&mut trigger, &vals, 1.0), vec![2]);
+    }
+
+    #[test]
+    fn test_k_of_n() {
+        let opts = TriggerOpts {
+            k: 2,
+            n: 4,
+            ..Default::default()
+        };
+        let mut trigger = KOfNTrigger::new(opts);
+        let vals = [2.0, 0.0, 0.0, 0.0, 2.0, 0.0, 2.0, 2.0];
+
+        assert_eq!(fire_times(&mut trigger, &vals, 1.0), vec![6]);
+    }
+
+    #[test]
+    fn test_rise_profile() {
+        let opts = CurveTriggerOpts::default();
+
+        // gradual rise of a matched event
+        assert!(is_rise_profile(&[0.0, 1.0, 2.0, 3.0, 4.5, 6.0], 2.5, &opts));
+        // single sample spike
+        assert!(!is_rise_profile(&[0.0, 0.1, 0.0, 0.1, 0.0, 6.0], 2.5, &opts));
+        // step followed by a plateau (flare like)
+        assert!(!is_rise_profile(&[0.0, 0.1, 5.0, 5.1, 5.2, 5.3], 2.5, &opts));
+        // decaying after the peak
+        assert!(!is_rise_profile(&[6.0, 5.5, 5.0, 4.5, 4.0, 3.5], 2.5, &opts));
+    }
+
+    #[test]
+    fn test_curve_trigger() {
+        let mut trigger = CurveTrigger::new(TriggerOpts::default(), CurveTriggerOpts::default());
+        let vals = [0.0, 0.1, 5.0, 5.1, 5.2, 5.3, 5.4, 5.3, 0.0, 1.0, 2.0, 3.0, 4.5, 6.0];
+
+        assert_eq!(fire_times(&mut trigger, &vals, 2.5), vec![13]);
+    }
+
+    #[test]
+    fn test_class_scores() {
+        let classes = [EventClass::Microlensing, EventClass::Flare];
+
+        let scores = ClassScores { classes: &classes, scores: &[2.0, 8.0] };
+        let res = scores.to_result();
+        assert_eq!(res.class, EventClass::Flare);
+        assert_eq!(res.class_scores, vec![2.0, 8.0]);
+        assert_abs_diff_eq!(res.confidence, 0.75);
+
+        let scores = ClassScores { classes: &classes, scores: &[4.0, 4.0] };
+        assert_eq!(scores.best().0, EventClass::Microlensing);
+        assert_abs_diff_eq!(scores.confidence(), 0.0);
+
+        let scores = ClassScores { classes: &classes[..1], scores: &[4.0] };
+        assert_abs_diff_eq!(scores.confidence(), 1.0);
+
+        // NOTE a NaN score (ex. from a NaN sample) must not panic
+        let scores = ClassScores { classes: &classes, scores: &[std::f32::NAN, 4.0] };
+        scores.confidence();
+    }
+
+    #[test]
+    fn test_best_window_scales() {
+        let classes = [EventClass::Microlensing, EventClass::Flare];
+        let names = ["a", "a", "a", "b", "c", "c"]
+            .iter()
+            .map(|name| name.to_string())
+            .collect::<Vec<String>>();
+        let scores = vec![
+            vec![1.0, 2.0],
+            vec![5.0, 2.0],
+            vec![3.0, 4.0],
+            vec![0.0, 0.0],
+            vec![1.0, 1.0],
+            vec![1.0, 0.5],
+        ];
+        let window_lens = vec![10; names.len()];
+
+        assert_eq!(best_window_scales(&classes, &scores, &names, &window_lens), vec![1, 3, 4]);
+    }
+
+    #[test]
+    fn test_best_window_scales_normalized() {
+        let classes = [EventClass::Microlensing];
+        let names = vec!["a".to_string(); 3];
+        let window_lens = vec![25, 50, 100];
+
+        // NOTE noise only, the raw scores grow with the square root of the length
+        let scores = vec![vec![5.0], vec![7.0], vec![10.0]];
+        assert_eq!(best_window_scales(&classes, &scores, &names, &window_lens), vec![0]);
+
+        // NOTE the event fills the middle window
+        let scores = vec![vec![5.0], vec![14.0], vec![15.0]];
+        assert_eq!(best_window_scales(&classes, &scores, &names, &window_lens), vec![1]);
+    }
+
+    #[test]
+    fn test_k_of_n_rearms() {
+        let opts = TriggerOpts {
+            k: 3,
+            n: 3,
+            disarm_threshold: Some(0.5),
+            cooldown: 0,
+        };
+        let mut trigger = KOfNTrigger::new(opts);
+        let vals = [2.0, 2.0, 2.0, 2.0, 0.0, 2.0, 2.0, 2.0];
+
+        assert_eq!(fire_times(&mut trigger, &vals, 1.0), vec![2, 7]);
+    }
+}
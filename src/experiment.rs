@@ -0,0 +1,147 @@
+use std::fs;
+
+/// Outcome of a run, one row of an experiment's CSV.
+pub struct RunSummary {
+    pub label: String,
+    /// run file matrix (grid) keys and values of the run
+    pub params: Vec<(String, String)>,
+    pub num_stars: usize,
+    pub true_events: usize,
+    pub false_events: usize,
+    pub adps: Vec<f32>,
+    pub run_secs: f64,
+}
+
+/// (min, max, avg, std_dev) of the values.
+pub fn stats(data: &[f32]) -> (f32, f32, f32, f32) {
+    let mut avg = 0.0;
+    let mut min = std::f32::INFINITY;
+    let mut max = std::f32::NEG_INFINITY;
+    let mut std_dev = 0.0;
+    let len = data.len() as f32;
+
+    for &datum in data {
+        avg += datum;
+        min = if min < datum { min } else { datum };
+        max = if max > datum { max } else { datum };
+        std_dev += datum * datum;
+    }
+
+    avg /= len;
+    std_dev = (std_dev / len - avg * avg).sqrt();
+
+    (min, max, avg, std_dev)
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains(|c: char| c == ',' || c == '"' || c == '\n') {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+/// Aggregated CSV of the runs, a column per grid key and then the
+/// detection metrics and ADP stats.
+///
+/// NOTE every run of an experiment has the same grid keys
+pub fn summaries_csv(summaries: &[RunSummary]) -> String {
+    let param_keys: Vec<&str> = summaries
+        .first()
+        .map(|summary| summary.params.iter().map(|(key, _)| key.as_str()).collect())
+        .unwrap_or_default();
+
+    let mut header = vec!["label"];
+    header.extend(param_keys.iter());
+    header.extend(&[
+        "num_stars",
+        "num_events_detected",
+        "num_true_events",
+        "num_false_events",
+        "precision",
+        "num_adps",
+        "adp_min",
+        "adp_max",
+        "adp_avg",
+        "adp_std_dev",
+        "run_secs",
+    ]);
+
+    let mut csv = header.join(",") + "\n";
+    for summary in summaries.iter() {
+        let num_events = summary.true_events + summary.false_events;
+        let precision = if num_events > 0 {
+            (summary.true_events as f32 / num_events as f32).to_string()
+        } else {
+            String::new()
+        };
+        // NOTE no ADP stats without true events
+        let adp_stats = if summary.adps.is_empty() {
+            vec![String::new(); 4]
+        } else {
+            let (min, max, avg, std_dev) = stats(&summary.adps);
+            vec![min, max, avg, std_dev]
+                .iter()
+                .map(|stat| stat.to_string())
+                .collect()
+        };
+
+        let mut row = vec![summary.label.clone()];
+        row.extend(summary.params.iter().map(|(_, value)| value.clone()));
+        row.extend(vec![
+            summary.num_stars.to_string(),
+            num_events.to_string(),
+            summary.true_events.to_string(),
+            summary.false_events.to_string(),
+            precision,
+            summary.adps.len().to_string(),
+        ]);
+        row.extend(adp_stats);
+        row.push(format!("{:.3}", summary.run_secs));
+
+        csv += &row.iter().map(|field| csv_field(field)).collect::<Vec<String>>().join(",");
+        csv += "\n";
+    }
+
+    csv
+}
+
+pub fn write_csv(output: &str, summaries: &[RunSummary]) {
+    fs::write(output, summaries_csv(summaries))
+        .unwrap_or_else(|_| panic!("Failed to write experiment results to {}", output));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summaries_csv() {
+        let summary = |label: &str, dc_norm: &str, adps: Vec<f32>| RunSummary {
+            label: label.to_string(),
+            params: vec![
+                ("dc_norm".to_string(), dc_norm.to_string()),
+                ("alert_classes".to_string(), "Flare,Nova".to_string()),
+            ],
+            num_stars: 10,
+            true_events: adps.len(),
+            false_events: 1,
+            adps,
+            run_secs: 1.5,
+        };
+
+        let csv = summaries_csv(&[
+            summary("a", "None", vec![1.0, 3.0]),
+            summary("b", "MeanRemoveStar", vec![]),
+        ]);
+        let lines: Vec<&str> = csv.lines().collect();
+
+        assert_eq!(
+            lines[0],
+            "label,dc_norm,alert_classes,num_stars,num_events_detected,num_true_events,\
+             num_false_events,precision,num_adps,adp_min,adp_max,adp_avg,adp_std_dev,run_secs"
+        );
+        assert_eq!(lines[1], "a,None,\"Flare,Nova\",10,3,2,1,0.6666667,2,1,3,2,1,1.500");
+        assert_eq!(lines[2], "b,MeanRemoveStar,\"Flare,Nova\",10,1,0,1,0,0,,,,,1.500");
+    }
+}
@@ -0,0 +1,194 @@
+use crate::cli::DetectorOpts;
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// A ready star the ticker could filter this tick.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Candidate {
+    /// position of the star in the ticker
+    pub idx: usize,
+    pub watched: bool,
+    /// ticks the star has been ready without being filtered
+    pub overdue: u32,
+    /// filter operations of the star (one per window scale)
+    pub ops: usize,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct FilterStatus {
+    pub watched: usize,
+    /// ready stars left for a later tick (over budget)
+    pub deferred: usize,
+}
+
+/// Adaptive filtering cadence of the stars.
+///
+/// Stars with a recent score or variability above the watch levels are
+/// filtered every tick, quiet stars keep the skip_delta cadence. The budget
+/// bounds the filter operations of a tick so live runs keep up with the
+/// cameras, watched and then the longest waiting stars go first.
+pub struct FilterScheduler {
+    watch_level: Option<f32>,
+    watch_variability: Option<f32>,
+    budget: Option<usize>,
+    // NOTE written by the detector, read by the ticker once a tick
+    score_watched: Mutex<HashSet<String>>,
+}
+
+impl FilterScheduler {
+    pub fn new(detector_opts: &DetectorOpts) -> FilterScheduler {
+        FilterScheduler {
+            watch_level: detector_opts.watch_level,
+            watch_variability: detector_opts.watch_variability,
+            budget: detector_opts.filter_budget,
+            score_watched: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Records the (best) score the star was filtered at.
+    pub fn report_score(&self, star: &str, score: f32) {
+        let watch_level = match self.watch_level {
+            Some(watch_level) => watch_level,
+            None => return,
+        };
+
+        let mut score_watched = self
+            .score_watched
+            .lock()
+            .expect("Filter scheduler lock poisoned.");
+        if score >= watch_level {
+            score_watched.insert(star.to_string());
+        } else {
+            score_watched.remove(star);
+        }
+    }
+
+    /// Drops a star that is gone (evicted live star).
+    pub fn forget(&self, star: &str) {
+        self.score_watched
+            .lock()
+            .expect("Filter scheduler lock poisoned.")
+            .remove(star);
+    }
+
+    /// Stars watched because of their last score.
+    pub fn score_watched(&self) -> HashSet<String> {
+        self.score_watched
+            .lock()
+            .expect("Filter scheduler lock poisoned.")
+            .clone()
+    }
+
+    /// Is a star with these recent samples variable enough to watch?
+    pub fn is_variable(&self, recent: &[f32]) -> bool {
+        match self.watch_variability {
+            Some(level) if recent.len() > 1 => stddev(recent) >= level,
+            _ => false,
+        }
+    }
+
+    /// Picks the candidates filtered this tick (in ticker order).
+    pub fn schedule(&self, mut candidates: Vec<Candidate>) -> (Vec<usize>, FilterStatus) {
+        let watched = candidates.iter().filter(|cand| cand.watched).count();
+        let budget = match self.budget {
+            Some(budget) => budget,
+            None => {
+                let selected = candidates.iter().map(|cand| cand.idx).collect();
+                return (selected, FilterStatus { watched, deferred: 0 });
+            }
+        };
+
+        // NOTE stable sort, ties keep the ticker order
+        candidates.sort_by_key(|cand| (!cand.watched, std::cmp::Reverse(cand.overdue)));
+
+        let mut ops = 0;
+        let mut selected = Vec::new();
+        for cand in candidates.iter() {
+            // NOTE always filter something so a tiny budget can not stall a run
+            if !selected.is_empty() && ops + cand.ops > budget {
+                continue;
+            }
+            ops += cand.ops;
+            selected.push(cand.idx);
+        }
+        let deferred = candidates.len() - selected.len();
+        selected.sort_unstable();
+
+        (selected, FilterStatus { watched, deferred })
+    }
+}
+
+fn stddev(vals: &[f32]) -> f32 {
+    let len = vals.len() as f32;
+    let mean = vals.iter().sum::<f32>() / len;
+
+    (vals.iter().map(|val| (val - mean).powi(2)).sum::<f32>() / (len - 1.0)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scheduler(budget: Option<usize>) -> FilterScheduler {
+        FilterScheduler {
+            watch_level: Some(5.0),
+            watch_variability: Some(1.0),
+            budget,
+            score_watched: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn candidate(idx: usize, watched: bool, overdue: u32) -> Candidate {
+        Candidate { idx, watched, overdue, ops: 2 }
+    }
+
+    #[test]
+    fn test_report_score() {
+        let scheduler = scheduler(None);
+
+        scheduler.report_score("a", 6.0);
+        scheduler.report_score("b", 1.0);
+        assert!(scheduler.score_watched().contains("a"));
+        assert!(!scheduler.score_watched().contains("b"));
+
+        scheduler.report_score("a", 4.0);
+        assert!(scheduler.score_watched().is_empty());
+    }
+
+    #[test]
+    fn test_is_variable() {
+        let scheduler = scheduler(None);
+
+        assert!(!scheduler.is_variable(&[10.0, 10.1, 9.9, 10.0]));
+        assert!(scheduler.is_variable(&[10.0, 12.0, 9.0, 11.0]));
+        assert!(!scheduler.is_variable(&[10.0]));
+    }
+
+    #[test]
+    fn test_schedule_unlimited() {
+        let cands = vec![candidate(0, false, 0), candidate(3, true, 0)];
+        let (selected, status) = scheduler(None).schedule(cands);
+
+        assert_eq!(selected, vec![0, 3]);
+        assert_eq!(status.watched, 1);
+        assert_eq!(status.deferred, 0);
+    }
+
+    #[test]
+    fn test_schedule_budget() {
+        let cands = vec![
+            candidate(0, false, 0),
+            candidate(1, false, 3),
+            candidate(2, true, 0),
+            candidate(4, false, 1),
+        ];
+        let (selected, status) = scheduler(Some(4)).schedule(cands);
+
+        // NOTE watched first, then the longest waiting
+        assert_eq!(selected, vec![1, 2]);
+        assert_eq!(status.deferred, 2);
+
+        let (selected, _) = scheduler(Some(1)).schedule(vec![candidate(5, false, 0)]);
+        assert_eq!(selected, vec![5]);
+    }
+}
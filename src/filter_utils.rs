@@ -10,7 +10,7 @@ use arrayfire::Dim4 as AF_Dim4;
 use crate::cyclic_queue::{CyclicQueue, CyclicQueueInterface};
 
 arg_enum! {
-    #[derive(Clone, Copy)]
+    #[derive(Clone, Copy, Debug)]
     #[allow(dead_code)]
     pub enum WindowFunc {
         Nuttall,
@@ -434,6 +434,16 @@ pub fn stars_historical_mean_removal(
     subtract_means(stars, &stars_means)
 }
 
+/// Drops the historical means of a star that is gone (evicted live star)
+/// at every window scale (`star@...`, see the filter names in detector.rs).
+pub fn forget_historical_means(star: &str) {
+    let scale_prefix = format!("{}@", star);
+    HISTORICAL_MEANS_GLOBAL
+        .lock()
+        .unwrap()
+        .retain(|name, _| name != star && !name.starts_with(&scale_prefix));
+}
+
 pub fn stars_fft(
     stars: &AF_Array<f32>,
     fft_len: usize,
@@ -0,0 +1,390 @@
+use crate::gwac_reader::{FrameName, GWACData, GWACFrame, GWACMessage};
+use crate::info_handler::InformationHandler;
+use crate::log;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::Receiver;
+use tokio::sync::watch;
+
+arg_enum! {
+    /// What to do when the detector can not keep up with the GWAC frames.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum BufferPolicy {
+        // hold back the readers (and so the upstream) until there is room
+        Block,
+        // throw away the oldest queued frame to make room
+        DropOldest,
+        // keep ticking the star buffers but skip filtering while lagging
+        SkipFilter,
+    }
+}
+
+/// A complete frame of one camera.
+pub struct QueuedFrame {
+    pub camera: usize,
+    pub name: Option<FrameName>,
+    pub stars: Vec<GWACData>,
+    /// when the end of the frame was read
+    pub arrived: Instant,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct QueueStatus {
+    /// frames (of all cameras) still queued
+    pub depth: usize,
+    /// most frames still queued for a single camera
+    pub frames_behind: usize,
+    /// time the frame taken off waited in the queue
+    pub lag: Duration,
+    pub dropped_frames: usize,
+}
+
+/// Result of asking the queue for the next frame of a tick.
+pub enum NextFrame {
+    Frame(QueuedFrame, QueueStatus),
+    /// the next frame belongs to a camera already done this tick
+    CameraAhead,
+    /// all readers are done and the queue is empty
+    Closed,
+}
+
+#[derive(Default)]
+struct QueueState {
+    frames: VecDeque<QueuedFrame>,
+    dropped_frames: usize,
+    closed: bool,
+}
+
+/// Bounded queue of complete frames between the GWAC readers and the ticker.
+///
+/// Frames are assembled as they are read (so the readers never wait on the
+/// detector) and `max_frames` bounds how many frames are held in memory.
+pub struct FrameQueue {
+    policy: BufferPolicy,
+    max_frames: usize,
+    state: Mutex<QueueState>,
+    // NOTE watch channels are only used as wake ups (the value is meaningless)
+    pushed: (watch::Sender<()>, watch::Receiver<()>),
+    popped: (watch::Sender<()>, watch::Receiver<()>),
+}
+
+impl FrameQueue {
+    pub fn new(policy: BufferPolicy, max_frames: usize) -> FrameQueue {
+        FrameQueue {
+            policy,
+            max_frames: std::cmp::max(max_frames, 1),
+            state: Mutex::new(QueueState::default()),
+            pushed: watch::channel(()),
+            popped: watch::channel(()),
+        }
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<()> {
+        self.pushed.1.clone()
+    }
+
+    /// Is the frame taken off with this status lagging enough to skip filtering?
+    pub fn should_skip_filter(&self, status: &QueueStatus) -> bool {
+        self.policy == BufferPolicy::SkipFilter && status.depth >= self.max_frames
+    }
+
+    fn lock(&self) -> MutexGuard<QueueState> {
+        self.state.lock().expect("Frame queue lock poisoned.")
+    }
+
+    /// Assembles the frames of all cameras and queues them.
+    ///
+    /// Also reports per frame problems and the per camera cadence.
+    pub async fn fill(
+        &self,
+        mut gwac_rx: Receiver<GWACMessage>,
+        cameras: Vec<String>,
+        info_handler: Arc<InformationHandler>,
+    ) {
+        let log = log::get_root_logger();
+        let mut popped_rx = self.popped.1.clone();
+        let mut partial: Vec<Option<QueuedFrame>> = (0..cameras.len()).map(|_| None).collect();
+        let mut last_frame_end: Vec<Option<Instant>> = vec![None; cameras.len()];
+        let mut last_obs_time: Vec<Option<Duration>> = vec![None; cameras.len()];
+
+        // If all senders close, then all cameras are done for the night
+        while let Some(GWACMessage { camera, frame }) = gwac_rx.recv().await {
+            match frame {
+                GWACFrame::Start => {
+                    partial[camera] = Some(QueuedFrame {
+                        camera,
+                        name: None,
+                        stars: Vec::new(),
+                        arrived: Instant::now(),
+                    });
+                }
+                GWACFrame::Filename(frame_name) => {
+                    debug!(log, "";
+                           "camera"=>&cameras[camera],
+                           "frame"=>&frame_name.name,
+                           "obs_time"=>format!("{:?}", frame_name.obs_time));
+                    if let Some(queued) = partial[camera].as_mut() {
+                        queued.name = Some(frame_name);
+                    }
+                }
+                GWACFrame::Star(star) => {
+                    if let Some(queued) = partial[camera].as_mut() {
+                        queued.stars.push(star);
+                    }
+                }
+                GWACFrame::End(stats) => {
+                    if !stats.is_clean() {
+                        warn!(log, "Problem with GWAC frame.";
+                              "camera"=>&cameras[camera],
+                              "stars"=>stats.stars,
+                              "malformed"=>stats.malformed,
+                              "dropped"=>stats.dropped,
+                              "duplicates"=>stats.duplicates,
+                              "missing_filename"=>stats.missing_filename,
+                              "truncated"=>stats.truncated,
+                              "star_count_outlier"=>stats.star_count_outlier,
+                              "first_error"=>stats.first_error.unwrap_or_default());
+                    }
+
+                    let mut queued = match partial[camera].take() {
+                        Some(queued) => queued,
+                        None => continue,
+                    };
+
+                    // NOTE prefer the observation times of the frames
+                    //      over when they arrived (wraps at UTC midnight)
+                    let now = Instant::now();
+                    let obs_time = queued.name.as_ref().and_then(|name| name.obs_time);
+                    let cadence = match (last_obs_time[camera], obs_time) {
+                        (Some(last), Some(cur)) if cur >= last => Some(cur - last),
+                        (Some(last), Some(cur)) => Some(cur + Duration::from_secs(24 * 3600) - last),
+                        _ => last_frame_end[camera].map(|last| now.duration_since(last)),
+                    };
+                    if let Some(cadence) = cadence {
+                        info_handler.set_camera_cadence(&cameras[camera], cadence);
+                    }
+                    last_frame_end[camera] = Some(now);
+                    last_obs_time[camera] = obs_time;
+
+                    queued.arrived = now;
+                    self.push(queued, &mut popped_rx).await;
+                }
+            }
+        }
+
+        self.lock().closed = true;
+        let _ = self.pushed.0.broadcast(());
+    }
+
+    async fn push(&self, frame: QueuedFrame, popped_rx: &mut watch::Receiver<()>) {
+        let log = log::get_root_logger();
+        // NOTE skipping filtering starts at max_frames, so allow
+        //      the ticker room to catch up before blocking
+        let limit = match self.policy {
+            BufferPolicy::Block | BufferPolicy::DropOldest => self.max_frames,
+            BufferPolicy::SkipFilter => 2 * self.max_frames,
+        };
+
+        loop {
+            {
+                let mut state = self.lock();
+                if state.frames.len() < limit {
+                    state.frames.push_back(frame);
+                    break;
+                }
+
+                if self.policy == BufferPolicy::DropOldest {
+                    if let Some(dropped) = state.frames.pop_front() {
+                        state.dropped_frames += 1;
+                        warn!(log, "Detector behind, dropped oldest GWAC frame.";
+                              "camera"=>dropped.camera,
+                              "stars"=>dropped.stars.len(),
+                              "dropped_frames"=>state.dropped_frames);
+                    }
+                    continue;
+                }
+            }
+
+            // NOTE wait for the ticker to take a frame off, this holds
+            //      back the readers (and upstream) as their channel fills
+            if popped_rx.recv().await.is_none() {
+                return;
+            }
+        }
+
+        let _ = self.pushed.0.broadcast(());
+    }
+
+    /// Takes the next frame off the queue unless its camera is `done` this tick.
+    pub async fn next_frame(&self, done: &[bool], pushed_rx: &mut watch::Receiver<()>) -> NextFrame {
+        loop {
+            {
+                let mut state = self.lock();
+                let next_camera = state.frames.front().map(|frame| frame.camera);
+                match next_camera {
+                    Some(camera) if done[camera] => return NextFrame::CameraAhead,
+                    Some(_) => {
+                        let frame = state.frames.pop_front().expect("Checked front of queue.");
+                        let mut per_camera = vec![0; done.len()];
+                        state.frames.iter().for_each(|queued| per_camera[queued.camera] += 1);
+
+                        let status = QueueStatus {
+                            depth: state.frames.len(),
+                            frames_behind: per_camera.into_iter().max().unwrap_or(0),
+                            lag: frame.arrived.elapsed(),
+                            dropped_frames: state.dropped_frames,
+                        };
+                        drop(state);
+
+                        let _ = self.popped.0.broadcast(());
+                        return NextFrame::Frame(frame, status);
+                    }
+                    None if state.closed => return NextFrame::Closed,
+                    None => (),
+                }
+            }
+
+            if pushed_rx.recv().await.is_none() {
+                return NextFrame::Closed;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::task::Poll;
+    use tokio::future::poll_fn;
+
+    // NOTE the star count tells the test frames apart
+    fn frame(camera: usize, num_stars: usize) -> QueuedFrame {
+        let star = |i: usize| GWACData {
+            xpix: 0.0,
+            ypix: 0.0,
+            ra: 0.0,
+            dec: 0.0,
+            zone: String::new(),
+            star_id: i.to_string(),
+            mag: 0.0,
+            timestamp: 0.0,
+            ellipiticity: 0.0,
+            ccd_num: String::new(),
+        };
+
+        QueuedFrame {
+            camera,
+            name: None,
+            stars: (0..num_stars).map(star).collect(),
+            arrived: Instant::now(),
+        }
+    }
+
+    async fn is_pending<F: Future + Unpin>(fut: &mut F) -> bool {
+        poll_fn(|cx| Poll::Ready(Pin::new(&mut *fut).poll(cx).is_pending())).await
+    }
+
+    async fn next_stars(queue: &FrameQueue, done: &[bool]) -> (usize, QueueStatus) {
+        match queue.next_frame(done, &mut queue.subscribe()).await {
+            NextFrame::Frame(frame, status) => (frame.stars.len(), status),
+            NextFrame::CameraAhead => panic!("Expected a frame, camera ahead."),
+            NextFrame::Closed => panic!("Expected a frame, queue closed."),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_block() {
+        let queue = FrameQueue::new(BufferPolicy::Block, 2);
+        let mut popped_rx = queue.popped.1.clone();
+        queue.push(frame(0, 1), &mut popped_rx).await;
+        queue.push(frame(0, 2), &mut popped_rx).await;
+
+        // NOTE a full queue holds back the reader until a frame is taken off
+        let mut third = Box::pin(queue.push(frame(0, 3), &mut popped_rx));
+        assert!(is_pending(&mut third).await);
+        assert_eq!(queue.lock().frames.len(), 2);
+
+        assert_eq!(next_stars(&queue, &[false]).await.0, 1);
+        third.await;
+        assert_eq!(next_stars(&queue, &[false]).await.0, 2);
+        assert_eq!(next_stars(&queue, &[false]).await.0, 3);
+        assert_eq!(queue.lock().dropped_frames, 0);
+    }
+
+    #[tokio::test]
+    async fn test_drop_oldest() {
+        let queue = FrameQueue::new(BufferPolicy::DropOldest, 2);
+        let mut popped_rx = queue.popped.1.clone();
+        for num_stars in 1..=4 {
+            queue.push(frame(0, num_stars), &mut popped_rx).await;
+        }
+
+        let (num_stars, status) = next_stars(&queue, &[false]).await;
+        assert_eq!(num_stars, 3);
+        assert_eq!(status.dropped_frames, 2);
+        assert_eq!(next_stars(&queue, &[false]).await.0, 4);
+    }
+
+    #[tokio::test]
+    async fn test_skip_filter() {
+        let queue = FrameQueue::new(BufferPolicy::SkipFilter, 2);
+        let mut popped_rx = queue.popped.1.clone();
+        for num_stars in 1..=4 {
+            queue.push(frame(0, num_stars), &mut popped_rx).await;
+        }
+
+        // NOTE frames are kept (up to twice max_frames), filtering is
+        //      skipped while max_frames are still queued behind
+        let mut fifth = Box::pin(queue.push(frame(0, 5), &mut popped_rx));
+        assert!(is_pending(&mut fifth).await);
+
+        let (num_stars, status) = next_stars(&queue, &[false]).await;
+        assert_eq!((num_stars, status.depth), (1, 3));
+        assert!(queue.should_skip_filter(&status));
+        fifth.await;
+
+        let (num_stars, status) = next_stars(&queue, &[false]).await;
+        assert_eq!((num_stars, status.depth), (2, 3));
+        assert!(queue.should_skip_filter(&status));
+
+        let (num_stars, status) = next_stars(&queue, &[false]).await;
+        assert_eq!((num_stars, status.depth), (3, 2));
+        assert!(queue.should_skip_filter(&status));
+
+        let (num_stars, status) = next_stars(&queue, &[false]).await;
+        assert_eq!((num_stars, status.depth), (4, 1));
+        assert!(!queue.should_skip_filter(&status));
+        assert_eq!(status.dropped_frames, 0);
+    }
+
+    #[tokio::test]
+    async fn test_camera_ahead_and_closed() {
+        let queue = FrameQueue::new(BufferPolicy::Block, 4);
+        let mut popped_rx = queue.popped.1.clone();
+        queue.push(frame(1, 1), &mut popped_rx).await;
+        queue.push(frame(0, 2), &mut popped_rx).await;
+        queue.push(frame(1, 3), &mut popped_rx).await;
+
+        // NOTE camera 1 already ticked, its next frame waits for the next tick
+        let done = [false, true];
+        match queue.next_frame(&done, &mut queue.subscribe()).await {
+            NextFrame::CameraAhead => (),
+            _ => panic!("Expected the camera to be ahead."),
+        }
+
+        let (num_stars, status) = next_stars(&queue, &[false, false]).await;
+        assert_eq!((num_stars, status.depth, status.frames_behind), (1, 2, 1));
+
+        // NOTE frames still queued are handed out after the readers are done
+        queue.lock().closed = true;
+        assert_eq!(next_stars(&queue, &[false, false]).await.0, 2);
+        assert_eq!(next_stars(&queue, &[false, false]).await.0, 3);
+        match queue.next_frame(&[false, false], &mut queue.subscribe()).await {
+            NextFrame::Closed => (),
+            _ => panic!("Expected the queue to be closed."),
+        }
+    }
+}
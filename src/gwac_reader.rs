@@ -1,16 +1,13 @@
+use crate::log;
+use std::collections::{HashSet, VecDeque};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 use tokio::fs::File;
 use tokio::io::AsyncBufReadExt;
-use tokio::io::BufReader;
+use tokio::io::{AsyncRead, BufReader};
+use tokio::net::{TcpStream, UnixStream};
 use tokio::sync::mpsc::{channel, Receiver, Sender};
-
-macro_rules! unwrap_or_continue {
-    ($e:expr) => {
-        match $e {
-            Ok(val) => val,
-            _ => continue,
-        }
-    };
-}
+use tokio::timer::delay;
 
 pub struct GWACData {
     pub xpix: f32,
@@ -20,136 +17,732 @@ pub struct GWACData {
     pub zone: String,
     pub star_id: String,
     pub mag: f32,
-    pub timestamp: f32,
+    /// NOTE f64 so absolute (epoch) times keep sub second resolution
+    pub timestamp: f64,
     pub ellipiticity: f32,
     pub ccd_num: String,
 }
 
+/// File name sent right after the start of a frame.
+pub struct FrameName {
+    pub name: String,
+    /// observation time as the time since (UTC) midnight
+    pub obs_time: Option<Duration>,
+}
+
+/// Bookkeeping of a single frame, sent with the end of the frame.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    /// star lines sent on
+    pub stars: usize,
+    /// star lines that do not follow the line grammar
+    pub malformed: usize,
+    /// star lines outside of a frame (counted towards the next frame)
+    pub dropped: usize,
+    /// star lines of a star already seen in this frame
+    pub duplicates: usize,
+    /// frame had no file name line
+    pub missing_filename: bool,
+    /// frame was cut short (disconnect or a start without an end)
+    pub truncated: bool,
+    /// star count deviates strongly from the recent frames
+    pub star_count_outlier: bool,
+    /// why the first malformed star line was rejected
+    pub first_error: Option<String>,
+}
+
+impl FrameStats {
+    pub fn is_clean(&self) -> bool {
+        *self
+            == FrameStats {
+                stars: self.stars,
+                ..FrameStats::default()
+            }
+    }
+}
+
 pub enum GWACFrame {
     Start,
-    Filename(String),
-    End,
+    Filename(FrameName),
+    End(FrameStats),
     Star(GWACData),
 }
 
-pub struct GWACReader {
-    // NOTE lazily initialize so that new function is non-async
-    data_file_path: String,
+/// Where the GWAC data is read from.
+///
+/// - unix://path -- Unix domain socket (reconnects)
+/// - tcp://host:port -- TCP socket (reconnects)
+/// - fifo:path -- named pipe (reopened when the writer closes)
+/// - path -- plain file, the end of the file is the end of the night
+#[derive(Clone, Debug, PartialEq)]
+pub enum GWACSource {
+    File(String),
+    Fifo(String),
+    Unix(String),
+    Tcp(String),
+}
+
+impl GWACSource {
+    /// Does losing the source mean reconnecting (instead of the end of the night)?
+    fn reconnects(&self) -> bool {
+        match self {
+            GWACSource::File(_) => false,
+            _ => true,
+        }
+    }
+}
+
+impl FromStr for GWACSource {
+    type Err = String;
+
+    fn from_str(uri: &str) -> Result<GWACSource, String> {
+        let source = if uri.starts_with("unix://") {
+            GWACSource::Unix(uri["unix://".len()..].to_string())
+        } else if uri.starts_with("tcp://") {
+            GWACSource::Tcp(uri["tcp://".len()..].to_string())
+        } else if uri.starts_with("fifo://") {
+            GWACSource::Fifo(uri["fifo://".len()..].to_string())
+        } else if uri.starts_with("fifo:") {
+            GWACSource::Fifo(uri["fifo:".len()..].to_string())
+        } else if uri.contains("://") {
+            return Err(format!("Unknown GWAC source scheme: {}", uri));
+        } else {
+            GWACSource::File(uri.to_string())
+        };
+
+        match &source {
+            GWACSource::File(path)
+            | GWACSource::Fifo(path)
+            | GWACSource::Unix(path)
+            | GWACSource::Tcp(path) if path.is_empty() => {
+                Err(format!("Missing path or address in GWAC source: {}", uri))
+            }
+            GWACSource::Tcp(addr) if !addr.contains(':') => {
+                Err(format!("GWAC tcp source must be tcp://host:port: {}", uri))
+            }
+            _ => Ok(source),
+        }
+    }
+}
+
+impl std::fmt::Display for GWACSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            GWACSource::File(path) => write!(f, "{}", path),
+            GWACSource::Fifo(path) => write!(f, "fifo:{}", path),
+            GWACSource::Unix(path) => write!(f, "unix://{}", path),
+            GWACSource::Tcp(addr) => write!(f, "tcp://{}", addr),
+        }
+    }
+}
+
+type GWACStream = BufReader<Box<dyn AsyncRead + Unpin + Send>>;
+
+enum ReadEnd {
+    /// source closed or errored, after reading any lines or not
+    Disconnected { received_data: bool },
+    /// receiving end of the data channel is gone (shutting down)
+    ReceiverClosed,
+}
+
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// Sessions lasting this long reset the backoff even without data.
+const STABLE_SESSION: Duration = Duration::from_secs(10);
+
+/// Backoff before reconnecting after a session, only a session that
+/// delivered data or lasted STABLE_SESSION starts over from MIN_BACKOFF.
+///
+/// NOTE an upstream that accepts and closes right away would otherwise
+///      be reconnected to in a busy loop
+fn session_backoff(backoff: Duration, received_data: bool, session_len: Duration) -> Duration {
+    if received_data || session_len >= STABLE_SESSION {
+        MIN_BACKOFF
+    } else {
+        backoff
+    }
+}
+
+/// Why a star line was rejected.
+#[derive(Debug, PartialEq)]
+pub enum LineError {
+    FieldCount(usize),
+    BadField { field: &'static str, value: String },
+}
+
+impl std::fmt::Display for LineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            LineError::FieldCount(count) => write!(
+                f,
+                "expected {} fields, found {}",
+                STAR_LINE_FIELDS,
+                count
+            ),
+            LineError::BadField { field, value } => {
+                write!(f, "bad {} value: {}", field, value)
+            }
+        }
+    }
+}
+
+const STAR_LINE_FIELDS: usize = 10;
+
+/// Number of recent frames the star count of a frame is compared against.
+const STAR_COUNT_HISTORY: usize = 10;
+/// Fraction the star count may deviate from the recent median before flagging.
+const STAR_COUNT_DEVIATION: f32 = 0.25;
+
+fn parse_float(field: &'static str, value: &str) -> Result<f32, LineError> {
+    match value.parse::<f32>() {
+        Ok(val) if val.is_finite() => Ok(val),
+        _ => Err(LineError::BadField {
+            field,
+            value: value.to_string(),
+        }),
+    }
+}
+
+fn parse_time(field: &'static str, value: &str) -> Result<f64, LineError> {
+    match value.parse::<f64>() {
+        Ok(val) if val.is_finite() => Ok(val),
+        _ => Err(LineError::BadField {
+            field,
+            value: value.to_string(),
+        }),
+    }
+}
+
+/// Parses a star line of the form:
+///
+/// `xpix ypix ra dec zone star_id mag timestamp ellipiticity ccd_num`
+pub fn parse_star_line(line: &str) -> Result<GWACData, LineError> {
+    let fields = line.split_whitespace().collect::<Vec<&str>>();
+    if fields.len() != STAR_LINE_FIELDS {
+        return Err(LineError::FieldCount(fields.len()));
+    }
+
+    Ok(GWACData {
+        xpix: parse_float("xpix", fields[0])?,
+        ypix: parse_float("ypix", fields[1])?,
+        ra: parse_float("ra", fields[2])?,
+        dec: parse_float("dec", fields[3])?,
+        zone: fields[4].to_string(),
+        star_id: fields[5].to_string(),
+        mag: parse_float("mag", fields[6])?,
+        timestamp: parse_time("timestamp", fields[7])?,
+        ellipiticity: parse_float("ellipiticity", fields[8])?,
+        ccd_num: fields[9].to_string(),
+    })
+}
+
+/// Parses the observation time (since UTC midnight) out of a frame file name.
+///
+/// The last three `_` separated parts are the hour, minute and second
+/// (e.g. `gwac_gwac_gwac_13_4_59`), anything trailing the seconds
+/// (e.g. a `.star` extension) is ignored.
+pub fn parse_frame_time(name: &str) -> Option<Duration> {
+    let name = name.rsplit('/').next()?;
+    let parts = name.rsplitn(4, '_').collect::<Vec<&str>>();
+    if parts.len() < 3 {
+        return None;
+    }
+
+    let secs_end = parts[0]
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or_else(|| parts[0].len());
+    let secs = parts[0][..secs_end]
+        .trim_end_matches('.')
+        .parse::<f32>()
+        .ok()?;
+    let mins = parts[1].parse::<u64>().ok()?;
+    let hours = parts[2].parse::<u64>().ok()?;
+
+    // NOTE 60 seconds is allowed for leap seconds
+    if hours >= 24 || mins >= 60 || secs >= 61.0 {
+        return None;
+    }
+
+    Some(
+        Duration::from_secs(hours * 3600 + mins * 60)
+            + Duration::from_millis((secs * 1000.0) as u64),
+    )
+}
+
+/// Does the star count deviate strongly from the median of the recent frames?
+pub fn is_star_count_outlier(recent: &[usize], count: usize) -> bool {
+    // NOTE too few frames to know what is normal
+    if recent.len() < 3 {
+        return false;
+    }
+
+    let mut sorted = recent.to_vec();
+    sorted.sort_unstable();
+    let median = sorted[sorted.len() / 2] as f32;
+
+    (count as f32 - median).abs() > STAR_COUNT_DEVIATION * median
+}
+
+/// Interprets the lines of a GWAC source as frames:
+///
+/// ```text
+/// start
+/// <file name>
+/// <star line>
+/// ...
+/// end
+/// ```
+#[derive(Default)]
+pub struct FrameParser {
+    in_frame: bool,
+    expect_filename: bool,
+    stats: FrameStats,
+    seen: HashSet<(String, String)>,
+    star_counts: VecDeque<usize>,
+}
+
+impl FrameParser {
+    pub fn new() -> FrameParser {
+        FrameParser::default()
+    }
+
+    pub fn parse_line(&mut self, line: &str) -> Vec<GWACFrame> {
+        let line = line.trim();
+        if line.is_empty() {
+            return Vec::new();
+        }
+
+        if line == "start" {
+            let mut frames = Vec::new();
+            if self.in_frame {
+                self.stats.truncated = true;
+                frames.push(self.end_frame());
+            }
+            self.in_frame = true;
+            self.expect_filename = true;
+            frames.push(GWACFrame::Start);
+            return frames;
+        }
+
+        if line == "end" {
+            if !self.in_frame {
+                return Vec::new();
+            }
+            return vec![self.end_frame()];
+        }
+
+        if !self.in_frame {
+            self.stats.dropped += 1;
+            return Vec::new();
+        }
+
+        // NOTE file names have no whitespace but star lines always do,
+        //      so a missing file name does not cost us the first star
+        if self.expect_filename {
+            self.expect_filename = false;
+            if !line.contains(char::is_whitespace) {
+                return vec![GWACFrame::Filename(FrameName {
+                    name: line.to_string(),
+                    obs_time: parse_frame_time(line),
+                })];
+            }
+            self.stats.missing_filename = true;
+        }
+
+        match parse_star_line(line) {
+            Ok(star) => {
+                if self
+                    .seen
+                    .insert((star.ccd_num.clone(), star.star_id.clone()))
+                {
+                    self.stats.stars += 1;
+                    vec![GWACFrame::Star(star)]
+                } else {
+                    self.stats.duplicates += 1;
+                    Vec::new()
+                }
+            }
+            Err(err) => {
+                self.stats.malformed += 1;
+                if self.stats.first_error.is_none() {
+                    self.stats.first_error = Some(err.to_string());
+                }
+                Vec::new()
+            }
+        }
+    }
+
+    /// Closes the partial frame (if any) when the source is lost.
+    pub fn disconnect(&mut self) -> Option<GWACFrame> {
+        if !self.in_frame {
+            return None;
+        }
+        self.stats.truncated = true;
+
+        Some(self.end_frame())
+    }
+
+    fn end_frame(&mut self) -> GWACFrame {
+        if self.expect_filename {
+            self.stats.missing_filename = true;
+        }
+
+        let mut stats = std::mem::replace(&mut self.stats, FrameStats::default());
+
+        // NOTE truncated frames say nothing about the normal star count
+        if !stats.truncated {
+            let recent = self.star_counts.iter().cloned().collect::<Vec<usize>>();
+            stats.star_count_outlier = is_star_count_outlier(&recent, stats.stars);
+
+            self.star_counts.push_back(stats.stars);
+            if self.star_counts.len() > STAR_COUNT_HISTORY {
+                self.star_counts.pop_front();
+            }
+        }
+
+        self.in_frame = false;
+        self.expect_filename = false;
+        self.seen.clear();
+
+        GWACFrame::End(stats)
+    }
+}
+
+/// A frame message tagged with the camera (GWAC source) it was read from.
+pub struct GWACMessage {
+    pub camera: usize,
+    pub frame: GWACFrame,
+}
+
+/// All GWAC sources of a run (one per camera) merged into one channel.
+pub struct GWACReaders {
+    readers: Vec<GWACReader>,
     // NOTE use option so later can move out of it
-    data_chan: (Sender<GWACFrame>, Option<Receiver<GWACFrame>>),
+    data_rx: Option<Receiver<GWACMessage>>,
 }
 
-impl GWACReader {
-    pub fn new(data_file: &str) -> GWACReader {
-        // NOTE for now a large number (can tweak this later)
-        // -- if more than 100,000 stars might overrun channel
-        //    and causing locking RIGHT???
-        let (tx, rx) = channel(100_000);
-        let data_chan = (tx, Some(rx));
+impl GWACReaders {
+    pub fn new(data_sources: &[&str]) -> GWACReaders {
+        // NOTE lines are assembled into frames as soon as they are read
+        //      (see FrameQueue), so this only needs to smooth out bursts
+        //      -- a full channel holds back the readers (and upstream)
+        let (tx, rx) = channel(10_000);
 
-        GWACReader {
-            data_file_path: data_file.to_string(),
-            data_chan,
+        let readers = data_sources
+            .iter()
+            .enumerate()
+            .map(|(camera, data_source)| GWACReader::new(data_source, camera, tx.clone()))
+            .collect();
+
+        GWACReaders {
+            readers,
+            data_rx: Some(rx),
         }
     }
 
-    pub fn get_data_channel(&mut self) -> Receiver<GWACFrame> {
-        if self.data_chan.1.is_some() {
-            self.data_chan.1.take().expect("Will never panic here.")
+    /// Names of the cameras indexed by `GWACMessage::camera`.
+    pub fn camera_names(&self) -> Vec<String> {
+        self.readers.iter().map(|reader| reader.source.to_string()).collect()
+    }
+
+    pub fn get_data_channel(&mut self) -> Receiver<GWACMessage> {
+        if self.data_rx.is_some() {
+            self.data_rx.take().expect("Will never panic here.")
         } else {
             panic!("Only one GWAC data receiver is allowed.")
         }
     }
 
+    // NOTE should only be called once
+    // NOTE each camera is read in its own task so a slow or
+    //      reconnecting camera does not hold up the others
+    pub async fn start(self) {
+        for mut reader in self.readers {
+            tokio::spawn(async move { reader.start().await });
+        }
+    }
+}
+
+/// Where the GWAC data of one camera is read from.
+pub struct GWACReader {
+    // NOTE lazily initialize so that new function is non-async
+    source: GWACSource,
+    camera: usize,
+    data_tx: Sender<GWACMessage>,
+    // NOTE kept over reconnects for the star count history
+    parser: FrameParser,
+}
+
+impl GWACReader {
+    pub fn new(data_source: &str, camera: usize, data_tx: Sender<GWACMessage>) -> GWACReader {
+        let source = GWACSource::from_str(data_source)
+            .unwrap_or_else(|err| panic!("Problem parsing GWAC source: {}", err));
+
+        GWACReader {
+            source,
+            camera,
+            data_tx,
+            parser: FrameParser::new(),
+        }
+    }
+
+    // send error denotes other ends pipe is closed
+    // - exit and assume that other processes have sent
+    //   shutdown signal
+    async fn send(&mut self, frame: GWACFrame) -> Result<(), ()> {
+        let camera = self.camera;
+        self.data_tx
+            .send(GWACMessage { camera, frame })
+            .await
+            .map_err(|_| ())
+    }
+
+    async fn connect(&self) -> std::io::Result<GWACStream> {
+        let stream: Box<dyn AsyncRead + Unpin + Send> = match &self.source {
+            GWACSource::File(path) | GWACSource::Fifo(path) => {
+                Box::new(File::open(path.clone()).await?)
+            }
+            GWACSource::Unix(path) => {
+                Box::new(UnixStream::connect(path.clone()).await?)
+            }
+            GWACSource::Tcp(addr) => {
+                Box::new(TcpStream::connect(addr.clone()).await?)
+            }
+        };
+
+        Ok(BufReader::new(stream))
+    }
+
     // NOTE should only be called once
     // NOTE separates parsing from interpreting
     // NOTE in separate thread to simplify keeping data and reading off data
     //      - also might speed up as can read off data before ticker is unlocked RIGHT???
+    // NOTE for sockets and fifos losing the upstream is not the end of the
+    //      night, we reconnect with backoff until the program is shutdown
     pub async fn start(&mut self) {
-        let data_file = File::open(&self.data_file_path)
-            .await
-            .expect("Could not open GWAC file.");
-        let mut data_file = BufReader::new(data_file);
+        let log = log::get_root_logger();
+        let mut backoff = MIN_BACKOFF;
+
+        loop {
+            let data_file = match self.connect().await {
+                Ok(data_file) => {
+                    info!(log, "Connected to GWAC source."; "source"=>self.source.to_string());
+                    data_file
+                }
+                Err(err) => {
+                    if !self.source.reconnects() {
+                        panic!("Could not open GWAC file: {}", err);
+                    }
+
+                    warn!(log, "Could not connect to GWAC source, retrying...";
+                          "source"=>self.source.to_string(),
+                          "err"=>err.to_string(),
+                          "backoff"=>format!("{:?}", backoff));
+                    delay(Instant::now() + backoff).await;
+                    backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                    continue;
+                }
+            };
+
+            let connected = Instant::now();
+            match self.read_frames(data_file).await {
+                ReadEnd::ReceiverClosed => return,
+                ReadEnd::Disconnected { .. } if !self.source.reconnects() => return,
+                ReadEnd::Disconnected { received_data } => {
+                    backoff = session_backoff(backoff, received_data, connected.elapsed());
+                    warn!(log, "Lost GWAC source, reconnecting...";
+                          "source"=>self.source.to_string(),
+                          "backoff"=>format!("{:?}", backoff));
+                    delay(Instant::now() + backoff).await;
+                    backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+                }
+            }
+        }
+    }
 
-        let mut recently_started = false;
+    async fn read_frames(&mut self, mut data_file: GWACStream) -> ReadEnd {
+        let log = log::get_root_logger();
         let mut buf = String::new();
+        let mut received_data = false;
         loop {
             // NOTE read_line does not do this automatically
             buf.clear();
-            match data_file.read_line(&mut buf).await {
-                Ok(val) if val == 0 => break, // TODO graceful shutdown
-                Ok(_) => (),
-                _ => break, // TODO graceful shutdown
-            }
-
-            let data = buf.trim();
-
-            // NOTE: Right after start signal a file name is sent
-            //       this logic handles parsing and sending that
-            if recently_started {
-                match self
-                    .data_chan
-                    .0
-                    .send(GWACFrame::Filename(data.to_string()))
-                    .await
-                {
-                    Ok(_) => (),
-                    _ => break,
-                };
-                recently_started = false;
-                continue;
-            }
-
-            if data == "start" {
-                // send error denotes other ends pipe is closed
-                // - exit and assume that other processes have sent
-                //   shutdown signal
-                match self.data_chan.0.send(GWACFrame::Start).await {
-                    Ok(_) => (),
-                    _ => break,
-                };
-                recently_started = true;
-            } else if data == "end" {
-                match self.data_chan.0.send(GWACFrame::End).await {
-                    Ok(_) => (),
-                    _ => break,
-                };
-            } else {
-                let fields = data.split_whitespace().collect::<Vec<&str>>();
-
-                let xpix = unwrap_or_continue!(fields[0].parse::<f32>());
-                let ypix = unwrap_or_continue!(fields[1].parse::<f32>());
-                let ra = unwrap_or_continue!(fields[2].parse::<f32>());
-                let dec = unwrap_or_continue!(fields[3].parse::<f32>());
-                let zone = fields[4].trim().to_string();
-                let star_id = fields[5].trim().to_string();
-                let mag = unwrap_or_continue!(fields[6].parse::<f32>());
-                let timestamp = unwrap_or_continue!(fields[7].parse::<f32>());
-                let ellipiticity =
-                    unwrap_or_continue!(fields[8].parse::<f32>());
-                let ccd_num = fields[9].trim().to_string();
-
-                match self
-                    .data_chan
-                    .0
-                    .send(GWACFrame::Star(GWACData {
-                        xpix,
-                        ypix,
-                        ra,
-                        dec,
-                        zone,
-                        star_id,
-                        mag,
-                        timestamp,
-                        ellipiticity,
-                        ccd_num,
-                    }))
-                    .await
-                {
-                    Ok(_) => (),
-                    _ => break,
-                };
+            let disconnected = match data_file.read_line(&mut buf).await {
+                Ok(val) if val == 0 => true,
+                Ok(_) => false,
+                _ => true,
+            };
+
+            if disconnected {
+                // NOTE close the partial frame so the ticker does not
+                //      merge it with the first frame after reconnecting
+                if let Some(end) = self.parser.disconnect() {
+                    warn!(log, "GWAC source lost in the middle of a frame.";
+                          "source"=>self.source.to_string());
+                    if self.send(end).await.is_err() {
+                        return ReadEnd::ReceiverClosed;
+                    }
+                }
+
+                return ReadEnd::Disconnected { received_data };
+            }
+            received_data = true;
+
+            for frame in self.parser.parse_line(&buf) {
+                if self.send(frame).await.is_err() {
+                    return ReadEnd::ReceiverClosed;
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_gwac_source_from_str() {
+        assert_eq!(
+            GWACSource::from_str("unix:///tmp/gwac.sock"),
+            Ok(GWACSource::Unix("/tmp/gwac.sock".to_string()))
+        );
+        assert_eq!(
+            GWACSource::from_str("tcp://localhost:9000"),
+            Ok(GWACSource::Tcp("localhost:9000".to_string()))
+        );
+        assert_eq!(
+            GWACSource::from_str("fifo:/tmp/gwac"),
+            Ok(GWACSource::Fifo("/tmp/gwac".to_string()))
+        );
+        assert_eq!(
+            GWACSource::from_str("fifo:///tmp/gwac"),
+            Ok(GWACSource::Fifo("/tmp/gwac".to_string()))
+        );
+        assert_eq!(
+            GWACSource::from_str("data/gwac.txt"),
+            Ok(GWACSource::File("data/gwac.txt".to_string()))
+        );
+
+        assert!(GWACSource::from_str("tcp://localhost").is_err());
+        assert!(GWACSource::from_str("unix://").is_err());
+        assert!(GWACSource::from_str("http://localhost:80").is_err());
+    }
+
+    #[test]
+    fn test_session_backoff() {
+        let backoff = Duration::from_secs(4);
+
+        // NOTE accepted and closed right away, keep backing off
+        assert_eq!(session_backoff(backoff, false, Duration::from_millis(5)), backoff);
+        assert_eq!(session_backoff(backoff, true, Duration::from_millis(5)), MIN_BACKOFF);
+        assert_eq!(session_backoff(backoff, false, STABLE_SESSION), MIN_BACKOFF);
+    }
+
+    const STAR_LINE: &str = "64.1 63.2 62.3 61.4 zone star_a 12.5 3 59.6 ccd_1";
+
+    #[test]
+    fn test_parse_star_line() {
+        let star = parse_star_line(STAR_LINE).unwrap();
+        assert_eq!(star.star_id, "star_a");
+        assert_eq!(star.ccd_num, "ccd_1");
+        assert_eq!(star.mag, 12.5);
+
+        assert_eq!(
+            parse_star_line("64.1 63.2 62.3").err(),
+            Some(LineError::FieldCount(3))
+        );
+        assert_eq!(
+            parse_star_line("64.1 63.2 62.3 61.4 zone star_a NaN 3 59.6 ccd_1").err(),
+            Some(LineError::BadField {
+                field: "mag",
+                value: "NaN".to_string()
+            })
+        );
+    }
+
+    #[test]
+    fn test_parse_frame_time() {
+        assert_eq!(
+            parse_frame_time("gwac_gwac_gwac_13_4_59"),
+            Some(Duration::from_secs(13 * 3600 + 4 * 60 + 59))
+        );
+        assert_eq!(
+            parse_frame_time("/data/gwac_1_0_2.5.star"),
+            Some(Duration::from_millis(3600_000 + 2500))
+        );
+        assert_eq!(parse_frame_time("0.123_gwac.gwac.star"), None);
+        assert_eq!(parse_frame_time("gwac_25_0_0"), None);
+    }
+
+    fn frame_stats(parser: &mut FrameParser, lines: &[&str]) -> Vec<FrameStats> {
+        lines
+            .iter()
+            .flat_map(|line| parser.parse_line(line))
+            .filter_map(|frame| match frame {
+                GWACFrame::End(stats) => Some(stats),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_frame_parser_counters() {
+        let mut parser = FrameParser::new();
+        let stats = frame_stats(
+            &mut parser,
+            &[
+                STAR_LINE,
+                "start",
+                "gwac_gwac_gwac_1_2_3",
+                STAR_LINE,
+                STAR_LINE,
+                "64.1 63.2",
+                "end",
+            ],
+        );
+
+        assert_eq!(
+            stats,
+            vec![FrameStats {
+                stars: 1,
+                malformed: 1,
+                dropped: 1,
+                duplicates: 1,
+                first_error: Some(LineError::FieldCount(2).to_string()),
+                ..FrameStats::default()
+            }]
+        );
+    }
+
+    #[test]
+    fn test_frame_parser_missing_filename() {
+        let mut parser = FrameParser::new();
+        let frames = ["start", STAR_LINE, "end"]
+            .iter()
+            .flat_map(|line| parser.parse_line(line))
+            .collect::<Vec<GWACFrame>>();
+
+        match (&frames[1], &frames[2]) {
+            (GWACFrame::Star(_), GWACFrame::End(stats)) => {
+                assert!(stats.missing_filename);
+                assert_eq!(stats.stars, 1);
+            }
+            _ => panic!("Expected the first star to be kept."),
+        }
+
+        // NOTE start without an end truncates the open frame
+        let stats = frame_stats(&mut parser, &["start", "name", "start"]);
+        assert_eq!(stats.len(), 1);
+        assert!(stats[0].truncated);
+        assert!(parser.disconnect().is_some());
+    }
+
+    #[test]
+    fn test_star_count_outlier() {
+        assert!(!is_star_count_outlier(&[100, 100], 10));
+        assert!(!is_star_count_outlier(&[100, 98, 102, 101], 95));
+        assert!(is_star_count_outlier(&[100, 98, 102, 101], 50));
+        assert!(is_star_count_outlier(&[100, 98, 102, 101], 200));
+    }
+}
@@ -1,5 +1,9 @@
+use crate::filter_scheduler::FilterStatus;
+use crate::frame_queue::QueueStatus;
 use crate::log;
 use async_std::sync::Mutex;
+use std::collections::BTreeMap;
+use std::time::Duration;
 use tokio::sync as ts;
 
 struct Event;
@@ -13,6 +17,10 @@ pub struct InformationHandler {
     iterations_chan:
         (ts::mpsc::Sender<usize>, Mutex<ts::mpsc::Receiver<usize>>),
     total_iterations: Option<usize>,
+    // NOTE std mutex as it is only held to copy a few values
+    camera_cadences: std::sync::Mutex<BTreeMap<String, Duration>>,
+    queue_status: std::sync::Mutex<Option<QueueStatus>>,
+    filter_status: std::sync::Mutex<FilterStatus>,
     pub is_offline: bool,
 }
 
@@ -39,6 +47,9 @@ impl InformationHandler {
             total_iterations,
             is_offline,
             iterations_chan,
+            camera_cadences: std::sync::Mutex::new(BTreeMap::new()),
+            queue_status: std::sync::Mutex::new(None),
+            filter_status: std::sync::Mutex::new(FilterStatus::default()),
         }
     }
 
@@ -58,6 +69,36 @@ impl InformationHandler {
         self.iterations_chan.0.clone()
     }
 
+    /// Records the time between the last two frames of a camera.
+    pub fn set_camera_cadence(&self, camera: &str, cadence: Duration) {
+        self.camera_cadences
+            .lock()
+            .expect("Camera cadence lock poisoned.")
+            .insert(camera.to_string(), cadence);
+    }
+
+    /// Records the status of the live frame queue when the last frame was taken off.
+    pub fn set_queue_status(&self, status: QueueStatus) {
+        *self.queue_status.lock().expect("Queue status lock poisoned.") = Some(status);
+    }
+
+    /// Records how the stars of the last tick were scheduled.
+    pub fn set_filter_status(&self, status: FilterStatus) {
+        *self.filter_status.lock().expect("Filter status lock poisoned.") = status;
+    }
+
+    fn camera_cadences_str(&self) -> String {
+        self.camera_cadences
+            .lock()
+            .expect("Camera cadence lock poisoned.")
+            .iter()
+            .map(|(camera, cadence)| {
+                format!("{}={:.1}s", camera, cadence.as_millis() as f32 / 1000.0)
+            })
+            .collect::<Vec<String>>()
+            .join(" ")
+    }
+
     pub async fn progress_log(&self) {
         let mut iterations_chan_rx = self.iterations_chan.1.lock().await;
         let log = log::get_root_logger();
@@ -74,8 +115,9 @@ impl InformationHandler {
                 && iterations
                     == self.total_iterations.expect("Should never happen.")
             {
-                info!(log, "Sending shutdown signal...");
-                self.trigger_shutdown();
+                // NOTE the ticker ends the run once the stars run out
+                //      (after the detector finished the queued windows)
+                info!(log, "All samples ticked...");
                 return;
             }
 
@@ -97,13 +139,31 @@ impl InformationHandler {
                         "StarsPerTenSec"=>format!("{}", sps*10.0),
                         "%Progress"=>format!("{}%", pp));
                 } else {
+                    let queue_status = self
+                        .queue_status
+                        .lock()
+                        .expect("Queue status lock poisoned.")
+                        .clone()
+                        .unwrap_or_default();
+                    let filter_status = self
+                        .filter_status
+                        .lock()
+                        .expect("Filter status lock poisoned.")
+                        .clone();
                     info!(log, "";
                           "TotTime"=>format!("{}s", now.elapsed().as_secs()),
                           "IterationsLeft"=>"UNKNOWN",
                           "EstTimeLeft"=>"UNKNOWN",
                           "StarsPerSec"=>format!("{}", sps),
                           "StarsPerTenSec"=>format!("{}", sps*10.0),
-                          "%Progress"=>"UNKNOWN");
+                          "%Progress"=>"UNKNOWN",
+                          "CameraCadence"=>self.camera_cadences_str(),
+                          "QueueDepth"=>queue_status.depth,
+                          "FramesBehind"=>queue_status.frames_behind,
+                          "Lag"=>format!("{:?}", queue_status.lag),
+                          "DroppedFrames"=>queue_status.dropped_frames,
+                          "WatchedStars"=>filter_status.watched,
+                          "DeferredFilters"=>filter_status.deferred);
                 }
 
                 log_timer = std::time::Instant::now();
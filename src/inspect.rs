@@ -0,0 +1,284 @@
+use crate::cli::{for_each_star, InspectStarsOpts, InspectTemplatesOpts};
+use crate::experiment::stats;
+use crate::template::*;
+
+/// Most near-duplicate pairs listed per class.
+const MAX_LISTED_PAIRS: usize = 20;
+
+/// Number of bins of the template length histogram.
+const LENGTH_BINS: usize = 10;
+
+/// Report of a template bank (inspect-templates): template counts, length
+/// distribution, FFT length, group layout, device memory and the pairwise
+/// match of the templates.
+pub fn inspect_templates(opts: &InspectTemplatesOpts) {
+    let bank = read_template_bank(&opts.templates_file);
+    let sample_rate = read_template_sample_rate(&opts.templates_file);
+
+    println!("Template bank: {}", opts.templates_file);
+    match sample_rate {
+        Some(sample_rate) => println!("Sample rate: {}s", sample_rate),
+        None => println!("Sample rate: not given"),
+    }
+
+    let num_templates: usize = bank.iter().map(|(_, templates)| templates.len()).sum();
+    println!("\nTemplates: {}", num_templates);
+    for (class, templates) in bank.iter() {
+        println!("  {}: {}", class, templates.len());
+    }
+
+    let lengths = bank
+        .iter()
+        .flat_map(|(_, templates)| templates.iter().map(|template| template.len()))
+        .collect::<Vec<usize>>();
+    let max_len = *lengths.iter().max().expect("No templates in bank");
+    let (_, _, mean, std_dev) =
+        stats(&lengths.iter().map(|&len| len as f32).collect::<Vec<f32>>());
+    println!("\nTemplate lengths (samples)");
+    println!(
+        "  min: {} max: {} mean: {:.1} std_dev: {:.1} median: {}",
+        lengths.iter().min().expect("No templates in bank"),
+        max_len,
+        mean,
+        std_dev,
+        median(&lengths)
+    );
+    for (lo, hi, count) in length_histogram(&lengths, LENGTH_BINS) {
+        println!("  [{:>6}, {:>6}] {:>6} {}", lo, hi, count, "#".repeat(bar_len(count, lengths.len())));
+    }
+
+    let fft_len = padded_fft_len(max_len);
+    let kept_len = kept_fft_len(fft_len);
+    println!("\nFFT length: {} (longest template {} rounded up to a power of two)", fft_len, max_len);
+    println!("  kept rows (positive frequencies): {}", kept_len);
+
+    let class_sizes = bank.iter().map(|(_, templates)| templates.len()).collect::<Vec<usize>>();
+    let layout = group_layout(&class_sizes, opts.template_group_sz);
+    println!("\nTemplate groups (template_group_sz {}): {}", opts.template_group_sz, layout.len());
+    for (group_idx, (class_idx, group_len)) in layout.iter().enumerate() {
+        println!("  group {}: {} {} templates", group_idx, bank[*class_idx].0, group_len);
+    }
+
+    let largest_group = layout.iter().map(|(_, group_len)| *group_len).max().unwrap_or(0);
+    let (bank_bytes, op_bytes) =
+        device_memory(num_templates, fft_len, largest_group, opts.star_group_sz);
+    println!("\nEstimated device memory");
+    println!("  template spectra: {}", human_bytes(bank_bytes));
+    println!(
+        "  per filter operation (largest group x star_group_sz {}): {}",
+        opts.star_group_sz,
+        human_bytes(op_bytes)
+    );
+    println!("  total: {}", human_bytes(bank_bytes + op_bytes));
+
+    let templates = bank_templates(
+        &bank,
+        sample_rate,
+        opts.template_group_sz,
+        opts.dc_norm,
+        TemplateNorm::None,
+    );
+
+    println!("\nPairwise match (dc_norm {}, near-duplicates match >= {})", opts.dc_norm, opts.duplicate_match);
+    for (class_idx, class) in templates.classes.iter().enumerate() {
+        let matches = class_matches(&templates, class_idx);
+
+        let nearest = nearest_matches(&matches)
+            .into_iter()
+            .filter_map(|nearest| nearest.map(|(_, nearest_match)| nearest_match))
+            .collect::<Vec<f32>>();
+        if nearest.is_empty() {
+            println!("  {}: single template", class);
+            continue;
+        }
+        let (min, max, mean, _) = stats(&nearest);
+        println!(
+            "  {}: closest template match min: {:.4} max: {:.4} mean: {:.4}",
+            class, min, max, mean
+        );
+
+        let duplicates = near_duplicates(&matches, opts.duplicate_match);
+        println!("    near-duplicate pairs: {}", duplicates.len());
+        for (a, b, pair_match) in duplicates.iter().take(MAX_LISTED_PAIRS) {
+            println!(
+                "    templates {} and {} (lengths {} and {}): {:.4}",
+                a,
+                b,
+                bank[class_idx].1[*a].len(),
+                bank[class_idx].1[*b].len(),
+                pair_match
+            );
+        }
+        if duplicates.len() > MAX_LISTED_PAIRS {
+            println!("    ... and {} more", duplicates.len() - MAX_LISTED_PAIRS);
+        }
+    }
+}
+
+fn median(values: &[usize]) -> usize {
+    let mut values = values.to_vec();
+    values.sort_unstable();
+    values[values.len() / 2]
+}
+
+/// (lowest, highest, count) of equal width bins over the lengths.
+fn length_histogram(lengths: &[usize], bins: usize) -> Vec<(usize, usize, usize)> {
+    let min = *lengths.iter().min().expect("No template lengths");
+    let max = *lengths.iter().max().expect("No template lengths");
+    // NOTE ceiling so the bins cover max
+    let width = ((max - min + 1) + bins - 1) / bins;
+
+    (0..bins)
+        .map(|bin| {
+            let lo = min + bin * width;
+            let hi = lo + width - 1;
+            let count = lengths.iter().filter(|&&len| len >= lo && len <= hi).count();
+            (lo, hi, count)
+        })
+        .filter(|&(lo, _, _)| lo <= max)
+        .collect()
+}
+
+fn bar_len(count: usize, total: usize) -> usize {
+    (count * 40 + total - 1) / total
+}
+
+/// Estimated device memory (bytes) of the template spectra and of a single
+/// filter operation (see `filter::inner_product`), a template group
+/// against a star group: the star FFTs and the matrix products.
+fn device_memory(
+    num_templates: usize,
+    fft_len: usize,
+    largest_group: usize,
+    star_group_sz: usize,
+) -> (usize, usize) {
+    let complex = std::mem::size_of::<f32>() * 2;
+    let kept_len = kept_fft_len(fft_len);
+
+    let bank = num_templates * kept_len * complex;
+    let star_ffts = star_group_sz * (fft_len + kept_len) * complex;
+    // NOTE the double sided detector adds two complex products and takes the real part
+    let products = star_group_sz * largest_group * (2 * complex + std::mem::size_of::<f32>());
+
+    (bank, star_ffts + products)
+}
+
+fn human_bytes(bytes: usize) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+/// Closest other template (index, match) of every template, None for a
+/// single template.
+pub fn nearest_matches(matches: &[Vec<f32>]) -> Vec<Option<(usize, f32)>> {
+    matches
+        .iter()
+        .enumerate()
+        .map(|(i, row)| {
+            row.iter()
+                .enumerate()
+                .filter(|&(j, _)| j != i)
+                .map(|(j, &pair_match)| (j, pair_match))
+                .fold(None, |best: Option<(usize, f32)>, (j, pair_match)| match best {
+                    Some((_, best_match)) if best_match >= pair_match => best,
+                    _ => Some((j, pair_match)),
+                })
+        })
+        .collect()
+}
+
+/// Template pairs (i < j) matching at least `min_match`, best first.
+pub fn near_duplicates(matches: &[Vec<f32>], min_match: f32) -> Vec<(usize, usize, f32)> {
+    let mut pairs = matches
+        .iter()
+        .enumerate()
+        .flat_map(|(i, row)| {
+            row.iter()
+                .enumerate()
+                .skip(i + 1)
+                .filter(|&(_, &pair_match)| pair_match >= min_match)
+                .map(move |(j, &pair_match)| (i, j, pair_match))
+        })
+        .collect::<Vec<(usize, usize, f32)>>();
+    pairs.sort_by(|a, b| b.2.partial_cmp(&a.2).expect("NaN template match"));
+
+    pairs
+}
+
+/// Report of the stars of offline star data (inspect-stars), a line per
+/// star (streamed).
+pub fn inspect_stars(opts: &InspectStarsOpts) {
+    println!("uid\tid\ttype\tmodel\tsample_rate\tnum_samples\tmin\tmax\tmean\tstd_dev");
+
+    let mut num_stars = 0;
+    for_each_star(&opts.input_dirs, &opts.selection, &mut |star| {
+        let samples = star.samples.as_ref().map(|samples| samples.as_slice()).unwrap_or(&[]);
+        let (min, max, mean, std_dev) = stats(samples);
+
+        println!(
+            "{}\t{}\t{:?}\t{:?}\t{}\t{}\t{}\t{}\t{}\t{}",
+            star.uid,
+            star.id,
+            star.star_type,
+            star.model_type,
+            star.sample_rate,
+            samples.len(),
+            min,
+            max,
+            mean,
+            std_dev
+        );
+        num_stars += 1;
+    });
+
+    println!("Stars: {}", num_stars);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_length_histogram() {
+        let lengths = vec![10, 12, 19, 20, 29, 30];
+        let histogram = length_histogram(&lengths, 2);
+
+        assert_eq!(histogram, vec![(10, 20, 4), (21, 31, 2)]);
+        assert_eq!(
+            histogram.iter().map(|&(_, _, count)| count).sum::<usize>(),
+            lengths.len()
+        );
+        assert_eq!(length_histogram(&[5, 5], 10), vec![(5, 5, 2)]);
+    }
+
+    #[test]
+    fn test_matches() {
+        let matches = vec![
+            vec![1.0, 0.99, 0.5],
+            vec![0.99, 1.0, 0.98],
+            vec![0.5, 0.98, 1.0],
+        ];
+
+        assert_eq!(
+            nearest_matches(&matches),
+            vec![Some((1, 0.99)), Some((0, 0.99)), Some((1, 0.98))]
+        );
+        assert_eq!(near_duplicates(&matches, 0.98), vec![(0, 1, 0.99), (1, 2, 0.98)]);
+        assert_eq!(nearest_matches(&[vec![1.0]]), vec![None]);
+    }
+
+    #[test]
+    fn test_device_memory() {
+        // NOTE 4 templates of 1024 kept rows, 8 byte complex values
+        let (bank, op) = device_memory(4, 2048, 2, 3);
+        assert_eq!(bank, 4 * 1024 * 8);
+        assert_eq!(op, 3 * (2048 + 1024) * 8 + 3 * 2 * 20);
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+    }
+}
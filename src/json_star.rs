@@ -1,6 +1,9 @@
+use crate::cadence::{estimate_cadence, GWAC_SAMPLE_RATE};
 use crate::star::{parse_model, Star, StarModelType, StarType};
+use crate::utils;
 use std::collections::HashMap;
 use std::fs;
+use std::path::Path;
 
 /*
 #[allow(non_snake_case)]
@@ -67,30 +70,46 @@ pub fn parse_star_file(star_file: &str) -> Option<Star> {
                     .expect("Failed to read f(t) data")
                     .parse::<f32>()
                     .expect("Failed to parse f(t) data"),
+                // NOTE optional sample time (seconds), written by write_star_file
+                star_dp
+                    .get("time")
+                    .and_then(|time| time.as_str())
+                    .and_then(|time| time.parse::<f32>().ok()),
             )
         })
-        .fold(HashMap::new(), |mut map: HashMap<&str, Vec<f32>>, star| {
-            match map.get_mut(&star.0) {
-                Some(list) => list.push(star.1),
-                None => {
-                    let mut list = Vec::new();
-                    list.push(star.1);
-                    map.insert(star.0, list);
+        .fold(
+            HashMap::new(),
+            |mut map: HashMap<&str, (Vec<f32>, Vec<f32>)>, star| {
+                let (data, times) = map.entry(star.0).or_insert_with(|| (Vec::new(), Vec::new()));
+                data.push(star.1);
+                if let Some(time) = star.2 {
+                    times.push(time);
                 }
-            }
 
-            map
-        })
+                map
+            },
+        )
         .into_iter()
-        .map(|(key, data)| Star {
-            id: key.to_string(),
-            uid: key.to_string(),
-            samples: Some(data),
-            samples_tick_index: std::cell::RefCell::new(0),
-            star_type: StarType::Unknown,
-            model_type: StarModelType::None,
-            model: parse_model(StarModelType::None, "".to_string()),
-            sample_rate: 15,
+        .map(|(key, (data, times))| {
+            // NOTE GWAC JSON dumps carry no sample times
+            let sample_rate = match estimate_cadence(&times) {
+                Some(cadence) if times.len() == data.len() && cadence >= 1.0 => {
+                    cadence.round() as i32
+                }
+                _ => GWAC_SAMPLE_RATE,
+            };
+
+            Star {
+                id: key.to_string(),
+                uid: key.to_string(),
+                samples: Some(data),
+                samples_tick_index: std::sync::atomic::AtomicUsize::new(0),
+                position: None,
+                star_type: StarType::Unknown,
+                model_type: StarModelType::None,
+                model: parse_model(StarModelType::None, "".to_string()),
+                sample_rate,
+            }
         })
         .collect::<Vec<Star>>();
 
@@ -100,3 +119,38 @@ pub fn parse_star_file(star_file: &str) -> Option<Star> {
     //crate::utils::debug_plt(&star.samples[..], &star.uid[..], None);
     Some(star)
 }
+
+#[derive(Serialize)]
+struct StarDataPointOut<'a> {
+    star_id: &'a str,
+    magnorm: String,
+    time: String,
+}
+
+#[allow(non_snake_case)]
+#[derive(Serialize)]
+struct StarFileOut<'a> {
+    currentStarId: Vec<StarDataPointOut<'a>>,
+}
+
+/// Writes a star as a GWAC style JSON file (`<id>.json`) into the directory.
+///
+/// NOTE the sample times (seconds from the first sample) keep the sample rate
+pub fn write_star_file(star: &Star, dir: &Path) {
+    let samples = star.samples.as_ref().expect("Star has no samples to write");
+    let star_file = StarFileOut {
+        currentStarId: samples
+            .iter()
+            .enumerate()
+            .map(|(idx, sample)| StarDataPointOut {
+                star_id: &star.id,
+                magnorm: sample.to_string(),
+                time: (idx as i64 * star.sample_rate as i64).to_string(),
+            })
+            .collect(),
+    };
+
+    let contents = serde_json::to_string(&star_file).expect("Failed to serialize Star JSON file");
+    fs::write(dir.join(format!("{}.json", utils::star_file_name(&star.id))), contents)
+        .unwrap_or_else(|_| panic!("Failed to write Star JSON file of {}", star.id));
+}
@@ -30,36 +30,50 @@ extern crate jemallocator;
 #[global_allocator]
 static ALLOC: jemallocator::Jemalloc = jemallocator::Jemalloc;
 
+mod arima;
 mod async_utils;
+mod cadence;
 pub mod cli; // pub for documentation purposes
+mod convert;
 pub mod cyclic_queue;
 mod dat_star;
 mod detector;
 mod detector_utils;
+mod experiment;
 mod sqlite_stars;
 mod filter;
 mod filter_utils;
+mod filter_scheduler;
+mod frame_queue;
 mod gwac_reader;
 mod info_handler;
+mod inspect;
 mod json_star;
 mod log;
+mod mf_config;
 mod python;
+mod run_file;
 mod star;
 mod sw_star;
 mod template;
+mod template_prune;
 mod tester;
 mod ticker;
 mod toml_star;
 mod utils;
+mod veto;
 
-use async_utils::{twin_barrier, TwinBarrier};
 use cli::*;
 use detector::Detector;
-use gwac_reader::GWACReader;
+use experiment::RunSummary;
+use filter_scheduler::FilterScheduler;
+use gwac_reader::GWACReaders;
 use info_handler::InformationHandler;
 use log::*;
+use run_file::Run;
+use sqlite_stars::ResultsWriter;
 use sw_star::*;
-use ticker::Ticker;
+use ticker::{Ticker, WindowSnapshot};
 
 use arrayfire as AF;
 
@@ -67,26 +81,27 @@ use colored::*;
 
 use std::collections::HashMap;
 use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
-use tokio::sync::Lock;
+use tokio::sync::mpsc::{channel, Sender};
 
 use cpuprofiler::PROFILER;
 
 struct RunState {
-    stars: Lock<Vec<SWStar>>,
-    computation_end: TwinBarrier,
-    tick_end: TwinBarrier,
+    stars: Vec<SWStar>,
+    snapshot_tx: Sender<WindowSnapshot>,
     info_handler: Arc<InformationHandler>,
     detector_opts: DetectorOpts,
-    gwac_reader: Option<GWACReader>,
+    scheduler: Arc<FilterScheduler>,
+    gwac_reader: Option<GWACReaders>,
     // FIXME average stars per fragment
     // FIXME average stars per iteration???
 }
 
-// computation_end is a misnomer, it merely means that all the data for the current window
-//   has been copied and thus stars can be safely updated without producing unintended results
-// tick_end signifies that main can start the next computation
+// the ticker runs on its own runtime (thread) and hands main the windows of
+//   every tick through snapshot_tx, so ticking never waits on the filter
+//   (up to the snapshot lag)
 fn tick_driver(state: RunState) {
     let rt = tokio::runtime::Builder::new()
         .blocking_threads(4)
@@ -95,11 +110,11 @@ fn tick_driver(state: RunState) {
         .expect("Problem building tokio runtime.");
     let RunState {
         stars,
-        computation_end,
-        tick_end,
+        snapshot_tx,
         info_handler,
         mut gwac_reader,
         detector_opts,
+        scheduler,
     } = state;
 
     rt.block_on(
@@ -124,21 +139,23 @@ fn tick_driver(state: RunState) {
                 None
             };
 
-            if let Some(mut gwac_reader) = gwac_reader {
-                tokio::spawn(
-                    async move {
-                        gwac_reader.start().await;
-                    },
-                );
+            let gwac_cameras = gwac_reader
+                .as_ref()
+                .map(|gwac_reader| gwac_reader.camera_names())
+                .unwrap_or_default();
+
+            if let Some(gwac_reader) = gwac_reader {
+                gwac_reader.start().await;
             }
 
             Ticker::new(
-                computation_end,
-                tick_end,
+                snapshot_tx,
                 stars,
                 gwac_rx_chan,
+                gwac_cameras,
                 detector_opts.clone(),
                 info_handler,
+                scheduler,
             )
             .tick()
             .await;
@@ -161,12 +178,143 @@ async fn main() {
     }
 
     let log = get_root_logger();
-    let run_info = parse_args();
+    let cli = parse_args();
+
+    let mf_config = &cli.mf_config;
+    // NOTE resolved configuration (config file defaults and command line)
+    info!(
+        log, "Configuration";
+        "config_file"=>mf_config
+            .path
+            .as_ref()
+            .map(|path| path.display().to_string())
+            .unwrap_or_else(|| "none".to_string()),
+        "python_plugin_directories"=>format!("{:?}", mf_config.python_plugin_directories),
+        "data_directories"=>format!("{:?}", mf_config.data_directories),
+        "config_defaults"=>format!("{:?}", mf_config.defaults()),
+    );
+
+    match cli.command {
+        Command::Detect(ref run_plan) => {
+            init_af();
+            detect(&log, run_plan).await;
+        }
+        Command::InspectTemplates(ref opts) => {
+            init_af();
+            inspect::inspect_templates(opts);
+        }
+        Command::PruneTemplates(ref opts) => {
+            init_af();
+            template_prune::prune_templates(opts);
+        }
+        Command::InspectStars(ref opts) => {
+            // NOTE star models can be plugin predictors
+            python::start_plugins(&mf_config.python_plugin_directories);
+            inspect::inspect_stars(opts);
+            python::stop_plugins();
+        }
+        Command::Convert(ref opts) => {
+            python::start_plugins(&mf_config.python_plugin_directories);
+            convert::convert(opts);
+            python::stop_plugins();
+        }
+    }
 
+    if PROF {
+        PROFILER
+            .lock()
+            .expect("Couldn't lock PROFILER.")
+            .stop()
+            .expect("Couldn't start");
+    }
+}
+
+fn init_af() {
     AF::info();
     AF::set_backend(AF::Backend::OPENCL);
     AF::set_device(0);
+}
+
+/// Runs of the run, live and sweep subcommands (in order).
+async fn detect(log: &slog::Logger, run_plan: &RunPlan) {
+    // NOTE Ctrl-C can only be handled once per process, the handler shuts
+    //      down the current run (and no further runs are started)
+    let cur_info_handler: Arc<Mutex<Option<Arc<InformationHandler>>>> =
+        Arc::new(Mutex::new(None));
+    {
+        let cur_info_handler = cur_info_handler.clone();
+        ctrlc::set_handler(move || {
+            if CC_COUNT.load(Ordering::Relaxed) == 0 {
+                if PROF {
+                    PROFILER
+                        .lock()
+                        .expect("Couldn't lock profiler.")
+                        .stop()
+                        .expect("Couldn't start");
+                }
+
+                if MAIN_SHUTDOWN.load(Ordering::Relaxed) {
+                    std::process::exit(-1);
+                }
+
+                if let Some(ref info_handler) =
+                    *cur_info_handler.lock().expect("Info handler lock poisoned.")
+                {
+                    info_handler.trigger_shutdown();
+                }
+                CC_COUNT.store(1, Ordering::Relaxed);
+            } else {
+                std::process::exit(-1);
+            }
+        })
+        .expect("Issue setting Ctrl-C handler.");
+    }
+
+    // NOTE the runs of a sweep share their stars and templates
+    let mut data_cache = DataCache::default();
+    let mut summaries = Vec::new();
+    for run in run_plan.runs.iter() {
+        if CC_COUNT.load(Ordering::Relaxed) > 0 {
+            break;
+        }
 
+        let run_info = match run_plan.experiment {
+            Some(_) => run_plan.cached_run_info(run, &mut data_cache),
+            None => run_plan.run_info(run),
+        };
+        summaries.push(run_detector(run, run_info, &cur_info_handler).await);
+    }
+    drop(data_cache);
+
+    // NOTE plugin processes exit once the last star model lets go of them
+    python::stop_plugins();
+
+    // NOTE recap of a run file matrix (each run also logs its own stats)
+    if summaries.len() > 1 {
+        for summary in summaries.iter() {
+            info!(log, "{}", "Run Summary".on_green();
+                  "run"=>&summary.label,
+                  "num_events_detected"=>summary.true_events+summary.false_events,
+                  "num_true_events"=>summary.true_events,
+                  "num_false_events"=>summary.false_events,
+                  "num_stars"=>summary.num_stars);
+        }
+    }
+
+    if let Some(ref experiment) = run_plan.experiment {
+        experiment::write_csv(&experiment.output, &summaries);
+        info!(log, "Experiment results written";
+              "output"=>&experiment.output,
+              "runs"=>summaries.len());
+    }
+}
+
+/// Runs the detector over the stars (or live data) of a single run.
+async fn run_detector(
+    run: &Run,
+    run_info: RunInfo,
+    cur_info_handler: &Mutex<Option<Arc<InformationHandler>>>,
+) -> RunSummary {
     let RunInfo {
         stars,
         templates,
@@ -176,14 +324,15 @@ async fn main() {
         log_opts,
         tester,
         detector_trigger,
+        veto,
+        results_db,
     } = run_info;
+    let log = get_root_logger().new(o!("run"=>run.label.clone()));
+    let start = Instant::now();
 
-    let mut stars = Lock::new(stars);
+    let tot_stars = stars.len();
 
-    let stars_t = stars.lock().await;
-    let tot_stars = stars_t.len();
-
-    let max_len: Option<usize> = stars_t
+    let max_len: Option<usize> = stars
         .iter()
         .filter_map(|sw| sw.star.samples.as_ref())
         .map(|samps| samps.len())
@@ -191,7 +340,7 @@ async fn main() {
 
     let tot_iter: Option<usize> = if !gwac_reader.is_some() {
         Some(
-            stars_t
+            stars
                 .iter()
                 .filter_map(|sw| sw.star.samples.as_ref())
                 .map(|samps| samps.len())
@@ -201,88 +350,104 @@ async fn main() {
         None
     };
 
-    drop(stars_t);
-
     info!(
         log, "";
         "window_length"=>format!("{:?}", detector_opts.window_length),
+        "window_scales"=>format!("{:?}", detector_opts.window_scales),
+        "residual"=>detector_opts.residual,
         "total_iters_needed"=>tot_iter,
     );
 
+    info!(
+        log, "Options";
+        "detector_opts"=>format!("{:?}", detector_opts),
+        "log_opts"=>format!("{:?}", log_opts),
+    );
+
     let is_offline = !gwac_reader.is_some();
     let info_handler = Arc::new(InformationHandler::new(is_offline, tot_iter));
-    let (tick_barrier_main, tick_barrier_tick) = twin_barrier();
-    let (comp_barrier_main, comp_barrier_tick) = twin_barrier();
+
+    // NOTE do not store original data if plot is off (for memory space reasons)
+    let original_data: HashMap<String, Vec<f32>> = if log_opts.plot {
+        stars
+            .iter()
+            .filter_map(|sw| {
+                sw.star
+                    .samples
+                    .as_ref()
+                    .map(|samps| (sw.star.uid.clone(), samps.clone()))
+            })
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    // NOTE the ticker can be at most max_lag ticks ahead of the detector
+    let (snapshot_tx, snapshot_rx) = channel(detector_opts.max_lag);
+    // NOTE the detector reports scores the ticker schedules stars by
+    let scheduler = Arc::new(FilterScheduler::new(&detector_opts));
     {
-        let stars = stars.clone();
         let info_handler = info_handler.clone();
         let detector_opts = detector_opts.clone();
+        let scheduler = scheduler.clone();
         std::thread::spawn(move || {
             let run_state = RunState {
                 stars,
-                tick_end: tick_barrier_tick,
-                computation_end: comp_barrier_tick,
+                snapshot_tx,
                 info_handler,
                 gwac_reader,
                 detector_opts,
+                scheduler,
             };
 
             tick_driver(run_state);
         });
     }
 
-    {
-        let info_handler = info_handler.clone();
-        // TODO double cc should cause quit
-        ctrlc::set_handler(move || {
-            if CC_COUNT.load(Ordering::Relaxed) == 0 {
-                if PROF {
-                    PROFILER
-                        .lock()
-                        .expect("Couldn't lock profiler.")
-                        .stop()
-                        .expect("Couldn't start");
-                }
-
-                if MAIN_SHUTDOWN.load(Ordering::Relaxed) {
-                    std::process::exit(-1);
-                }
-
-                info_handler.trigger_shutdown();
-                CC_COUNT.store(1, Ordering::Relaxed);
-            } else {
-                std::process::exit(-1);
-            }
-        })
-        .expect("Issue setting Ctrl-C handler.");
-    }
+    *cur_info_handler.lock().expect("Info handler lock poisoned.") =
+        Some(info_handler.clone());
+    MAIN_SHUTDOWN.store(false, Ordering::Relaxed);
+
+    let results = results_db.map(|results_db| {
+        info!(log, "Writing results back into the star database";
+              "db"=>&results_db,
+              "write_results"=>log_opts.write_results.to_string());
+        ResultsWriter::new(
+            &results_db,
+            &run.label,
+            &format!("{:?}", detector_opts),
+            log_opts.write_results == WriteResults::EventsAndScores,
+        )
+    });
 
     let mut detector = {
-        let stars = stars.clone();
         //let into_handler = info_handler.clone();
         let detector_opts = detector_opts.clone();
 
         Detector::new(
-            tick_barrier_main,
-            comp_barrier_main,
+            snapshot_rx,
             info_handler,
-            stars,
+            original_data,
             templates,
             tester,
             //Box::new(DU::ThresholdTrigger::new()),
             //Box::new(DU::ThreeInARowTrigger::new(detector_opts.skip_delta as usize)),
             detector_trigger,
+            veto,
+            scheduler,
             detector_opts,
             log_opts.plot,
+            results,
         )
     };
 
     let (data, data2, adps, true_events, false_events) = detector.run().await;
+    let run_secs = start.elapsed().as_secs_f64();
 
     // so ctrl-c handler knows to shutdown on first or second ctrl-c
     MAIN_SHUTDOWN.store(true, Ordering::Relaxed);
 
-    compute_and_disp_stats(&data, &adps[..]);
+    compute_and_disp_stats(&log, &data, &adps[..]);
 
     info!(log, "{}", "Run Stats".on_green();
           "num_events_detected"=>true_events+false_events,
@@ -355,37 +520,24 @@ async fn main() {
         }
     }
 
-    if PROF {
-        PROFILER
-            .lock()
-            .expect("Couldn't lock PROFILER.")
-            .stop()
-            .expect("Couldn't start");
+    RunSummary {
+        label: run.label.clone(),
+        params: run.params.clone(),
+        num_stars: tot_stars,
+        true_events,
+        false_events,
+        adps,
+        run_secs,
     }
 }
 
-fn compute_and_disp_stats(data: &HashMap<String, Vec<f32>>, adps: &[f32]) {
-    let log = get_root_logger();
-
-    let stats = |data: &[f32]| {
-        let mut avg = 0.0;
-        let mut min = std::f32::INFINITY;
-        let mut max = std::f32::NEG_INFINITY;
-        let mut std_dev = 0.0;
-        let len = data.len() as f32;
-
-        for &datum in data {
-            avg += datum;
-            min = if min < datum { min } else { datum };
-            max = if max > datum { max } else { datum };
-            std_dev += datum * datum;
-        }
-
-        avg /= len;
-        std_dev = (std_dev / len - avg * avg).sqrt();
+fn compute_and_disp_stats(
+    log: &slog::Logger,
+    data: &HashMap<String, Vec<f32>>,
+    adps: &[f32],
+) {
 
-        (min, max, avg, std_dev)
-    };
+    let stats = experiment::stats;
 
     {
         let (min, max, avg, std_dev) = stats(&adps);
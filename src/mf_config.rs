@@ -1,17 +1,322 @@
 use serde_derive::Deserialize;
+use std::collections::HashMap;
+use std::env;
 use std::fs;
+use std::path::{Path, PathBuf};
 
-#[derive(Debug, Deserialize)]
+const CONFIG_FILE: &str = "mf_config.toml";
+
+/// Options of `DetectorOpts` that can be defaulted in the `[detector]` table,
+/// as (config key, command line argument).
+///
+/// NOTE config keys are the long command line flags with `_` for `-`
+pub const DETECTOR_KEYS: &[(&str, &str)] = &[
+    ("rho", "rho"),
+    ("noise", "noise"),
+    ("window_length", "window_length"),
+    ("min_window_length", "min_window_length"),
+    ("max_window_length", "max_window_length"),
+    ("window_scales", "window_scales"),
+    ("fragment", "fragment"),
+    ("skip_delta", "skip_delta"),
+    ("alert_threshold", "alert_threshold"),
+    ("window_func", "window_function"),
+    ("dc_norm", "dc_norm"),
+    ("star_group_sz", "star_group_sz"),
+    ("cpu_workers", "cpu_workers"),
+    ("alert_classes", "alert_classes"),
+    ("buffer_policy", "buffer_policy"),
+    ("max_queued_frames", "max_queued_frames"),
+    ("lag_warn_frames", "lag_warn_frames"),
+    ("missing_frames", "missing_frames"),
+    ("evict_after", "evict_after"),
+    ("cadence_mismatch", "cadence_mismatch"),
+    ("max_lag", "max_lag"),
+    ("watch_level", "watch_level"),
+    ("watch_variability", "watch_variability"),
+    ("filter_budget", "filter_budget"),
+    ("residual", "residual"),
+];
+
+/// Options of `LogOpts` that can be defaulted in the `[log]` table.
+pub const LOG_KEYS: &[(&str, &str)] = &[
+    ("sort", "sort"),
+    ("plot", "plot"),
+    ("write_results", "write_results"),
+];
+
+/// Star sources and the selection of their stars (run files only).
+pub const INPUT_KEYS: &[(&str, &str)] = &[
+    ("input", "input_dir"),
+    ("gwac_file", "gwac_file"),
+    ("where", "where"),
+    ("limit", "limit"),
+    ("ids", "ids"),
+    ("ids_file", "ids_file"),
+    ("sample", "sample"),
+    ("seed", "seed"),
+];
+
+/// Template bank (run files only).
+pub const TEMPLATE_KEYS: &[(&str, &str)] = &[
+    ("templates_file", "templates_file"),
+    ("template_group_sz", "template_group_sz"),
+    ("template_basis_energy", "template_basis_energy"),
+];
+
+/// Detector trigger and veto (run files only).
+pub const TRIGGER_KEYS: &[(&str, &str)] = &[
+    ("detector_trigger", "detector_trigger"),
+    ("trigger_k", "trigger_k"),
+    ("trigger_n", "trigger_n"),
+    ("disarm_threshold", "disarm_threshold"),
+    ("trigger_cooldown", "trigger_cooldown"),
+    ("curve_history", "curve_history"),
+    ("curve_min_rise", "curve_min_rise"),
+    ("curve_max_step", "curve_max_step"),
+    ("veto", "veto"),
+    ("veto_spike_sigma", "veto_spike_sigma"),
+    ("veto_flare_sigma", "veto_flare_sigma"),
+    ("veto_flare_step", "veto_flare_step"),
+];
+
+/// Tester (run files only).
+pub const TESTER_KEYS: &[(&str, &str)] = &[
+    ("tartan_test", "tartan_test"),
+    ("tartan_test_file", "tartan_test_file"),
+];
+
+/// Arguments a default does not apply to when a conflicting argument
+/// is set by a higher priority source (ex. the command line).
+const ARG_CONFLICTS: &[(&str, &[&str])] = &[
+    ("input_dir", &["gwac_file"]),
+    ("gwac_file", &["input_dir"]),
+    ("window_length", &["min_window_length", "max_window_length"]),
+    ("min_window_length", &["window_length"]),
+    ("max_window_length", &["window_length"]),
+];
+
+/// Argument values (as strings, like on the command line) by argument name.
+pub type ArgDefaults = HashMap<&'static str, Vec<String>>;
+
+pub fn arg_conflicts(arg: &str) -> &'static [&'static str] {
+    ARG_CONFLICTS
+        .iter()
+        .find(|(name, _)| *name == arg)
+        .map(|(_, conflicts)| *conflicts)
+        .unwrap_or(&[])
+}
+
+/// Argument defaults of a config/run file table.
+pub fn table_defaults(
+    file: &str,
+    table_name: &str,
+    table: &HashMap<String, toml::Value>,
+    keys: &[(&str, &'static str)],
+) -> ArgDefaults {
+    table
+        .iter()
+        .map(|(key, value)| {
+            let arg = match keys.iter().find(|&&(k, _)| k == key.as_str()) {
+                Some(&(_, arg)) => arg,
+                None => panic!(
+                    "Unknown key {} in the [{}] table of {}, expected one of: {}",
+                    key,
+                    table_name,
+                    file,
+                    keys.iter().map(|(k, _)| *k).collect::<Vec<&str>>().join(", ")
+                ),
+            };
+            (arg, value_strings(key, value))
+        })
+        .collect()
+}
+
+/// Global configuration (mf_config.toml), ex.
+///
+/// ```toml
+/// python_plugin_directories = ["plugins"]
+/// data_directories = ["/data/gwac"]
+///
+/// [detector]
+/// skip_delta = 10
+/// dc_norm = "MeanRemoveTemplateAndStar"
+/// alert_classes = ["Microlensing", "Nova"]
+///
+/// [log]
+/// plot = false
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
 pub struct MFConfig {
-    pub python_plugin_directories: Vec<String>;
-    pub data_directories: Vec<String>;
+    #[serde(default)]
+    pub python_plugin_directories: Vec<String>,
+    /// searched for templates/input files not found relative to the CWD
+    #[serde(default)]
+    pub data_directories: Vec<String>,
+    #[serde(default)]
+    detector: HashMap<String, toml::Value>,
+    #[serde(default)]
+    log: HashMap<String, toml::Value>,
+    /// file the configuration was read from (None when no file was found)
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// command line argument defaults of the detector and log tables
+    #[serde(skip)]
+    defaults: ArgDefaults,
 }
 
-pub load_mfconfig() -> MFConfig {
-    let config_path = "./mf_config.toml";
+impl MFConfig {
+    /// Default (as strings, like on the command line) of an argument.
+    pub fn default_of(&self, arg: &str) -> Option<&[String]> {
+        self.defaults.get(arg).map(|vals| vals.as_slice())
+    }
+
+    pub fn has_default(&self, arg: &str) -> bool {
+        self.defaults.contains_key(arg)
+    }
+
+    /// Arguments defaulted by the configuration (sorted by name).
+    pub fn defaults(&self) -> Vec<(&str, &[String])> {
+        let mut defaults: Vec<(&str, &[String])> = self
+            .defaults
+            .iter()
+            .map(|(arg, vals)| (*arg, vals.as_slice()))
+            .collect();
+        defaults.sort();
+        defaults
+    }
+
+    /// Finds a data file/directory, relative paths that do not exist
+    /// are looked up in the data directories (in order).
+    pub fn find_data_path(&self, path: &str) -> String {
+        if Path::new(path).exists() || Path::new(path).is_absolute() {
+            return path.to_string();
+        }
+
+        self.data_directories
+            .iter()
+            .map(|dir| Path::new(dir).join(path))
+            .find(|path| path.exists())
+            .map(|path| {
+                path.to_str()
+                    .expect("Problem converting data path to string")
+                    .to_string()
+            })
+            .unwrap_or_else(|| path.to_string())
+    }
+
+    pub fn arg_defaults(&self) -> &ArgDefaults {
+        &self.defaults
+    }
+
+    fn resolve_defaults(&mut self) {
+        let file = CONFIG_FILE;
+        self.defaults = table_defaults(file, "detector", &self.detector, DETECTOR_KEYS);
+        self.defaults
+            .extend(table_defaults(file, "log", &self.log, LOG_KEYS));
+    }
+}
+
+/// Config values as command line values (arrays give multiple values).
+pub fn value_strings(key: &str, value: &toml::Value) -> Vec<String> {
+    match value {
+        toml::Value::String(s) => vec![s.clone()],
+        toml::Value::Integer(i) => vec![i.to_string()],
+        toml::Value::Float(f) => vec![f.to_string()],
+        toml::Value::Boolean(b) => vec![b.to_string()],
+        toml::Value::Array(vals) => vals
+            .iter()
+            .flat_map(|val| value_strings(key, val))
+            .collect(),
+        _ => panic!("Unsupported value for {} in mf_config.toml", key),
+    }
+}
+
+/// Configuration files in search order: CWD and then
+/// $XDG_CONFIG_HOME/match_filter (~/.config/match_filter).
+fn search_paths() -> Vec<PathBuf> {
+    let mut paths = vec![PathBuf::from(".").join(CONFIG_FILE)];
+
+    let config_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => Some(PathBuf::from(dir)),
+        _ => env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")),
+    };
+    if let Some(config_home) = config_home {
+        paths.push(config_home.join("match_filter").join(CONFIG_FILE));
+    }
+
+    paths
+}
+
+/// Loads the given configuration file or else the first one found in
+/// the search paths (defaults if there are none).
+pub fn load_mfconfig(config_path: Option<&str>) -> MFConfig {
+    let path = match config_path {
+        Some(path) => {
+            if !Path::new(path).is_file() {
+                panic!("Config file {} does not exist", path);
+            }
+            Some(PathBuf::from(path))
+        }
+        None => search_paths().into_iter().find(|path| path.is_file()),
+    };
+
+    let mut config = match path {
+        Some(ref path) => {
+            let contents = fs::read_to_string(path).unwrap_or_else(|_| {
+                panic!("Failed to read config file {}", path.display())
+            });
+            parse_mfconfig(&contents)
+                .unwrap_or_else(|err| panic!("Failed to parse config file {}: {}", path.display(), err))
+        }
+        None => MFConfig::default(),
+    };
+    config.path = path;
+    config.resolve_defaults();
+
+    config
+}
+
+fn parse_mfconfig(contents: &str) -> Result<MFConfig, toml::de::Error> {
+    toml::from_str(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(contents: &str) -> MFConfig {
+        let mut config = parse_mfconfig(contents).unwrap();
+        config.resolve_defaults();
+        config
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = config(
+            "data_directories = [\"data\"]\n\
+             [detector]\n\
+             skip_delta = 10\n\
+             window_func = \"nuttall\"\n\
+             alert_threshold = 0.5\n\
+             alert_classes = [\"Flare\", \"Nova\"]\n\
+             [log]\n\
+             plot = false\n",
+        );
 
-    let contents =
-        fs::read_to_string(&config_path).expect("Failed to read mf_config.toml");
+        assert_eq!(config.data_directories, vec!["data".to_string()]);
+        assert!(config.python_plugin_directories.is_empty());
+        assert_eq!(config.default_of("skip_delta"), Some(&["10".to_string()][..]));
+        assert_eq!(config.default_of("window_function"), Some(&["nuttall".to_string()][..]));
+        assert_eq!(config.default_of("alert_threshold"), Some(&["0.5".to_string()][..]));
+        assert_eq!(config.default_of("alert_classes").map(|vals| vals.len()), Some(2));
+        assert_eq!(config.default_of("plot"), Some(&["false".to_string()][..]));
+        assert!(!config.has_default("rho"));
+    }
 
-    toml::from_str(&contents).expect("Failed to parse mf_config TOML")
+    #[test]
+    #[should_panic]
+    fn test_unknown_key() {
+        config("[detector]\nskip_deltaa = 10\n");
+    }
 }
@@ -1,5 +1,614 @@
 /*
-* This is a start to rewrite my python plugin system as one
-* that relies on RPC. Hopefully this executes quickly. We may
-* also try different communication mediums TCP/zeromq/etc.
+* Star model predictors running out of process (python plugins)
+* behind the Cap'n Proto Predictor interface (protos/predictor.capnp).
+*
+* Every plugin directory gets its own host process (python/cpc.py)
+* talking over a unix socket. A supervisor thread per plugin owns the
+* process and its RPC connection, restarts the plugin when it dies and
+* replays the predictor inits so the uids handed out stay valid.
 */
+use crate::log;
+use crate::star::{PluginPredictor, StarModel, StarModelErr, StarModelInitErrMsg};
+
+use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+use crossbeam_channel as channel;
+use futures::Future;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command};
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio01::io::AsyncRead;
+use tokio01::runtime::current_thread::Runtime;
+
+pub mod predictor_capnp {
+    include!(concat!(env!("OUT_DIR"), "/predictor_capnp.rs"));
+}
+
+use predictor_capnp::predictor;
+
+const PLUGIN_HOST_SCRIPT: &str = "python/cpc.py";
+// NOTE the plugin binds its socket after importing its plugins (can be slow)
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+const CONNECT_POLL: Duration = Duration::from_millis(100);
+const MIN_RESTART_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_RESTART_BACKOFF: Duration = Duration::from_secs(60);
+// predictor uids not handed out by a plugin (yet)
+const NO_UID: u32 = std::u32::MAX;
+
+lazy_static! {
+    static ref PLUGIN_HOST: RwLock<Option<Arc<PluginHost>>> = RwLock::new(None);
+}
+
+/// Starts a plugin host process for every plugin directory.
+pub fn start_plugins(plugin_dirs: &[String]) {
+    if plugin_dirs.is_empty() {
+        return;
+    }
+
+    let host = PluginHost::new(plugin_dirs);
+    *PLUGIN_HOST.write().expect("Plugin host lock poisoned.") = Some(Arc::new(host));
+}
+
+/// Stops the plugin processes (once the stars using them are gone).
+pub fn stop_plugins() {
+    PLUGIN_HOST.write().expect("Plugin host lock poisoned.").take();
+}
+
+pub fn plugin_host() -> Option<Arc<PluginHost>> {
+    PLUGIN_HOST.read().expect("Plugin host lock poisoned.").clone()
+}
+
+/// One prediction of a plugin predictor.
+pub struct PredictRequest {
+    pub predictor: PluginPredictor,
+    pub look_backs: Vec<Vec<f32>>,
+    pub times: Vec<f32>,
+}
+
+enum PluginCall {
+    Init {
+        predictor: String,
+        args: HashMap<String, String>,
+        reply: channel::Sender<Result<u32, String>>,
+    },
+    // NOTE all requests are sent before waiting on any response
+    //      so a batch is a single round trip
+    Predict {
+        reqs: Vec<(u32, Vec<Vec<f32>>, Vec<f32>)>,
+        reply: channel::Sender<Result<Vec<f32>, String>>,
+    },
+}
+
+struct Plugin {
+    dir: String,
+    calls: channel::Sender<PluginCall>,
+}
+
+pub struct PluginHost {
+    plugins: Vec<Plugin>,
+}
+
+impl PluginHost {
+    fn new(plugin_dirs: &[String]) -> PluginHost {
+        let plugins = plugin_dirs
+            .iter()
+            .enumerate()
+            .map(|(i, dir)| {
+                let (calls, calls_rx) = channel::unbounded();
+                let socket = std::env::temp_dir()
+                    .join(format!("match_filter_{}_plugin_{}", std::process::id(), i));
+                let supervisor = PluginSupervisor {
+                    dir: dir.clone(),
+                    socket,
+                    inits: Vec::new(),
+                };
+
+                std::thread::spawn(move || supervisor.run(calls_rx));
+
+                Plugin {
+                    dir: dir.clone(),
+                    calls,
+                }
+            })
+            .collect();
+
+        PluginHost { plugins }
+    }
+
+    /// Builds the predictor in the first plugin (directory) that knows it.
+    pub fn init(&self, predictor: &str, args: HashMap<String, String>) -> Result<PluginPredictor, String> {
+        let log = log::get_root_logger();
+        let mut errs = Vec::new();
+
+        for (plugin_idx, plugin) in self.plugins.iter().enumerate() {
+            let (reply, reply_rx) = channel::bounded(1);
+            let call = PluginCall::Init {
+                predictor: predictor.to_string(),
+                args: args.clone(),
+                reply,
+            };
+            if plugin.calls.send(call).is_err() {
+                errs.push(format!("{}: plugin stopped", plugin.dir));
+                continue;
+            }
+
+            match reply_rx.recv() {
+                Ok(Ok(uid)) => {
+                    debug!(log, "Plugin predictor initialized.";
+                           "predictor"=>predictor,
+                           "plugin"=>&plugin.dir,
+                           "uid"=>uid);
+                    return Ok(PluginPredictor { plugin: plugin_idx, uid });
+                }
+                Ok(Err(err)) => errs.push(format!("{}: {}", plugin.dir, err)),
+                Err(_) => errs.push(format!("{}: plugin stopped", plugin.dir)),
+            }
+        }
+
+        Err(format!("No plugin could build predictor {} ({})", predictor, errs.join(", ")))
+    }
+
+    /// Predictions of many (star) predictors, batched per plugin.
+    ///
+    /// The prediction of a request is NaN if its plugin failed.
+    pub fn predict_batch(&self, reqs: Vec<PredictRequest>) -> Vec<f32> {
+        let log = log::get_root_logger();
+        let mut preds = vec![std::f32::NAN; reqs.len()];
+
+        let mut per_plugin: Vec<(Vec<usize>, Vec<(u32, Vec<Vec<f32>>, Vec<f32>)>)> =
+            (0..self.plugins.len()).map(|_| (Vec::new(), Vec::new())).collect();
+        for (i, req) in reqs.into_iter().enumerate() {
+            let (idxs, plugin_reqs) = &mut per_plugin[req.predictor.plugin];
+            idxs.push(i);
+            plugin_reqs.push((req.predictor.uid, req.look_backs, req.times));
+        }
+
+        // NOTE send to every plugin first so the plugins predict in parallel
+        let replies = per_plugin
+            .into_iter()
+            .enumerate()
+            .filter(|(_, (idxs, _))| !idxs.is_empty())
+            .map(|(plugin_idx, (idxs, plugin_reqs))| {
+                let (reply, reply_rx) = channel::bounded(1);
+                let _ = self.plugins[plugin_idx].calls.send(PluginCall::Predict {
+                    reqs: plugin_reqs,
+                    reply,
+                });
+                (plugin_idx, idxs, reply_rx)
+            })
+            .collect::<Vec<_>>();
+
+        for (plugin_idx, idxs, reply_rx) in replies {
+            match reply_rx.recv() {
+                Ok(Ok(plugin_preds)) => {
+                    idxs.into_iter()
+                        .zip(plugin_preds)
+                        .for_each(|(i, pred)| preds[i] = pred);
+                }
+                Ok(Err(err)) => {
+                    warn!(log, "Plugin prediction failed.";
+                          "plugin"=>&self.plugins[plugin_idx].dir,
+                          "predictions"=>idxs.len(),
+                          "err"=>err);
+                }
+                Err(_) => {
+                    warn!(log, "Plugin stopped.";
+                          "plugin"=>&self.plugins[plugin_idx].dir);
+                }
+            }
+        }
+
+        preds
+    }
+}
+
+/// StarModel backed by a plugin predictor.
+///
+/// `model_file` names the predictor, `init` builds it in the plugins.
+pub struct PluginModel {
+    host: Arc<PluginHost>,
+    predictor: String,
+    plugin: AtomicUsize,
+    uid: AtomicU32,
+}
+
+impl PluginModel {
+    pub fn new(host: Arc<PluginHost>, predictor: String) -> PluginModel {
+        PluginModel {
+            host,
+            predictor,
+            plugin: AtomicUsize::new(0),
+            uid: AtomicU32::new(NO_UID),
+        }
+    }
+}
+
+impl StarModel for PluginModel {
+    fn init(&self, args: HashMap<String, String>) -> StarModelErr {
+        match self.host.init(&self.predictor, args) {
+            Ok(PluginPredictor { plugin, uid }) => {
+                self.plugin.store(plugin, Ordering::Relaxed);
+                self.uid.store(uid, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(err) => Err(StarModelInitErrMsg {
+                problem_entry: self.predictor.clone(),
+                err_msg: err,
+            }),
+        }
+    }
+
+    fn predict(&self, look_backs: Vec<Vec<f32>>, times: Vec<f32>) -> Option<f32> {
+        let predictor = self
+            .plugin_predictor()
+            .expect("Plugin model predicting before init.");
+
+        // NOTE a failed plugin predicts NaN
+        Some(self.host.predict_batch(vec![PredictRequest {
+            predictor,
+            look_backs,
+            times,
+        }])[0])
+        .filter(|prediction| !prediction.is_nan())
+    }
+
+    fn plugin_predictor(&self) -> Option<PluginPredictor> {
+        match self.uid.load(Ordering::Relaxed) {
+            NO_UID => None,
+            uid => Some(PluginPredictor {
+                plugin: self.plugin.load(Ordering::Relaxed),
+                uid,
+            }),
+        }
+    }
+}
+
+/// RPC connection to a running plugin host.
+struct Connection {
+    runtime: Runtime,
+    client: predictor::Client,
+}
+
+impl Connection {
+    fn connect(socket: &Path, child: &mut Child) -> Result<Connection, String> {
+        let mut runtime = Runtime::new().map_err(|err| err.to_string())?;
+
+        let start = std::time::Instant::now();
+        let stream = loop {
+            if let Ok(Some(status)) = child.try_wait() {
+                return Err(format!("plugin exited ({})", status));
+            }
+
+            if socket.exists() {
+                match runtime.block_on(tokio01::net::UnixStream::connect(socket)) {
+                    Ok(stream) => break stream,
+                    Err(err) if start.elapsed() > CONNECT_TIMEOUT => return Err(err.to_string()),
+                    Err(_) => (),
+                }
+            } else if start.elapsed() > CONNECT_TIMEOUT {
+                return Err("timed out waiting for the plugin socket".to_string());
+            }
+
+            std::thread::sleep(CONNECT_POLL);
+        };
+
+        let (reader, writer) = stream.split();
+        let network = Box::new(twoparty::VatNetwork::new(
+            reader,
+            std::io::BufWriter::new(writer),
+            rpc_twoparty_capnp::Side::Client,
+            Default::default(),
+        ));
+        let mut rpc_system = RpcSystem::new(network, None);
+        let client: predictor::Client = rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+        runtime.spawn(rpc_system.map_err(|_err| ()));
+
+        Ok(Connection { runtime, client })
+    }
+
+    fn init(&mut self, predictor: &str, args: &HashMap<String, String>) -> Result<u32, String> {
+        let mut request = self.client.init_request();
+        {
+            let mut params = request.get();
+            params.set_predictor(predictor);
+            let mut entries = params.init_args().init_entries(args.len() as u32);
+            for (i, (key, val)) in args.iter().enumerate() {
+                let mut entry = entries.reborrow().get(i as u32);
+                entry.set_key(key.as_str()).expect("Problem building plugin init request.");
+                entry.set_val(val.as_str()).expect("Problem building plugin init request.");
+            }
+        }
+
+        self.runtime
+            .block_on(request.send().promise.and_then(|response| Ok(response.get()?.get_uid())))
+            .map_err(|err| err.to_string())
+    }
+
+    fn predict(&mut self, reqs: &[(u32, Vec<Vec<f32>>, Vec<f32>)]) -> Result<Vec<f32>, String> {
+        let promises = reqs
+            .iter()
+            .map(|(uid, look_backs, times)| {
+                let mut request = self.client.predict_request();
+                {
+                    let mut req = request.get().init_req();
+                    req.set_predictor_u_i_d(*uid);
+
+                    let mut lbs = req.reborrow().init_look_backs(look_backs.len() as u32);
+                    for (i, look_back) in look_backs.iter().enumerate() {
+                        let mut lb = lbs.reborrow().init(i as u32, look_back.len() as u32);
+                        for (j, val) in look_back.iter().enumerate() {
+                            lb.set(j as u32, *val);
+                        }
+                    }
+
+                    let mut ts = req.init_times(times.len() as u32);
+                    for (i, time) in times.iter().enumerate() {
+                        ts.set(i as u32, *time);
+                    }
+                }
+
+                // NOTE one prediction per star, the first (next) time step
+                request.send().promise.and_then(|response| {
+                    let preds = response.get()?.get_res()?.get_predictions()?;
+                    Ok(if preds.len() == 0 { std::f32::NAN } else { preds.get(0) })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        self.runtime
+            .block_on(futures::future::join_all(promises))
+            .map_err(|err| err.to_string())
+    }
+}
+
+struct PluginSupervisor {
+    dir: String,
+    socket: PathBuf,
+    // NOTE replayed in order after a restart, the plugin hands out
+    //      uids in init order (of the predictors it built) so the
+    //      uids of the stars stay the same
+    inits: Vec<(String, HashMap<String, String>, u32)>,
+}
+
+impl PluginSupervisor {
+    fn spawn(&self) -> std::io::Result<Child> {
+        Command::new("python3")
+            .arg(PLUGIN_HOST_SCRIPT)
+            .arg(&self.dir)
+            .arg(&self.socket)
+            .spawn()
+    }
+
+    /// Starts (or restarts) the plugin and replays the inits.
+    fn start(&self) -> Result<(Child, Connection), String> {
+        let mut child = self.spawn().map_err(|err| err.to_string())?;
+        let mut conn = match Connection::connect(&self.socket, &mut child) {
+            Ok(conn) => conn,
+            Err(err) => {
+                let _ = child.kill();
+                return Err(err);
+            }
+        };
+
+        if let Err(err) = self.replay_inits(&mut |predictor, args| conn.init(predictor, args)) {
+            let _ = child.kill();
+            return Err(err);
+        }
+
+        Ok((child, conn))
+    }
+
+    /// Builds the predictors again (in order) after a restart, every
+    /// predictor has to get back the uid it had.
+    fn replay_inits(
+        &self,
+        init: &mut dyn FnMut(&str, &HashMap<String, String>) -> Result<u32, String>,
+    ) -> Result<(), String> {
+        for (predictor, args, uid) in self.inits.iter() {
+            match init(predictor, args) {
+                Ok(replayed_uid) if replayed_uid == *uid => (),
+                res => return Err(format!("could not replay init of {} ({:?})", predictor, res)),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn run(mut self, calls: channel::Receiver<PluginCall>) {
+        let log = log::get_root_logger();
+        let mut backoff = MIN_RESTART_BACKOFF;
+        let mut running: Option<(Child, Connection)> = None;
+
+        // NOTE the host is gone once all senders are dropped
+        for call in calls.iter() {
+            if running.is_none() {
+                match self.start() {
+                    Ok(started) => {
+                        info!(log, "Plugin started."; "plugin"=>&self.dir);
+                        backoff = MIN_RESTART_BACKOFF;
+                        running = Some(started);
+                    }
+                    Err(err) => {
+                        warn!(log, "Problem starting plugin.";
+                              "plugin"=>&self.dir,
+                              "err"=>&err,
+                              "retry_in"=>format!("{:?}", backoff));
+                        Self::fail(call, err);
+                        // FIXME calls in the meantime wait on the backoff
+                        std::thread::sleep(backoff);
+                        backoff = std::cmp::min(backoff * 2, MAX_RESTART_BACKOFF);
+                        continue;
+                    }
+                }
+            }
+
+            let (child, conn) = running.as_mut().expect("Plugin started above.");
+            let res_err = match call {
+                PluginCall::Init { predictor, args, reply } => {
+                    let res = conn.init(&predictor, &args);
+                    if let Ok(uid) = res {
+                        self.inits.push((predictor, args, uid));
+                    }
+                    let res_err = res.as_ref().err().cloned();
+                    let _ = reply.send(res);
+                    res_err
+                }
+                PluginCall::Predict { reqs, reply } => {
+                    let res = conn.predict(&reqs);
+                    let res_err = res.as_ref().err().cloned();
+                    let _ = reply.send(res);
+                    res_err
+                }
+            };
+
+            // NOTE an error from a live plugin is the caller's problem
+            //      (ex. unknown predictor), a dead plugin is restarted
+            if let Some(err) = res_err {
+                if let Ok(Some(status)) = child.try_wait() {
+                    warn!(log, "Plugin died, restarting on next call.";
+                          "plugin"=>&self.dir,
+                          "status"=>status.to_string(),
+                          "err"=>err);
+                    running = None;
+                }
+            }
+        }
+
+        if let Some((mut child, conn)) = running {
+            // NOTE closing the connection makes the plugin exit
+            drop(conn);
+            if wait_or_kill(&mut child) {
+                debug!(log, "Plugin stopped."; "plugin"=>&self.dir);
+            }
+        }
+        let _ = std::fs::remove_file(&self.socket);
+    }
+
+    fn fail(call: PluginCall, err: String) {
+        match call {
+            PluginCall::Init { reply, .. } => {
+                let _ = reply.send(Err(err));
+            }
+            PluginCall::Predict { reply, .. } => {
+                let _ = reply.send(Err(err));
+            }
+        }
+    }
+}
+
+/// Gives the plugin a moment to exit on its own, true if it did.
+fn wait_or_kill(child: &mut Child) -> bool {
+    for _ in 0..20 {
+        if let Ok(Some(_)) = child.try_wait() {
+            return true;
+        }
+        std::thread::sleep(CONNECT_POLL);
+    }
+
+    let _ = child.kill();
+    let _ = child.wait();
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NOTE answers predictions with 100 * plugin + uid + first look back
+    //      value, returns the number of calls it got
+    fn fake_plugin(plugin: usize, fails: bool) -> (Plugin, std::thread::JoinHandle<usize>) {
+        let (calls, calls_rx) = channel::unbounded();
+        let handle = std::thread::spawn(move || {
+            let mut num_calls = 0;
+            for call in calls_rx.iter() {
+                num_calls += 1;
+                if let PluginCall::Predict { reqs, reply } = call {
+                    let res = if fails {
+                        Err("plugin died".to_string())
+                    } else {
+                        Ok(reqs
+                            .iter()
+                            .map(|(uid, look_backs, _)| (100 * plugin) as f32 + *uid as f32 + look_backs[0][0])
+                            .collect())
+                    };
+                    let _ = reply.send(res);
+                }
+            }
+
+            num_calls
+        });
+
+        (Plugin { dir: format!("plugin_{}", plugin), calls }, handle)
+    }
+
+    fn predict_request(plugin: usize, uid: u32, val: f32) -> PredictRequest {
+        PredictRequest {
+            predictor: PluginPredictor { plugin, uid },
+            look_backs: vec![vec![val]],
+            times: vec![0.0],
+        }
+    }
+
+    #[test]
+    fn test_predict_batch() {
+        let (plugins, handles): (Vec<Plugin>, Vec<_>) =
+            vec![fake_plugin(0, false), fake_plugin(1, false), fake_plugin(2, true)]
+                .into_iter()
+                .unzip();
+        let host = PluginHost { plugins };
+
+        let preds = host.predict_batch(vec![
+            predict_request(1, 0, 0.5),
+            predict_request(0, 3, 0.25),
+            predict_request(1, 2, 0.0),
+            predict_request(2, 0, 0.0),
+            predict_request(0, 1, 0.0),
+        ]);
+
+        // NOTE predictions come back in request order, NaN for a failed plugin
+        assert_eq!(preds[..3], [100.5, 3.25, 102.0]);
+        assert!(preds[3].is_nan());
+        assert_eq!(preds[4], 1.0);
+
+        // NOTE one call per plugin for the whole batch
+        drop(host);
+        let num_calls = handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .collect::<Vec<usize>>();
+        assert_eq!(num_calls, vec![1, 1, 1]);
+    }
+
+    #[test]
+    fn test_replay_inits() {
+        let args = HashMap::new();
+        let supervisor = PluginSupervisor {
+            dir: "plugins".to_string(),
+            socket: PathBuf::from("plugins.sock"),
+            inits: vec![
+                ("arima".to_string(), args.clone(), 0),
+                ("lstm".to_string(), args.clone(), 1),
+                ("arima".to_string(), args.clone(), 2),
+            ],
+        };
+
+        // NOTE a restarted plugin hands out uids from 0 in init order
+        let mut replayed = Vec::new();
+        let res = supervisor.replay_inits(&mut |predictor, _args| {
+            replayed.push(predictor.to_string());
+            Ok(replayed.len() as u32 - 1)
+        });
+        assert_eq!(res, Ok(()));
+        assert_eq!(replayed, vec!["arima", "lstm", "arima"]);
+
+        // NOTE a plugin that skips a uid (ex. counting a failed build) can not be replayed
+        let mut next_uid = 0;
+        let res = supervisor.replay_inits(&mut |_predictor, _args| {
+            next_uid += 2;
+            Ok(next_uid - 2)
+        });
+        assert!(res.is_err());
+    }
+}
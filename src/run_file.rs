@@ -0,0 +1,272 @@
+use crate::mf_config::{
+    arg_conflicts, table_defaults, value_strings, ArgDefaults, DETECTOR_KEYS,
+    INPUT_KEYS, LOG_KEYS, TEMPLATE_KEYS, TESTER_KEYS, TRIGGER_KEYS,
+};
+use serde_derive::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+
+/// Declarative run (instead of a run_config_*.sh script), ex.
+///
+/// ```toml
+/// label = "gwac_30"
+///
+/// [input]
+/// input = ["data/stars"]
+///
+/// [templates]
+/// templates_file = "data/templates-1800.0d87616.0-0.1d50.0-25x25.toml"
+/// template_group_sz = 256
+///
+/// [detector]
+/// rho = 4.0
+/// noise = 0.06
+/// window_length = 30
+/// skip_delta = 120
+/// fragment = 1
+/// alert_threshold = 100.0
+///
+/// [trigger]
+/// detector_trigger = "KOfNTrigger"
+/// trigger_k = 2
+///
+/// [tester]
+/// tartan_test = false
+///
+/// [log]
+/// plot = false
+///
+/// # every combination is run in sequence (6 runs here)
+/// [matrix]
+/// window_length = [20, 30, 40]
+/// dc_norm = ["None", "MeanRemoveTemplateAndStar"]
+/// ```
+///
+/// NOTE keys are the long command line flags with `_` for `-`, the
+///      command line overrides the run file (except for matrix keys)
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RunFile {
+    label: Option<String>,
+    #[serde(default)]
+    input: HashMap<String, toml::Value>,
+    #[serde(default)]
+    templates: HashMap<String, toml::Value>,
+    #[serde(default)]
+    detector: HashMap<String, toml::Value>,
+    #[serde(default)]
+    trigger: HashMap<String, toml::Value>,
+    #[serde(default)]
+    tester: HashMap<String, toml::Value>,
+    #[serde(default)]
+    log: HashMap<String, toml::Value>,
+    // NOTE sorted so runs are in the same order every time
+    #[serde(default)]
+    matrix: BTreeMap<String, toml::Value>,
+    #[serde(skip)]
+    file: String,
+}
+
+/// A single run of a run file (one matrix combination).
+#[derive(Clone, Debug, Default)]
+pub struct Run {
+    pub label: String,
+    /// matrix keys and values of the run
+    pub params: Vec<(String, String)>,
+    pub args: ArgDefaults,
+}
+
+impl RunFile {
+    pub fn from_file(run_file: &str) -> RunFile {
+        let contents = fs::read_to_string(run_file)
+            .unwrap_or_else(|_| panic!("Failed to read run file: {}", run_file));
+
+        let mut run_file_toml: RunFile = toml::from_str(&contents)
+            .unwrap_or_else(|err| panic!("Failed to parse run file {}: {}", run_file, err));
+        run_file_toml.file = run_file.to_string();
+
+        run_file_toml
+    }
+
+    fn label(&self) -> String {
+        match self.label {
+            Some(ref label) => label.clone(),
+            None => Path::new(&self.file)
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .unwrap_or("run")
+                .to_string(),
+        }
+    }
+
+    fn base_args(&self) -> ArgDefaults {
+        let tables = [
+            ("input", &self.input, INPUT_KEYS),
+            ("templates", &self.templates, TEMPLATE_KEYS),
+            ("detector", &self.detector, DETECTOR_KEYS),
+            ("trigger", &self.trigger, TRIGGER_KEYS),
+            ("tester", &self.tester, TESTER_KEYS),
+            ("log", &self.log, LOG_KEYS),
+        ];
+
+        tables
+            .iter()
+            .flat_map(|&(table_name, table, keys)| {
+                table_defaults(&self.file, table_name, table, keys)
+            })
+            .collect()
+    }
+
+    /// Arguments swept by the matrix.
+    pub fn matrix_args(&self) -> Vec<&'static str> {
+        self.matrix.keys().map(|key| matrix_arg(&self.file, key)).collect()
+    }
+
+    /// Every combination of the matrix values (a single run without a matrix).
+    pub fn runs(&self) -> Vec<Run> {
+        let mut runs = vec![(Vec::new(), self.base_args())];
+
+        for (key, values) in self.matrix.iter() {
+            let arg = matrix_arg(&self.file, key);
+            let values = match values {
+                toml::Value::Array(values) if !values.is_empty() => values,
+                _ => panic!(
+                    "Matrix key {} of run file {} must be a non empty array",
+                    key, self.file
+                ),
+            };
+
+            runs = runs
+                .into_iter()
+                .flat_map(|(params, args)| {
+                    values.iter().map(move |value| {
+                        let vals = value_strings(key, value);
+                        let mut params = params.clone();
+                        params.push((key.clone(), vals.join(",")));
+
+                        // NOTE the swept value replaces base values it conflicts with
+                        let mut args = args.clone();
+                        for conflict in arg_conflicts(arg) {
+                            args.remove(conflict);
+                        }
+                        args.insert(arg, vals);
+
+                        (params, args)
+                    })
+                })
+                .collect();
+        }
+
+        let label = self.label();
+        runs.into_iter()
+            .map(|(params, args)| Run {
+                label: if params.is_empty() {
+                    label.clone()
+                } else {
+                    let params = params
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect::<Vec<String>>();
+                    format!("{} [{}]", label, params.join(" "))
+                },
+                params,
+                args,
+            })
+            .collect()
+    }
+}
+
+fn matrix_arg(file: &str, key: &str) -> &'static str {
+    let tables = [
+        INPUT_KEYS,
+        TEMPLATE_KEYS,
+        DETECTOR_KEYS,
+        TRIGGER_KEYS,
+        TESTER_KEYS,
+        LOG_KEYS,
+    ];
+
+    tables
+        .iter()
+        .flat_map(|keys| keys.iter())
+        .find(|&&(k, _)| k == key)
+        .map(|&(_, arg)| arg)
+        .unwrap_or_else(|| panic!("Unknown matrix key {} in run file {}", key, file))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_file(contents: &str) -> RunFile {
+        let mut run_file: RunFile = toml::from_str(contents).unwrap();
+        run_file.file = "test.toml".to_string();
+        run_file
+    }
+
+    #[test]
+    fn test_single_run() {
+        let runs = run_file(
+            "[templates]\n\
+             templates_file = \"temps.toml\"\n\
+             [detector]\n\
+             window_length = 30\n\
+             window_func = \"Nuttall\"\n",
+        )
+        .runs();
+
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].label, "test");
+        assert_eq!(runs[0].args["templates_file"], vec!["temps.toml".to_string()]);
+        assert_eq!(runs[0].args["window_function"], vec!["Nuttall".to_string()]);
+    }
+
+    #[test]
+    fn test_matrix() {
+        let run_file = run_file(
+            "label = \"sweep\"\n\
+             [detector]\n\
+             window_length = 30\n\
+             skip_delta = 10\n\
+             [matrix]\n\
+             min_window_length = [20, 25]\n\
+             max_window_length = [60]\n\
+             dc_norm = [\"None\", \"MeanRemoveStar\", \"HistMeanRemoveStar\"]\n",
+        );
+        let runs = run_file.runs();
+
+        assert_eq!(
+            run_file.matrix_args(),
+            vec!["dc_norm", "max_window_length", "min_window_length"]
+        );
+        assert_eq!(runs.len(), 6);
+        assert_eq!(
+            runs[0].label,
+            "sweep [dc_norm=None max_window_length=60 min_window_length=20]"
+        );
+        assert_eq!(
+            runs[5].label,
+            "sweep [dc_norm=HistMeanRemoveStar max_window_length=60 min_window_length=25]"
+        );
+        assert_eq!(runs[1].args["min_window_length"], vec!["25".to_string()]);
+        assert_eq!(runs[1].args["max_window_length"], vec!["60".to_string()]);
+        assert_eq!(runs[1].args["skip_delta"], vec!["10".to_string()]);
+        assert_eq!(
+            runs[1].params,
+            vec![
+                ("dc_norm".to_string(), "None".to_string()),
+                ("max_window_length".to_string(), "60".to_string()),
+                ("min_window_length".to_string(), "25".to_string())
+            ]
+        );
+        // NOTE the swept min_/max_window_length replace the fixed window_length
+        assert!(!runs[1].args.contains_key("window_length"));
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_unknown_matrix_key() {
+        run_file("[matrix]\nwindow_lenght = [1, 2]\n").runs();
+    }
+}
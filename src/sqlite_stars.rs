@@ -1,59 +1,720 @@
+//! SQLite star databases (.db inputs and convert outputs).
+//!
+//! Schema version 1 (the `schema_version` table holds the version):
+//!
+//! ```sql
+//! CREATE TABLE stars (
+//!     id TEXT PRIMARY KEY,
+//!     star_type TEXT NOT NULL,        -- constant, variable or unknown
+//!     sample_rate INTEGER NOT NULL,   -- seconds between samples
+//!     metadata TEXT NOT NULL,         -- JSON object, ex. {"source": "data/stars/a.toml"}
+//!     encoding TEXT NOT NULL,         -- of the samples blob: f32le or msgpack
+//!     num_samples INTEGER NOT NULL,
+//!     samples BLOB NOT NULL
+//! );
+//!
+//! -- written back by runs with --write-results
+//! CREATE TABLE runs (id INTEGER PRIMARY KEY, label TEXT, started INTEGER, options TEXT);
+//! CREATE TABLE scores (run_id INTEGER, star_id TEXT, tick INTEGER, score REAL);
+//! CREATE TABLE events (
+//!     run_id INTEGER, star_id TEXT, tick INTEGER, score REAL,
+//!     class TEXT, confidence REAL,
+//!     true_event INTEGER              -- 1/0 with a tester, NULL otherwise
+//! );
+//! ```
+//!
+//! `--where` conditions are plain SQL on the stars columns, ex.
+//! `--where "star_type = 'variable' AND num_samples > 1000"` or
+//! `--where "json_extract(metadata, '$.field') = 'GWAC'"`.
+//!
+//! Legacy databases (a `StarEntry (id, desc, data)` table of star TOML
+//! descriptions and msgpack samples, no `schema_version`) are still read,
+//! `convert -i old.db -o new.db` moves them to the current schema.
+//!
+//! NOTE star models are not stored, database stars have no model
+
+use crate::cli::StarSelection;
+use crate::log;
 use crate::star::{parse_model, Star, StarModelType, StarType};
-use crate::utils;
+use crate::toml_star::StarToml;
 use sqlite;
-use std::{fs, io::Read};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Version of the schema written (and the newest read).
+pub const SCHEMA_VERSION: i64 = 1;
+
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS schema_version (
+        version INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS stars (
+        id TEXT PRIMARY KEY,
+        star_type TEXT NOT NULL,
+        sample_rate INTEGER NOT NULL,
+        metadata TEXT NOT NULL DEFAULT '{}',
+        encoding TEXT NOT NULL,
+        num_samples INTEGER NOT NULL,
+        samples BLOB NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS stars_star_type ON stars (star_type);
+    CREATE INDEX IF NOT EXISTS stars_sample_rate ON stars (sample_rate);
+    CREATE TABLE IF NOT EXISTS runs (
+        id INTEGER PRIMARY KEY,
+        label TEXT NOT NULL,
+        started INTEGER NOT NULL,
+        options TEXT NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS scores (
+        run_id INTEGER NOT NULL REFERENCES runs (id),
+        star_id TEXT NOT NULL,
+        tick INTEGER NOT NULL,
+        score REAL NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS scores_run_star ON scores (run_id, star_id);
+    CREATE TABLE IF NOT EXISTS events (
+        run_id INTEGER NOT NULL REFERENCES runs (id),
+        star_id TEXT NOT NULL,
+        tick INTEGER NOT NULL,
+        score REAL NOT NULL,
+        class TEXT NOT NULL,
+        confidence REAL NOT NULL,
+        true_event INTEGER
+    );
+    CREATE INDEX IF NOT EXISTS events_run_star ON events (run_id, star_id);
+";
+
+/// Little endian f32s, 4 bytes per sample (written by convert).
+pub const ENCODING_F32_LE: &str = "f32le";
+/// A msgpack array of f32s (the legacy StarEntry samples).
+pub const ENCODING_MSGPACK: &str = "msgpack";
+
+const STAR_COLUMNS: &str = "id, star_type, sample_rate, encoding, num_samples, samples";
+
+/// Result rows buffered before they are written back and committed.
+const RESULTS_COMMIT_ROWS: usize = 10000;
+
+fn open(db_file: &str) -> sqlite::Connection {
+    sqlite::open(db_file)
+        .unwrap_or_else(|err| panic!("Failed to open star database {}: {}", db_file, err))
+}
+
+fn has_table(connection: &sqlite::Connection, table: &str) -> bool {
+    let mut statement = connection
+        .prepare("SELECT name FROM sqlite_master WHERE type = 'table' AND name = ?;")
+        .expect("Failed to query the star database tables");
+    statement.bind(1, table).expect("Failed to bind table name");
+
+    statement.next().expect("Failed to query the star database tables") == sqlite::State::Row
+}
+
+/// Schema version of a database, 0 for legacy StarEntry databases and
+/// None for empty ones.
+fn schema_version(connection: &sqlite::Connection, db_file: &str) -> Option<i64> {
+    if has_table(connection, "schema_version") {
+        let mut statement = connection
+            .prepare("SELECT MAX(version) FROM schema_version;")
+            .expect("Failed to query the schema version");
+        statement.next().expect("Failed to query the schema version");
+        let version = statement
+            .read::<i64>(0)
+            .unwrap_or_else(|err| panic!("Star database {} has no schema version: {}", db_file, err));
+
+        Some(version)
+    } else if has_table(connection, "StarEntry") {
+        Some(0)
+    } else {
+        None
+    }
+}
+
+/// Creates the current schema (in an empty database, or the results tables
+/// missing from an older version 1 database), panics for databases of
+/// another schema version.
+fn ensure_schema(connection: &sqlite::Connection, db_file: &str) {
+    match schema_version(connection, db_file) {
+        // NOTE the results tables were added to version 1 after the stars,
+        //      the schema only creates what is missing
+        Some(SCHEMA_VERSION) => connection.execute(SCHEMA).unwrap_or_else(|err| {
+            panic!("Failed to update the star database schema in {}: {}", db_file, err)
+        }),
+        None => connection
+            .execute(format!(
+                "BEGIN; {} INSERT INTO schema_version (version) VALUES ({}); COMMIT;",
+                SCHEMA, SCHEMA_VERSION
+            ))
+            .unwrap_or_else(|err| {
+                panic!("Failed to create the star database schema in {}: {}", db_file, err)
+            }),
+        Some(0) => panic!(
+            "Star database {} uses the legacy StarEntry table, convert it into a new database first",
+            db_file
+        ),
+        Some(version) => panic!(
+            "Star database {} has schema version {}, this build writes version {}",
+            db_file, version, SCHEMA_VERSION
+        ),
+    }
+}
 
-#[derive(Debug, Deserialize)]
-pub struct StarToml {
-    pub id: String,
-    pub star_type: String,
-    pub samples: String,
-    pub sample_rate: i32,
-    pub arima_model_file: String,
+/// Samples as a blob of the default encoding (f32le).
+pub fn encode_samples(samples: &[f32]) -> Vec<u8> {
+    samples.iter().flat_map(|sample| sample.to_le_bytes().to_vec()).collect()
+}
+
+pub fn decode_samples(encoding: &str, blob: &[u8]) -> Result<Vec<f32>, String> {
+    match encoding {
+        ENCODING_F32_LE => {
+            if blob.len() % 4 != 0 {
+                return Err(format!("{} byte samples blob is not a whole number of f32s", blob.len()));
+            }
+            Ok(blob
+                .chunks(4)
+                .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+                .collect())
+        }
+        ENCODING_MSGPACK => rmp_serde::from_slice(blob).map_err(|err| err.to_string()),
+        _ => Err(format!("Unknown samples encoding {}", encoding)),
+    }
+}
+
+/// Star type of its name in the database (see `StarType::as_str`).
+fn parse_star_type(star_type: &str) -> Result<StarType, String> {
+    match star_type {
+        "constant" => Ok(StarType::Constant),
+        "variable" => Ok(StarType::Variable),
+        "unknown" => Ok(StarType::Unknown),
+        _ => Err(format!(
+            "Unknown star_type {} (expected constant, variable or unknown)",
+            star_type
+        )),
+    }
 }
 
-pub fn parse_star_files(star_file: &str) -> Vec<Star> {
-    let connection = sqlite::open(star_file).unwrap();
+fn db_star(id: String, star_type: StarType, sample_rate: i32, samples: Vec<f32>, db_file: &str) -> Star {
+    Star {
+        uid: id.clone() + "," + db_file,
+        id,
+        samples: Some(samples),
+        samples_tick_index: std::sync::atomic::AtomicUsize::new(0),
+        position: None,
+        star_type,
+        model_type: StarModelType::None,
+        model: parse_model(StarModelType::None, "".to_string()),
+        sample_rate,
+    }
+}
+
+/// Id of a star of the database from its uid (`id,db_file`).
+fn star_id<'a>(uid: &'a str, db_file: &str) -> &'a str {
+    match uid.strip_suffix(db_file) {
+        Some(id) if id.ends_with(',') => &id[..id.len() - 1],
+        _ => uid,
+    }
+}
+
+/// Query of the stars columns, in id order so selections are reproducible.
+fn select_sql(columns: &str, where_clause: Option<&str>, limit: Option<usize>) -> String {
+    let mut sql = format!("SELECT {} FROM stars", columns);
+    if let Some(where_clause) = where_clause {
+        sql += &format!(" WHERE ({})", where_clause);
+    }
+    sql += " ORDER BY id";
+    if let Some(limit) = limit {
+        sql += &format!(" LIMIT {}", limit);
+    }
+
+    sql + ";"
+}
+
+/// splitmix64, NOTE kept here so a seed selects the same stars in every build
+fn next_random(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+/// Sorted indices of a seeded random sample of `k` of `n` items
+/// (all of them when `k >= n`).
+fn sample_indices(n: usize, k: usize, seed: u64) -> Vec<usize> {
+    let mut indices: Vec<usize> = (0..n).collect();
+    let k = k.min(n);
+
+    // NOTE partial Fisher-Yates shuffle, only the first k are drawn
+    let mut state = seed;
+    for i in 0..k {
+        let j = i + (next_random(&mut state) % (n - i) as u64) as usize;
+        indices.swap(i, j);
+    }
+    indices.truncate(k);
+    indices.sort_unstable();
+
+    indices
+}
+
+/// Calls `f` with every selected star of the database (one row at a time).
+///
+/// NOTE a --sample is drawn from each database separately
+pub fn for_each_star(db_file: &str, selection: &StarSelection, f: &mut dyn FnMut(Star)) {
+    let connection = open(db_file);
+
+    match schema_version(&connection, db_file) {
+        Some(SCHEMA_VERSION) => for_each_selected_star(&connection, db_file, selection, f),
+        Some(0) => for_each_legacy_star(&connection, db_file, selection, f),
+        Some(version) => panic!(
+            "Star database {} has schema version {}, this build reads up to version {}",
+            db_file, version, SCHEMA_VERSION
+        ),
+        None => panic!("{} is not a star database (no stars table)", db_file),
+    }
+}
+
+fn read_star(statement: &sqlite::Statement, db_file: &str) -> Star {
+    let id = statement.read::<String>(0).expect("Failed to read star id");
+    let star_type = statement.read::<String>(1).expect("Failed to read star_type");
+    let sample_rate = statement.read::<i64>(2).expect("Failed to read sample_rate");
+    let encoding = statement.read::<String>(3).expect("Failed to read samples encoding");
+    let num_samples = statement.read::<i64>(4).expect("Failed to read num_samples");
+    let blob = statement.read::<Vec<u8>>(5).expect("Failed to read samples");
+
+    let star_type = parse_star_type(&star_type)
+        .unwrap_or_else(|err| panic!("Star {} of {}: {}", id, db_file, err));
+    let samples = decode_samples(&encoding, &blob)
+        .unwrap_or_else(|err| panic!("Star {} of {}: {}", id, db_file, err));
+    if samples.len() as i64 != num_samples {
+        panic!(
+            "Star {} of {} has {} samples, num_samples says {}",
+            id,
+            db_file,
+            samples.len(),
+            num_samples
+        );
+    }
+
+    db_star(id, star_type, sample_rate as i32, samples, db_file)
+}
+
+fn for_each_selected_star(
+    connection: &sqlite::Connection,
+    db_file: &str,
+    selection: &StarSelection,
+    f: &mut dyn FnMut(Star),
+) {
+    let where_clause = selection.where_clause.as_deref();
+    let query_failed = format!("Failed to select stars of {}", db_file);
+
+    // NOTE without an id list or sample the stars are streamed straight
+    //      from the query
+    if selection.ids.is_none() && selection.sample.is_none() {
+        let mut statement = connection
+            .prepare(select_sql(STAR_COLUMNS, where_clause, selection.limit))
+            .expect(&query_failed);
+        while let sqlite::State::Row = statement.next().expect(&query_failed) {
+            f(read_star(&statement, db_file));
+        }
+        return;
+    }
+
+    // NOTE the ids (no samples) are selected from first and then the
+    //      selected stars read one at a time
+    let mut ids = Vec::new();
+    let mut statement = connection
+        .prepare(select_sql("id", where_clause, None))
+        .expect(&query_failed);
+    while let sqlite::State::Row = statement.next().expect(&query_failed) {
+        let id = statement.read::<String>(0).expect("Failed to read star id");
+        if selection.has_id(&id) {
+            ids.push(id);
+        }
+    }
+
+    if let Some(sample) = selection.sample {
+        ids = sample_indices(ids.len(), sample, selection.seed)
+            .into_iter()
+            .map(|i| ids[i].clone())
+            .collect();
+    }
+    if let Some(limit) = selection.limit {
+        ids.truncate(limit);
+    }
 
-    let mut res = Vec::new();
     let mut statement = connection
-        .prepare("SELECT * from StarEntry;")
-        .unwrap();
+        .prepare(format!("SELECT {} FROM stars WHERE id = ?;", STAR_COLUMNS))
+        .expect(&query_failed);
+    for id in ids.iter() {
+        statement.reset().expect(&query_failed);
+        statement.bind(1, &id[..]).expect(&query_failed);
+        if let sqlite::State::Row = statement.next().expect(&query_failed) {
+            f(read_star(&statement, db_file));
+        }
+    }
+}
 
-    while let sqlite::State::Row = statement.next().unwrap() {
-        let id = statement.read::<i64>(0).unwrap();
-        let desc = statement.read::<String>(1).unwrap();
-        let data = statement.read::<Vec<u8>>(2).unwrap();
+/// Stars of a legacy StarEntry database, only id lists and limits select.
+fn for_each_legacy_star(
+    connection: &sqlite::Connection,
+    db_file: &str,
+    selection: &StarSelection,
+    f: &mut dyn FnMut(Star),
+) {
+    if selection.where_clause.is_some() || selection.sample.is_some() {
+        panic!(
+            "Star database {} uses the legacy StarEntry table, --where and --sample need a converted database",
+            db_file
+        );
+    }
 
-        let star_toml: StarToml =
-            toml::from_str(&desc).expect(&format!("Failed to parse Star TOML file: {}", star_file));
+    let mut statement = connection
+        .prepare("SELECT desc, data FROM StarEntry ORDER BY id;")
+        .unwrap_or_else(|err| panic!("Failed to select stars of {}: {}", db_file, err));
 
-        let star_type = match star_toml.star_type.as_ref() {
-            "constant" => StarType::Constant,
-            "variable" => StarType::Variable,
-            _ => StarType::Constant,
-        };
+    let mut num_stars = 0;
+    while let sqlite::State::Row = statement
+        .next()
+        .unwrap_or_else(|err| panic!("Failed to read stars of {}: {}", db_file, err))
+    {
+        if selection.limit.map_or(false, |limit| num_stars >= limit) {
+            break;
+        }
 
-        let samples = {
-            let mut de = rmp_serde::Deserializer::new(&data[..]);
+        let desc = statement.read::<String>(0).expect("Failed to read StarEntry desc");
+        // NOTE the desc of a StarEntry row is a star file
+        let star_toml: StarToml = toml::from_str(&desc)
+            .unwrap_or_else(|err| panic!("Failed to parse Star TOML of {}: {}", db_file, err));
+        if !selection.has_id(&star_toml.id) {
+            continue;
+        }
 
-            serde::Deserialize::deserialize(&mut de)
-                .expect("Failed to deserialize Star samples file")
-        };
+        let data = statement.read::<Vec<u8>>(1).expect("Failed to read StarEntry data");
+        let star_type = parse_star_type(&star_toml.star_type)
+            .unwrap_or_else(|err| panic!("Star {} of {}: {}", star_toml.id, db_file, err));
+        let samples = decode_samples(ENCODING_MSGPACK, &data)
+            .unwrap_or_else(|err| panic!("Star {} of {}: {}", star_toml.id, db_file, err));
 
-        res.push(
-            Star {
-                id: star_toml.id.clone(),
-                uid: star_toml.id + "," + &star_file.to_string(),
-                samples: Some(samples),
-                samples_tick_index: std::cell::RefCell::new(0),
-                star_type,
-                model_type: StarModelType::None,
-                model: parse_model(StarModelType::None, "".to_string()),
-                sample_rate: star_toml.sample_rate,
+        f(db_star(star_toml.id, star_type, star_toml.sample_rate, samples, db_file));
+        num_stars += 1;
+    }
+}
+
+/// Writes stars into a (new or current schema) star database, all in one
+/// transaction committed by `finish`.
+///
+/// NOTE the insert is prepared once, it borrows the connection (see `open_stars`)
+pub struct StarWriter<'c> {
+    connection: &'c sqlite::Connection,
+    insert: sqlite::Statement<'c>,
+    db_file: String,
+}
+
+/// Star database to write stars into with a `StarWriter`.
+pub fn open_stars(db_file: &str) -> sqlite::Connection {
+    let connection = open(db_file);
+    ensure_schema(&connection, db_file);
+
+    connection
+}
+
+impl<'c> StarWriter<'c> {
+    pub fn new(connection: &'c sqlite::Connection, db_file: &str) -> StarWriter<'c> {
+        connection.execute("BEGIN;").expect("Failed to begin writing stars");
+        let insert = connection
+            .prepare(
+                "INSERT INTO stars (id, star_type, sample_rate, metadata, encoding, num_samples, samples)
+                 VALUES (?, ?, ?, ?, ?, ?, ?);",
+            )
+            .expect("Failed to prepare star insert");
+
+        StarWriter {
+            connection,
+            insert,
+            db_file: db_file.to_string(),
+        }
+    }
+
+    pub fn write(&mut self, star: &Star) {
+        let samples = star.samples.as_ref().expect("Star has no samples to write");
+        let metadata = serde_json::json!({ "source": star.uid }).to_string();
+
+        let statement = &mut self.insert;
+        statement.reset().expect("Failed to reset star insert");
+        statement.bind(1, &star.id[..]).expect("Failed to bind star id");
+        statement.bind(2, star.star_type.as_str()).expect("Failed to bind star_type");
+        statement.bind(3, star.sample_rate as i64).expect("Failed to bind sample_rate");
+        statement.bind(4, &metadata[..]).expect("Failed to bind metadata");
+        statement.bind(5, ENCODING_F32_LE).expect("Failed to bind encoding");
+        statement.bind(6, samples.len() as i64).expect("Failed to bind num_samples");
+        statement.bind(7, &encode_samples(samples)[..]).expect("Failed to bind samples");
+        statement.next().unwrap_or_else(|err| {
+            panic!("Failed to insert star {} into {} (ids are unique): {}", star.id, self.db_file, err)
+        });
+    }
+
+    pub fn finish(self) {
+        drop(self.insert);
+        self.connection.execute("COMMIT;").expect("Failed to commit stars");
+    }
+}
+
+/// Writes the scores and events of a run back into the star database the
+/// stars came from (a runs row per run), see --write-results.
+///
+/// NOTE the results are buffered and written (and committed) every
+///      RESULTS_COMMIT_ROWS rows, the rest when the writer is dropped so
+///      a run that panics or stops early still keeps its results
+pub struct ResultsWriter {
+    connection: sqlite::Connection,
+    db_file: String,
+    run_id: i64,
+    write_scores: bool,
+    scores: Vec<ScoreRow>,
+    events: Vec<EventRow>,
+}
+
+struct ScoreRow {
+    star_id: String,
+    tick: i64,
+    score: f64,
+}
+
+struct EventRow {
+    star_id: String,
+    tick: i64,
+    score: f64,
+    class: String,
+    confidence: f64,
+    true_event: Option<bool>,
+}
+
+impl ResultsWriter {
+    pub fn new(db_file: &str, label: &str, options: &str, write_scores: bool) -> ResultsWriter {
+        let connection = open(db_file);
+        ensure_schema(&connection, db_file);
+
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since| since.as_secs() as i64)
+            .unwrap_or(0);
+        let mut statement = connection
+            .prepare("INSERT INTO runs (label, started, options) VALUES (?, ?, ?);")
+            .expect("Failed to prepare run insert");
+        statement.bind(1, label).expect("Failed to bind run label");
+        statement.bind(2, started).expect("Failed to bind run start");
+        statement.bind(3, options).expect("Failed to bind run options");
+        statement
+            .next()
+            .unwrap_or_else(|err| panic!("Failed to insert run into {}: {}", db_file, err));
+        drop(statement);
+
+        let mut statement = connection
+            .prepare("SELECT last_insert_rowid();")
+            .expect("Failed to query run id");
+        statement.next().expect("Failed to query run id");
+        let run_id = statement.read::<i64>(0).expect("Failed to read run id");
+        drop(statement);
+
+        ResultsWriter {
+            connection,
+            db_file: db_file.to_string(),
+            run_id,
+            write_scores,
+            scores: Vec::new(),
+            events: Vec::new(),
+        }
+    }
+
+    /// Best score of a star at a tick (only with EventsAndScores).
+    pub fn score(&mut self, star: &str, tick: usize, score: f32) {
+        if !self.write_scores {
+            return;
+        }
+
+        self.scores.push(ScoreRow {
+            star_id: star_id(star, &self.db_file).to_string(),
+            tick: tick as i64,
+            score: score as f64,
+        });
+        self.row_written();
+    }
+
+    /// Alerted event, `true_event` is the tester's verdict (None without one).
+    pub fn event(
+        &mut self,
+        star: &str,
+        tick: usize,
+        score: f32,
+        class: &str,
+        confidence: f32,
+        true_event: Option<bool>,
+    ) {
+        self.events.push(EventRow {
+            star_id: star_id(star, &self.db_file).to_string(),
+            tick: tick as i64,
+            score: score as f64,
+            class: class.to_string(),
+            confidence: confidence as f64,
+            true_event,
+        });
+        self.row_written();
+    }
+
+    fn row_written(&mut self) {
+        if self.scores.len() + self.events.len() >= RESULTS_COMMIT_ROWS {
+            self.write_rows()
+                .unwrap_or_else(|err| panic!("Failed to write results into {}: {}", self.db_file, err));
+        }
+    }
+
+    /// Writes (and commits) the buffered rows, every insert statement is
+    /// prepared once and reused for all of its rows.
+    fn write_rows(&mut self) -> sqlite::Result<()> {
+        let scores = std::mem::take(&mut self.scores);
+        let events = std::mem::take(&mut self.events);
+        if scores.is_empty() && events.is_empty() {
+            return Ok(());
+        }
+
+        self.connection.execute("BEGIN;")?;
+        match insert_results(&self.connection, self.run_id, &scores, &events) {
+            Ok(()) => self.connection.execute("COMMIT;"),
+            Err(err) => {
+                // NOTE drop the partial batch, the insert error is the one reported
+                let _ = self.connection.execute("ROLLBACK;");
+                Err(err)
             }
-        )
+        }
+    }
+}
+
+impl Drop for ResultsWriter {
+    fn drop(&mut self) {
+        // NOTE no panic here, the writer may be dropped while unwinding
+        if let Err(err) = self.write_rows() {
+            let log = log::get_root_logger();
+            error!(log, "Failed to write results.";
+                   "db"=>&self.db_file,
+                   "err"=>err.to_string());
+        }
+    }
+}
+
+fn insert_results(
+    connection: &sqlite::Connection,
+    run_id: i64,
+    scores: &[ScoreRow],
+    events: &[EventRow],
+) -> sqlite::Result<()> {
+    let mut statement = connection
+        .prepare("INSERT INTO scores (run_id, star_id, tick, score) VALUES (?, ?, ?, ?);")?;
+    for row in scores.iter() {
+        statement.reset()?;
+        statement.bind(1, run_id)?;
+        statement.bind(2, &row.star_id[..])?;
+        statement.bind(3, row.tick)?;
+        statement.bind(4, row.score)?;
+        statement.next()?;
     }
 
-    res
+    let mut statement = connection.prepare(
+        "INSERT INTO events (run_id, star_id, tick, score, class, confidence, true_event)
+         VALUES (?, ?, ?, ?, ?, ?, ?);",
+    )?;
+    for row in events.iter() {
+        statement.reset()?;
+        statement.bind(1, run_id)?;
+        statement.bind(2, &row.star_id[..])?;
+        statement.bind(3, row.tick)?;
+        statement.bind(4, row.score)?;
+        statement.bind(5, &row.class[..])?;
+        statement.bind(6, row.confidence)?;
+        match row.true_event {
+            Some(true_event) => statement.bind(7, true_event as i64)?,
+            None => statement.bind(7, ())?,
+        }
+        statement.next()?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_samples_encoding() {
+        let samples = vec![1.5, -2.25, 0.0, 1e-3];
+
+        let blob = encode_samples(&samples);
+        assert_eq!(blob.len(), 16);
+        assert_eq!(decode_samples(ENCODING_F32_LE, &blob).unwrap(), samples);
+
+        let blob = rmp_serde::to_vec(&samples).unwrap();
+        assert_eq!(decode_samples(ENCODING_MSGPACK, &blob).unwrap(), samples);
+
+        assert!(decode_samples(ENCODING_F32_LE, &[0, 0, 0]).is_err());
+        assert!(decode_samples("f64be", &[]).is_err());
+    }
+
+    #[test]
+    fn test_star_type() {
+        assert_eq!(parse_star_type("variable").unwrap().as_str(), "variable");
+        assert_eq!(parse_star_type("unknown").unwrap().as_str(), "unknown");
+        assert!(parse_star_type("Variable").is_err());
+    }
+
+    #[test]
+    fn test_sample_indices() {
+        let sample = sample_indices(100, 10, 42);
+        assert_eq!(sample.len(), 10);
+        assert!(sample.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(sample.iter().all(|&i| i < 100));
+
+        // NOTE the same seed selects the same stars
+        assert_eq!(sample, sample_indices(100, 10, 42));
+        assert_ne!(sample, sample_indices(100, 10, 43));
+
+        assert_eq!(sample_indices(3, 10, 0), vec![0, 1, 2]);
+        assert!(sample_indices(0, 10, 0).is_empty());
+    }
+
+    #[test]
+    fn test_select_sql() {
+        assert_eq!(select_sql("id", None, None), "SELECT id FROM stars ORDER BY id;");
+        assert_eq!(
+            select_sql("id, samples", Some("sample_rate = 15 OR num_samples > 10"), Some(5)),
+            "SELECT id, samples FROM stars WHERE (sample_rate = 15 OR num_samples > 10) ORDER BY id LIMIT 5;"
+        );
+    }
+
+    #[test]
+    fn test_star_id() {
+        assert_eq!(star_id("ref_1,data/stars.db", "data/stars.db"), "ref_1");
+        assert_eq!(star_id("a,b,data/stars.db", "data/stars.db"), "a,b");
+        assert_eq!(star_id("data/stars/ref_1.toml", "data/stars.db"), "data/stars/ref_1.toml");
+    }
+
+    #[test]
+    fn test_results_written_on_drop() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_file = dir.path().join("stars.db");
+        let db_file = db_file.to_str().unwrap();
+
+        let mut results = ResultsWriter::new(db_file, "test", "", true);
+        results.score(&format!("ref_1,{}", db_file), 10, 2.5);
+        results.score(&format!("ref_1,{}", db_file), 11, 3.5);
+        results.event(&format!("ref_1,{}", db_file), 11, 3.5, "Flare", 0.75, None);
+        drop(results);
+
+        let connection = open(db_file);
+        let count = |table: &str| {
+            let mut statement = connection
+                .prepare(format!("SELECT COUNT(*) FROM {} WHERE star_id = 'ref_1';", table))
+                .unwrap();
+            statement.next().unwrap();
+            statement.read::<i64>(0).unwrap()
+        };
+        assert_eq!(count("scores"), 2);
+        assert_eq!(count("events"), 1);
+    }
 }
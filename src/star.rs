@@ -1,34 +1,89 @@
-use std::cell::RefCell;
+use crate::arima::ArimaModel;
+use crate::python::{self, PluginModel};
+use std::sync::atomic::AtomicUsize;
+use std::sync::Arc;
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 pub enum StarType {
     Constant,
     Variable,
     Unknown,
 }
 
-#[derive(Debug)]
+impl StarType {
+    /// Name of the star type in star files.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StarType::Constant => "constant",
+            StarType::Variable => "variable",
+            StarType::Unknown => "unknown",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
 pub enum StarModelType {
     //Lstm,
-    //Arima,
+    // native ARIMA baseline (see arima.rs)
+    Arima,
+    // predictor of a python plugin (see python.rs)
+    Plugin,
     None,
 }
 
+/// Where a (live) star is on the sky and on the ccd, kept for alerting.
+#[derive(Clone, Copy, Debug)]
+pub struct StarPosition {
+    pub ra: f32,
+    pub dec: f32,
+    pub xpix: f32,
+    pub ypix: f32,
+}
+
 pub struct Star {
     pub id: String,
     pub uid: String,
     pub star_type: StarType,
     pub model_type: StarModelType,
-    pub model: Box<dyn StarModel + Send>,
+    // NOTE shared by the clones of a star
+    pub model: Arc<dyn StarModel + Send + Sync>,
     pub sample_rate: i32,
     // Used to run on offline data
     pub samples: Option<Vec<f32>>,
-    pub samples_tick_index: RefCell<usize>,
+    pub samples_tick_index: AtomicUsize,
+    // Latest position reported by the live data (None for offline data)
+    pub position: Option<StarPosition>,
 }
 
+// NOTE a clone starts over at the first sample, so an offline
+//      star can be run again (see cli::DataCache)
+impl Clone for Star {
+    fn clone(&self) -> Star {
+        Star {
+            id: self.id.clone(),
+            uid: self.uid.clone(),
+            star_type: self.star_type,
+            model_type: self.model_type,
+            model: self.model.clone(),
+            sample_rate: self.sample_rate,
+            samples: self.samples.clone(),
+            samples_tick_index: AtomicUsize::new(0),
+            position: self.position,
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct StarModelInitErrMsg {
-    _problem_entry: String,
-    _err_msg: String,
+    pub problem_entry: String,
+    pub err_msg: String,
+}
+
+/// Predictor of a plugin process (index into the plugins and uid within the plugin).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PluginPredictor {
+    pub plugin: usize,
+    pub uid: u32,
 }
 
 pub type StarModelErr = Result<(), StarModelInitErrMsg>;
@@ -37,7 +92,18 @@ pub trait StarModel {
         &self,
         args: std::collections::HashMap<String, String>,
     ) -> StarModelErr;
-    fn predict(&self, look_backs: Vec<Vec<f32>>, times: Vec<f32>) -> f32;
+    /// Prediction of the next sample, None if the model can not predict it
+    fn predict(&self, look_backs: Vec<Vec<f32>>, times: Vec<f32>) -> Option<f32>;
+    /// Plugin predictor behind the model, lets predictions of many
+    /// stars be batched (see python::PluginHost::predict_batch)
+    fn plugin_predictor(&self) -> Option<PluginPredictor> {
+        None
+    }
+    /// Expected (baseline) value of every sample of the window, the matched
+    /// filter runs on the residual in residual mode (None filters the window)
+    fn baseline(&self, _window: &[f32]) -> Option<Vec<f32>> {
+        None
+    }
 }
 
 #[derive(Debug)]
@@ -50,17 +116,26 @@ impl StarModel for NoneModel {
     ) -> StarModelErr {
         Ok(())
     }
-    fn predict(&self, _look_backs: Vec<Vec<f32>>, _times: Vec<f32>) -> f32 {
-        0.0
+    fn predict(&self, _look_backs: Vec<Vec<f32>>, _times: Vec<f32>) -> Option<f32> {
+        None
     }
 }
 
 // [ ] TODO implement model functionality
+// NOTE for plugin models mfile is the name of the predictor
+//      for ARIMA models the model file
 pub fn parse_model(
     mtype: StarModelType,
-    _mfile: String,
-) -> Box<dyn StarModel + Send> {
+    mfile: String,
+) -> Arc<dyn StarModel + Send + Sync> {
     match mtype {
-        StarModelType::None => Box::new(NoneModel {}),
+        StarModelType::Plugin => {
+            let host = python::plugin_host().expect(
+                "Star uses a plugin model but no python_plugin_directories are configured.",
+            );
+            Arc::new(PluginModel::new(host, mfile))
+        }
+        StarModelType::Arima => Arc::new(ArimaModel::from_file(&mfile)),
+        StarModelType::None => Arc::new(NoneModel {}),
     }
 }
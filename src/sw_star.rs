@@ -1,19 +1,86 @@
+use crate::cyclic_queue::{ContiguousCyclicQueue, CyclicQueueInterface};
 use crate::star::Star;
-use std::cell::RefCell;
+use std::ops::Deref;
+use std::sync::{Mutex, MutexGuard};
 
+arg_enum! {
+    /// What a live star does for a frame it is missing from.
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    pub enum MissingFramePolicy {
+        // repeat the last sample
+        Hold,
+        // push a NaN, the star is not filtered until it leaves the window
+        NaN,
+        // throw away the window and start filling it again
+        Reset,
+    }
+}
+
+/// Window lengths spread evenly from `min` to `max` (a single scale is `max`).
+///
+/// Stars with fewer samples than a scale are filtered at what they have,
+/// so one scale keeps the old grow until `max` behaviour.
+///
+/// NOTE `num` is checked to be at least 1 when parsing --window-scales
+pub fn window_scales(min: usize, max: usize, num: usize) -> Vec<usize> {
+    if num <= 1 || min == max {
+        return vec![max];
+    }
+
+    let mut scales = (0..num)
+        .map(|i| min + (max - min) * i / (num - 1))
+        .collect::<Vec<usize>>();
+    scales.dedup();
+
+    scales
+}
+
+/// Sliding window state, behind the lock of its star.
+struct WindowState {
+    buffer: ContiguousCyclicQueue<f32>,
+    // These are for keeping track of iterations between matched filtering
+    // ex. once every X iterations
+    //available_pos: u32, // starting at X iteration (for initialization)
+    available_count: u32, // have X left
+    // watched stars are filtered every tick (see FilterScheduler)
+    watched: bool,
+    // ticks spent ready without being filtered (over the filter budget)
+    overdue: u32,
+}
+
+/// The current window of a star, borrowed straight from its ring buffer.
+///
+/// Holds the lock of the star, so ticking it waits until the view is dropped.
+pub struct WindowView<'a> {
+    state: MutexGuard<'a, WindowState>,
+    len: usize,
+}
+
+impl<'a> WindowView<'a> {
+    /// The newest `len` samples of the window (all of them if it is shorter).
+    pub fn scale(&self, len: usize) -> &[f32] {
+        self.state.buffer.last_n(len.min(self.len))
+    }
+}
+
+impl<'a> Deref for WindowView<'a> {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        self.state.buffer.last_n(self.len)
+    }
+}
+
+// NOTE each star has its own lock so the ticker and the detector
+//      never have to take a lock over all the stars
 pub struct SWStar {
     pub star: Star,
-    buffer: RefCell<Vec<f32>>,
+    state: Mutex<WindowState>,
     _max_buffer_len: u32, // for now unused but potential use in prediction, etc.
     // set these equal to get constant window length
     max_window_len: u32,
     min_window_len: u32,
-    pub cur_window_len: RefCell<u32>,
-    // These are for keeping track of iterations between matched filtering
-    // ex. once every X iterations
-    //available_pos: u32, // starting at X iteration (for initialization)
-    available_count: RefCell<u32>, // have X left
-    available_delta: u32,          // every X
+    available_delta: u32, // every X
 }
 
 impl SWStar {
@@ -21,51 +88,94 @@ impl SWStar {
     pub fn new() -> SWStarBuilder {
         Default::default()
     }
-    pub fn is_ready(&self) -> bool {
-        let cur_window_len = *self.cur_window_len.borrow();
-        let available_count = *self.available_count.borrow();
+    fn lock(&self) -> MutexGuard<WindowState> {
+        self.state.lock().expect("SWStar lock poisoned.")
+    }
+    fn is_ready_locked(&self, state: &WindowState) -> bool {
+        let cur_window_len = state.buffer.len() as u32;
 
         self.min_window_len <= cur_window_len
             && cur_window_len <= self.max_window_len
-            && available_count == 0
+            && (state.available_count == 0 || state.watched)
     }
-    // TODO look into ways to prevent this copy
-    pub fn window(&self) -> Option<Vec<f32>> {
-        let cur_window_len = *self.cur_window_len.borrow();
-        let buff = self.buffer.borrow();
+    pub fn is_ready(&self) -> bool {
+        self.is_ready_locked(&self.lock())
+    }
+    pub fn cur_window_len(&self) -> u32 {
+        self.lock().buffer.len() as u32
+    }
+    pub fn is_watched(&self) -> bool {
+        self.lock().watched
+    }
+    pub fn set_watched(&self, watched: bool) {
+        self.lock().watched = watched;
+    }
+    pub fn overdue(&self) -> u32 {
+        self.lock().overdue
+    }
+    /// Runs `f` on the newest `len` samples (all of them if there are fewer).
+    pub fn with_recent<R>(&self, len: usize, f: impl FnOnce(&[f32]) -> R) -> R {
+        f(self.lock().buffer.last_n(len))
+    }
+    pub fn window(&self) -> Option<WindowView> {
+        let mut state = self.lock();
 
-        if self.is_ready() {
-            self.available_count.replace(self.available_delta);
+        if self.is_ready_locked(&state) {
+            state.available_count = self.available_delta;
+            state.overdue = 0;
 
-            Some(buff[..cur_window_len as usize].to_vec())
+            // NOTE missing frames (see MissingFramePolicy::NaN) would
+            //      poison the filter output of the whole star group
+            if state.buffer.as_slice().iter().any(|val| val.is_nan()) {
+                return None;
+            }
+
+            let len = state.buffer.len();
+            Some(WindowView { state, len })
         } else {
             None
         }
     }
+    pub fn last_value(&self) -> Option<f32> {
+        self.lock().buffer.get_back().cloned()
+    }
+    // handles a frame the star is missing from
+    pub fn tick_missing(&self, policy: MissingFramePolicy) {
+        match policy {
+            MissingFramePolicy::Hold => {
+                if let Some(val) = self.last_value() {
+                    self.tick(val);
+                }
+            }
+            MissingFramePolicy::NaN => self.tick(std::f32::NAN),
+            MissingFramePolicy::Reset => self.reset(),
+        }
+    }
+    // empties the window, the star is ready again once the window is refilled
+    pub fn reset(&self) {
+        let mut state = self.lock();
+
+        state.buffer.clear();
+        state.available_count = 1;
+        state.overdue = 0;
+    }
     // pushes new data and advances state variables one time point
     pub fn tick(&self, new_data_point: f32) {
-        let mut buff = self.buffer.borrow_mut();
-        let cur_window_len = { *self.cur_window_len.borrow() };
+        let mut state = self.lock();
+        let cur_window_len = state.buffer.len() as u32;
 
-        buff.push(new_data_point);
-        // FIXME for now use max_window_len as buffer length
-        if buff.len() > self.max_window_len as usize {
-            buff.remove(0);
-        } else {
-            self.cur_window_len.replace(cur_window_len + 1);
+        // NOTE the window was ready but not taken (deferred)
+        if self.is_ready_locked(&state) {
+            state.overdue += 1;
         }
 
-        let available_count = { *self.available_count.borrow() };
+        // FIXME for now use max_window_len as buffer length
+        state.buffer.push(new_data_point);
 
         if cur_window_len >= self.min_window_len {
-            self.available_count.replace(available_count - 1);
+            state.available_count = state.available_count.saturating_sub(1);
         }
     }
-    /*
-    fn raw_window() -> Vec<f32> {
-        &self.buffer[..self.cur_window_len as usize]
-    }
-    */
 }
 
 #[derive(Default)]
@@ -101,16 +211,16 @@ impl SWStarBuilder {
         self
     }
     pub fn build(self) -> SWStar {
+        let max_window_len = self
+            .max_window_len
+            .expect("Tried to build a partial SWStar.");
+
         SWStar {
             star: self.star.expect("Tried to build a partial SWStar."),
-            max_window_len: self
-                .max_window_len
-                .expect("Tried to build a partial SWStar."),
+            max_window_len,
             min_window_len: self
                 .min_window_len
                 .expect("Tried to build a partial SWStar."),
-            cur_window_len: RefCell::new(0),
-            buffer: RefCell::new(Vec::new()),
             _max_buffer_len: self
                 .max_buffer_len
                 .expect("Tried to build a partial SWStar."),
@@ -118,15 +228,132 @@ impl SWStarBuilder {
             available_delta: self
                 .available_delta
                 .expect("Tried to build a partial SWStar."),
-            // ex. let 'o' be the start pos and 'x' not do anything
-            //     8 be delta and 8 be min and max window
-            // 1) xxxxxxxoxxxxxxxo -- available_pos = 0
-            // 2) xxxxxxxxoxxxxxxxo -- available_pos = 1
-            available_count: RefCell::new(
-                self.available_pos
+            state: Mutex::new(WindowState {
+                buffer: ContiguousCyclicQueue::new(max_window_len as usize),
+                // ex. let 'o' be the start pos and 'x' not do anything
+                //     8 be delta and 8 be min and max window
+                // 1) xxxxxxxoxxxxxxxo -- available_pos = 0
+                // 2) xxxxxxxxoxxxxxxxo -- available_pos = 1
+                available_count: self
+                    .available_pos
                     .expect("Tried to build a partial SWStar.")
                     + 1,
-            ),
+                watched: false,
+                overdue: 0,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::star::{parse_model, StarModelType, StarType};
+    use std::sync::atomic::AtomicUsize;
+
+    fn sw_star(min: u32, max: u32) -> SWStar {
+        let star = Star {
+            id: "1".to_string(),
+            uid: "1".to_string(),
+            star_type: StarType::Unknown,
+            model_type: StarModelType::None,
+            model: parse_model(StarModelType::None, "".to_string()),
+            sample_rate: 15,
+            samples: None,
+            samples_tick_index: AtomicUsize::new(0),
+            position: None,
+        };
+
+        SWStar::new()
+            .set_star(star)
+            .set_availables(0, 1)
+            .set_window_lens(min, max)
+            .set_max_buffer_len(max)
+            .build()
+    }
+
+    #[test]
+    fn test_window_scales() {
+        assert_eq!(window_scales(20, 20, 3), vec![20]);
+        assert_eq!(window_scales(20, 50, 1), vec![50]);
+        assert_eq!(window_scales(20, 50, 4), vec![20, 30, 40, 50]);
+        assert_eq!(window_scales(2, 3, 4), vec![2, 3]);
+    }
+
+    #[test]
+    fn test_variable_window() {
+        let sw = sw_star(2, 4);
+
+        (0..3).for_each(|i| sw.tick(i as f32));
+        {
+            let window = sw.window().unwrap();
+            assert_eq!(&*window, &[0.0, 1.0, 2.0]);
+            assert_eq!(window.scale(2), &[1.0, 2.0]);
+            assert_eq!(window.scale(4), &[0.0, 1.0, 2.0]);
         }
+
+        // NOTE a full window is the newest samples
+        (3..6).for_each(|i| sw.tick(i as f32));
+        let window = sw.window().unwrap();
+        assert_eq!(&*window, &[2.0, 3.0, 4.0, 5.0]);
+        assert_eq!(window.scale(3), &[3.0, 4.0, 5.0]);
+    }
+
+    #[test]
+    fn test_watched_every_tick() {
+        let sw = SWStar::new()
+            .set_star(sw_star(2, 2).star)
+            .set_availables(0, 5)
+            .set_window_lens(2, 2)
+            .set_max_buffer_len(2)
+            .build();
+
+        (0..3).for_each(|i| sw.tick(i as f32));
+        assert!(sw.window().is_some());
+        sw.tick(3.0);
+        assert!(sw.window().is_none());
+
+        sw.set_watched(true);
+        assert!(sw.window().is_some());
+        sw.tick(4.0);
+        assert_eq!(sw.overdue(), 1);
+        assert_eq!(&*sw.window().unwrap(), &[3.0, 4.0]);
+        assert_eq!(sw.overdue(), 0);
+        assert_eq!(sw.with_recent(1, |recent| recent.to_vec()), vec![4.0]);
+    }
+
+    #[test]
+    fn test_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SWStar>();
+    }
+
+    #[test]
+    fn test_window_slides() {
+        let sw = sw_star(3, 3);
+
+        (0..2).for_each(|i| sw.tick(i as f32));
+        assert!(sw.window().is_none());
+
+        (2..5).for_each(|i| sw.tick(i as f32));
+        assert_eq!(&*sw.window().unwrap(), &[2.0, 3.0, 4.0]);
+        assert_eq!(sw.last_value(), Some(4.0));
+    }
+
+    #[test]
+    fn test_missing_frames() {
+        let sw = sw_star(2, 2);
+
+        sw.tick(1.0);
+        sw.tick(2.0);
+        sw.tick_missing(MissingFramePolicy::NaN);
+        assert!(sw.window().is_none());
+
+        sw.reset();
+        assert_eq!(sw.cur_window_len(), 0);
+        sw.tick(3.0);
+        sw.tick_missing(MissingFramePolicy::Hold);
+        sw.tick_missing(MissingFramePolicy::Hold);
+        assert_eq!(&*sw.window().unwrap(), &[3.0, 3.0]);
     }
 }
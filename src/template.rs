@@ -1,9 +1,14 @@
 use crate::cli::DCNorm;
+use crate::detector_utils::EventClass;
+use crate::filter_utils::af_to_vec1d;
+use crate::log;
 use crate::utils;
 
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use std::fs;
 use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
 
 use num::Complex;
 
@@ -11,10 +16,36 @@ use arrayfire as AF;
 use arrayfire::Array as AF_Array;
 use arrayfire::Dim4 as AF_Dim4;
 
-#[derive(Debug, Deserialize)]
+/// Template bank description
+///
+/// `templates` are microlensing templates, other classes of
+/// templates can be added with `[[classes]]` entries, ex.
+///
+/// ```toml
+/// templates = "ml_templates.mpk"
+/// pre_fft = false
+/// sample_rate = 15
+///
+/// [[classes]]
+/// class = "flare"
+/// templates = "flare_templates.mpk"
+/// ```
+#[derive(Debug, Deserialize, Serialize)]
 pub struct TemplateToml {
-    pub templates: String,
+    pub templates: Option<String>,
+    /// templates are stored FFT'd (false for raw time-domain templates)
     pub pre_fft: bool,
+    /// seconds between template samples (the cadence the bank was built for)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sample_rate: Option<i32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub classes: Vec<TemplateClassToml>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct TemplateClassToml {
+    pub class: String,
+    pub templates: String,
 }
 
 pub struct TemplateGroup {
@@ -22,11 +53,32 @@ pub struct TemplateGroup {
     pub num_templates: usize,
     pub max_len: usize,
     pub fft_len: usize,
+    /// index into `Templates::classes`
+    pub class_idx: usize,
+    /// SVD-reduced basis filtered against instead of the templates (see
+    /// `Templates::reduce_to_basis`)
+    pub basis: Option<TemplateBasis>,
+}
+
+/// Reduced basis of a template group, `templates ~= basis * coeffs`.
+///
+/// The stars are projected on the (few) basis vectors and the
+/// per-template scores are reconstructed from the projections.
+pub struct TemplateBasis {
+    /// left singular vectors (rows x rank)
+    pub basis: AF_Array<Complex<f32>>,
+    /// singular values times the right singular vectors (rank x num_templates)
+    pub coeffs: AF_Array<Complex<f32>>,
+    pub rank: usize,
 }
 
 pub struct Templates {
     pub templates: Vec<TemplateGroup>,
     pub pre_fft: bool,
+    /// every class has at least one template group
+    pub classes: Vec<EventClass>,
+    /// seconds between template samples (None if the bank does not say)
+    pub sample_rate: Option<i32>,
 }
 
 arg_enum! {
@@ -39,30 +91,161 @@ arg_enum! {
     }
 }
 
-pub fn parse_template_file(file_name: String, template_group_sz: usize,
-                           dc_norm: DCNorm, template_norm: TemplateNorm) -> Templates {
+fn read_templates(file_name: &str, templates_file: &str) -> Vec<Vec<f32>> {
+    let toml_templates_file = utils::normalize_local_data_paths(&file_name,
+                                                                &templates_file);
+    let mut file = fs::File::open(&toml_templates_file)
+        .expect(&format!("Failed to read Templates templates file {}", toml_templates_file));
+    let mut contents: Vec<u8> = Vec::new();
+    file.read_to_end(&mut contents)
+        .expect("Failed reading contents of templates.");
+
+    let mut de = rmp_serde::Deserializer::new(&contents[..]);
+
+    serde::Deserialize::deserialize(&mut de)
+        .expect("Failed to deserialize templates")
+}
+
+fn read_template_toml(file_name: &str) -> TemplateToml {
     let contents = fs::read_to_string(&file_name)
         .expect("Failed to read Templates TOML file");
 
-    let template_toml: TemplateToml =
-        toml::from_str(&contents).expect("Failed to parse Templates TOML file");
+    toml::from_str(&contents).expect("Failed to parse Templates TOML file")
+}
 
-    let templates: Vec<TemplateGroup> = {
-        let toml_templates_file = utils::normalize_local_data_paths(&file_name,
-                                                                    &template_toml.templates);
-        let mut file = fs::File::open(&toml_templates_file)
-            .expect(&format!("Failed to read Templates templates file {}", toml_templates_file));
-        let mut contents: Vec<u8> = Vec::new();
-        file.read_to_end(&mut contents)
-            .expect("Failed reading contents of templates.");
+/// Reads the cadence (seconds between samples) a bank was built for
+pub fn read_template_sample_rate(file_name: &str) -> Option<i32> {
+    read_template_toml(file_name).sample_rate
+}
+
+/// Reads the templates of every class in a bank
+/// - templates of repeated classes are merged
+pub fn read_template_bank(file_name: &str) -> Vec<(EventClass, Vec<Vec<f32>>)> {
+    let template_toml = read_template_toml(file_name);
+
+    let mut bank: Vec<(EventClass, Vec<Vec<f32>>)> = Vec::new();
+    if let Some(templates_file) = template_toml.templates.as_ref() {
+        bank.push((EventClass::Microlensing, read_templates(file_name, templates_file)));
+    }
+
+    for class_toml in template_toml.classes.iter() {
+        let class = EventClass::from_str(&class_toml.class)
+            .unwrap_or_else(|_| panic!("Unknown template class {}", class_toml.class));
+        let mut templates = read_templates(file_name, &class_toml.templates);
+
+        match bank.iter_mut().find(|(bank_class, _)| *bank_class == class) {
+            Some((_, bank_templates)) => bank_templates.append(&mut templates),
+            None => bank.push((class, templates)),
+        }
+    }
+
+    bank.retain(|(_, templates)| !templates.is_empty());
+    if bank.is_empty() {
+        panic!("Template file {} does not contain any templates", file_name);
+    }
+
+    bank
+}
+
+/// Writes a bank as a template TOML file with a msgpack templates file per
+/// class next to it (`<stem>.mpk` for microlensing, `<stem>-<class>.mpk`).
+pub fn write_template_bank(file_name: &str, bank: &[(EventClass, Vec<Vec<f32>>)],
+                           sample_rate: Option<i32>) {
+    let path = Path::new(file_name);
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .expect("Problem reading template file name");
+
+    // NOTE the templates are written as raw time-domain templates
+    let mut template_toml = TemplateToml {
+        templates: None,
+        pre_fft: false,
+        sample_rate,
+        classes: Vec::new(),
+    };
+
+    for (class, templates) in bank.iter() {
+        let templates_file = match class {
+            EventClass::Microlensing => format!("{}.mpk", stem),
+            _ => format!("{}-{}.mpk", stem, class.to_string().to_lowercase()),
+        };
+
+        let contents = rmp_serde::to_vec(templates).expect("Failed to serialize templates");
+        fs::write(path.with_file_name(&templates_file), contents)
+            .unwrap_or_else(|_| panic!("Failed to write templates file {}", templates_file));
+
+        match class {
+            EventClass::Microlensing => template_toml.templates = Some(templates_file),
+            _ => template_toml.classes.push(TemplateClassToml {
+                class: class.to_string(),
+                templates: templates_file,
+            }),
+        }
+    }
 
-        let mut de = rmp_serde::Deserializer::new(&contents[..]);
+    let contents = toml::to_string(&template_toml).expect("Failed to serialize Templates TOML file");
+    fs::write(path, contents)
+        .unwrap_or_else(|_| panic!("Failed to write Templates TOML file {}", file_name));
+}
+
+/// FFT length of a bank, the longest template rounded up to a power of two.
+pub fn padded_fft_len(max_len: usize) -> usize {
+    (2 as usize).pow((max_len as f32).log2().ceil() as u32)
+}
 
-        let temp: Vec<Vec<f32>> = serde::Deserialize::deserialize(&mut de)
-            .expect("Failed to deserialize templates");
+/// Number of rows of the FFT kept for the templates (the positive frequencies).
+pub fn kept_fft_len(fft_len: usize) -> usize {
+    // using the numpy fftfreq reference
+    // [ ] TODO check if correct
+    // - ie only concerned with pos. freq. in fft
+    // NOTE rows 0 up to and including the last kept row
+    if fft_len % 2 == 1 {
+        // odd
+        (fft_len - 1) / 2 + 1
+    } else {
+        // even
+        fft_len / 2
+    }
+}
 
-        let mut max_len = temp
+/// (class index, number of templates) of every template group, the
+/// templates of a class are split into groups of `template_group_sz`.
+pub fn group_layout(class_sizes: &[usize], template_group_sz: usize) -> Vec<(usize, usize)> {
+    class_sizes
+        .iter()
+        .enumerate()
+        .flat_map(|(class_idx, &class_size)| {
+            (0..class_size)
+                .step_by(template_group_sz)
+                .map(move |start| (class_idx, template_group_sz.min(class_size - start)))
+        })
+        .collect()
+}
+
+pub fn parse_template_file(file_name: String, template_group_sz: usize,
+                           dc_norm: DCNorm, template_norm: TemplateNorm) -> Templates {
+    let bank = read_template_bank(&file_name);
+
+    bank_templates(
+        &bank,
+        read_template_sample_rate(&file_name),
+        template_group_sz,
+        dc_norm,
+        template_norm,
+    )
+}
+
+/// FFT'd and normalized template groups of a bank (see `read_template_bank`).
+pub fn bank_templates(bank: &[(EventClass, Vec<Vec<f32>>)], sample_rate: Option<i32>,
+                      template_group_sz: usize, dc_norm: DCNorm,
+                      template_norm: TemplateNorm) -> Templates {
+    let classes = bank.iter().map(|(class, _)| *class).collect::<Vec<EventClass>>();
+
+    let templates: Vec<TemplateGroup> = {
+        let max_len = bank
             .iter()
+            .flat_map(|(_, templates)| templates.iter())
             .map(|template| template.len())
             .max()
             .expect("Issue getting max template set length.");
@@ -83,23 +266,20 @@ pub fn parse_template_file(file_name: String, template_group_sz: usize,
         // NOTE FOR NOW FIXED IN TEMPLATE GENERATION BY ROUNDING TO NEAREST POWER OF TWO LENGTH
         // - SO WE DO THE SAME HERE
 
-        let max_len = (2 as usize).pow((max_len as f32).log2().ceil() as u32);
+        let max_len = padded_fft_len(max_len);
         //let max_len = 8192;
-        println!("FFT length: {}", max_len);
-
-        // using the numpy fftfreq reference
-        // [ ] TODO check if correct
-        // - ie only concerned with pos. freq. in fft
-        let real_len: usize = if max_len % 2 == 1 {
-            // odd
-            (max_len - 1) / 2
-        } else {
-            // even
-            max_len / 2 - 1
-        };
+        let log = log::get_root_logger();
+        debug!(log, "Template bank FFT length."; "fft_len"=>max_len.to_string());
+
+        // NOTE last kept row (AF::rows is inclusive)
+        let real_len = kept_fft_len(max_len) - 1;
 
-        temp.chunks(template_group_sz)
-            .map(|chunk| {
+        bank.iter()
+            .enumerate()
+            .flat_map(|(class_idx, (_, temp))| {
+                temp.chunks(template_group_sz).map(move |chunk| (class_idx, chunk))
+            })
+            .map(|(class_idx, chunk)| {
                 let chunk_len = chunk.len();
 
                 let mut chunk: Vec<AF_Array<Complex<f32>>> = chunk
@@ -185,13 +365,173 @@ pub fn parse_template_file(file_name: String, template_group_sz: usize,
                     max_len: real_len,
                     fft_len: max_len,
                     num_templates: chunk_len,
+                    class_idx,
+                    basis: None,
                 }
             })
             .collect::<Vec<TemplateGroup>>()
     };
 
+    // NOTE the groups are FFT'd here from the raw templates of the bank
     Templates {
         templates,
-        pre_fft: true,
+        pre_fft: false,
+        classes,
+        sample_rate,
+    }
+}
+
+/// Pairwise match of the templates of a class (in bank order), the
+/// normalized inner product of their spectra as taken by
+/// `filter::inner_product` (1.0 for identical templates).
+///
+/// NOTE templates without power (ex. constant ones after mean removal)
+///      match nothing (0.0)
+pub fn class_matches(templates: &Templates, class_idx: usize) -> Vec<Vec<f32>> {
+    let groups = templates
+        .templates
+        .iter()
+        .filter(|group| group.class_idx == class_idx)
+        .collect::<Vec<&TemplateGroup>>();
+    let num_templates: usize = groups.iter().map(|group| group.num_templates).sum();
+
+    let norms: Vec<f32> = groups
+        .iter()
+        .flat_map(|group| {
+            let power = AF::real(&AF::mul(
+                &group.templates,
+                &AF::conjg(&group.templates),
+                false,
+            ));
+            af_to_vec1d(&AF::sum(&power, 0))
+        })
+        .map(|power| power.sqrt())
+        .collect();
+
+    let mut matches = vec![vec![0.0; num_templates]; num_templates];
+    let mut offset_a = 0;
+    for group_a in groups.iter() {
+        let mut offset_b = 0;
+        for group_b in groups.iter() {
+            let inner = AF::real(&AF::matmul(
+                &group_a.templates,
+                &group_b.templates,
+                AF::MatProp::CTRANS,
+                AF::MatProp::NONE,
+            ));
+            // NOTE column major, a column per template of group_b
+            let inner = af_to_vec1d(&inner);
+
+            for (j, column) in inner.chunks(group_a.num_templates).enumerate() {
+                let b = offset_b + j;
+                for (i, &value) in column.iter().enumerate() {
+                    let a = offset_a + i;
+                    let norm = norms[a] * norms[b];
+                    matches[a][b] = if norm > 0.0 { value / norm } else { 0.0 };
+                }
+            }
+
+            offset_b += group_b.num_templates;
+        }
+        offset_a += group_a.num_templates;
+    }
+
+    matches
+}
+
+
+/// Smallest rank whose singular values (largest first) keep at least
+/// `energy` (0.0 to 1.0) of the total energy.
+pub fn basis_rank(singular_values: &[f32], energy: f32) -> usize {
+    let total: f32 = singular_values.iter().map(|val| val * val).sum();
+
+    let mut kept = 0.0;
+    for (rank, val) in singular_values.iter().enumerate() {
+        kept += val * val;
+        if kept >= energy * total {
+            return rank + 1;
+        }
+    }
+
+    singular_values.len()
+}
+
+/// SVD-reduced basis of a template group keeping `energy` of its energy.
+pub fn group_basis(group: &TemplateGroup, energy: f32) -> TemplateBasis {
+    let (u, s, vt) = AF::svd(&group.templates);
+    let rank = basis_rank(&af_to_vec1d(&s), energy);
+
+    let basis = AF::cols(&u, 0, rank as u64 - 1);
+    let singular_values = AF::rows(&s, 0, rank as u64 - 1).cast::<Complex<f32>>();
+    let coeffs = AF::mul(&AF::rows(&vt, 0, rank as u64 - 1), &singular_values, true);
+
+    TemplateBasis {
+        basis,
+        coeffs,
+        rank,
+    }
+}
+
+impl Templates {
+    /// Filter every template group against an SVD-reduced basis keeping
+    /// `energy` of the group's energy (see `filter::inner_product`).
+    ///
+    /// NOTE pays off when the rank is well below the group size, the
+    ///      scores are approximations for energy < 1.0
+    pub fn reduce_to_basis(mut self, energy: f32) -> Templates {
+        for group in self.templates.iter_mut() {
+            group.basis = Some(group_basis(group, energy));
+        }
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fft_len() {
+        assert_eq!(padded_fft_len(1000), 1024);
+        assert_eq!(padded_fft_len(1024), 1024);
+        assert_eq!(kept_fft_len(1024), 512);
+        assert_eq!(kept_fft_len(1023), 512);
+    }
+
+    #[test]
+    fn test_basis_rank() {
+        let singular_values = vec![3.0, 2.0, 1.0, 0.1];
+
+        assert_eq!(basis_rank(&singular_values, 1.0), 4);
+        assert_eq!(basis_rank(&singular_values, 0.99), 3);
+        assert_eq!(basis_rank(&singular_values, 0.9), 2);
+        assert_eq!(basis_rank(&singular_values, 0.5), 1);
+    }
+
+    #[test]
+    fn test_group_layout() {
+        assert_eq!(
+            group_layout(&[5, 2], 2),
+            vec![(0, 2), (0, 2), (0, 1), (1, 2)]
+        );
+        assert_eq!(group_layout(&[3], 1024), vec![(0, 3)]);
+    }
+
+    #[test]
+    fn test_template_bank_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let file_name = dir.path().join("bank.toml");
+        let file_name = file_name.to_str().unwrap();
+        let bank = vec![
+            (EventClass::Microlensing, vec![vec![1.0, 2.0, 1.0], vec![0.0, 3.0]]),
+            (EventClass::Flare, vec![vec![0.0, 5.0, 2.0, 1.0]]),
+        ];
+
+        write_template_bank(file_name, &bank, Some(15));
+
+        assert!(!read_template_toml(file_name).pre_fft);
+        assert_eq!(read_template_sample_rate(file_name), Some(15));
+        assert_eq!(read_template_bank(file_name), bank);
     }
 }
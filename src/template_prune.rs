@@ -0,0 +1,123 @@
+use crate::cli::{DCNorm, PruneTemplatesOpts};
+use crate::detector_utils::EventClass;
+use crate::template::*;
+
+/// Greedily picks templates until every template matches a picked one by at
+/// least `min_match` (see `class_matches`), the templates covering the most
+/// others are tried first. Returns the picked indices in bank order.
+pub fn greedy_prune(matches: &[Vec<f32>], min_match: f32) -> Vec<usize> {
+    let num_templates = matches.len();
+    let coverage = matches
+        .iter()
+        .map(|row| row.iter().filter(|&&pair_match| pair_match >= min_match).count())
+        .collect::<Vec<usize>>();
+
+    // NOTE stable, ties are tried in bank order
+    let mut order = (0..num_templates).collect::<Vec<usize>>();
+    order.sort_by_key(|&idx| std::cmp::Reverse(coverage[idx]));
+
+    let mut covered = vec![false; num_templates];
+    let mut kept = Vec::new();
+    for idx in order {
+        if covered[idx] {
+            continue;
+        }
+
+        kept.push(idx);
+        for (other, &pair_match) in matches[idx].iter().enumerate() {
+            if pair_match >= min_match {
+                covered[other] = true;
+            }
+        }
+    }
+
+    kept.sort_unstable();
+    kept
+}
+
+/// Prunes every class of a bank to a minimal match of `min_match`.
+///
+/// NOTE the templates are FFT'd and normalized as for a run (see
+///      `bank_templates`) so the match is the one the detector sees
+pub fn prune_bank(
+    bank: &[(EventClass, Vec<Vec<f32>>)],
+    template_group_sz: usize,
+    dc_norm: DCNorm,
+    min_match: f32,
+) -> Vec<(EventClass, Vec<Vec<f32>>)> {
+    let templates = bank_templates(bank, None, template_group_sz, dc_norm, TemplateNorm::None);
+
+    bank.iter()
+        .enumerate()
+        .map(|(class_idx, (class, class_templates))| {
+            let kept = greedy_prune(&class_matches(&templates, class_idx), min_match);
+
+            (*class, kept.into_iter().map(|idx| class_templates[idx].clone()).collect())
+        })
+        .collect()
+}
+
+/// Prunes a template bank and writes the pruned bank (prune-templates).
+pub fn prune_templates(opts: &PruneTemplatesOpts) {
+    let bank = read_template_bank(&opts.templates_file);
+    let sample_rate = read_template_sample_rate(&opts.templates_file);
+
+    let pruned = prune_bank(&bank, opts.template_group_sz, opts.dc_norm, opts.min_match);
+
+    println!(
+        "Pruned {} to a minimal match of {} (dc_norm {})",
+        opts.templates_file, opts.min_match, opts.dc_norm
+    );
+    for ((class, templates), (_, pruned_templates)) in bank.iter().zip(pruned.iter()) {
+        println!("  {}: {} -> {} templates", class, templates.len(), pruned_templates.len());
+    }
+
+    write_template_bank(&opts.output, &pruned, sample_rate);
+    println!("Pruned bank written to {}", opts.output);
+
+    if let Some(energy) = opts.basis_energy {
+        let templates = bank_templates(
+            &pruned,
+            sample_rate,
+            opts.template_group_sz,
+            opts.dc_norm,
+            TemplateNorm::None,
+        );
+
+        println!("SVD-reduced basis keeping {} of the energy (see --template-basis-energy)", energy);
+        for (group_idx, group) in templates.templates.iter().enumerate() {
+            println!(
+                "  group {}: {} {} templates -> rank {}",
+                group_idx,
+                templates.classes[group.class_idx],
+                group.num_templates,
+                group_basis(group, energy).rank
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_greedy_prune() {
+        // NOTE 1 covers 0 and 2, 3 only matches itself
+        let matches = vec![
+            vec![1.0, 0.98, 0.9, 0.1],
+            vec![0.98, 1.0, 0.97, 0.1],
+            vec![0.9, 0.97, 1.0, 0.1],
+            vec![0.1, 0.1, 0.1, 1.0],
+        ];
+
+        assert_eq!(greedy_prune(&matches, 0.97), vec![1, 3]);
+        assert_eq!(greedy_prune(&matches, 0.99), vec![0, 1, 2, 3]);
+
+        // NOTE every template is kept or matched by a kept one
+        let kept = greedy_prune(&matches, 0.95);
+        for row in matches.iter() {
+            assert!(kept.iter().any(|&idx| row[idx] >= 0.95));
+        }
+    }
+}
@@ -1,190 +1,648 @@
-use crate::async_utils::TwinBarrier;
+use crate::async_utils::with_shutdown;
+use crate::cadence::{cadences_match, estimate_cadence, CadenceMismatch, Resampler, GWAC_SAMPLE_RATE};
 use crate::cli::DetectorOpts;
-use crate::gwac_reader::GWACFrame;
+use crate::filter_scheduler::{Candidate, FilterScheduler};
+use crate::frame_queue::{FrameQueue, NextFrame};
+use crate::gwac_reader::GWACMessage;
 use crate::info_handler::InformationHandler;
 use crate::log;
-use crate::star::{parse_model, Star, StarModelType, StarType};
-use crate::sw_star::SWStar;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use crate::python::{self, PredictRequest};
+use crate::star::{parse_model, Star, StarModelType, StarPosition, StarType};
+use crate::sw_star::{MissingFramePolicy, SWStar};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::{mpsc::Receiver, mpsc::Sender, Lock};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tokio_executor::blocking;
 
+/// Number of sample times used to estimate the cadence of a live star.
+const CADENCE_SAMPLES: usize = 5;
+
+/// Bookkeeping of a live star (kept aligned with the star list).
+struct LiveStar {
+    key: (String, String),
+    camera: usize,
+    /// frame (of its camera) the star was last seen in
+    last_seen: usize,
+    /// timestamp of the first sample, later times are relative to it
+    first_time: Option<f64>,
+    /// sample times until the cadence is estimated
+    times: VecDeque<f32>,
+    cadence_checked: bool,
+    /// cadence does not match the templates (star is not ticked)
+    refused: bool,
+    resampler: Option<Resampler>,
+}
+
+impl LiveStar {
+    fn new(key: (String, String), camera: usize, frame_num: usize) -> LiveStar {
+        LiveStar {
+            key,
+            camera,
+            last_seen: frame_num,
+            first_time: None,
+            times: VecDeque::with_capacity(CADENCE_SAMPLES),
+            cadence_checked: false,
+            refused: false,
+            resampler: None,
+        }
+    }
+
+    /// Samples to tick the star with for an observation.
+    ///
+    /// Once enough observations are in, the cadence of the star is
+    /// estimated from the GWAC timestamps and checked against the templates.
+    fn samples(
+        &mut self,
+        sw: &mut SWStar,
+        time: f64,
+        val: f32,
+        template_rate: Option<i32>,
+        policy: CadenceMismatch,
+    ) -> Vec<f32> {
+        // NOTE absolute (epoch) timestamps are past what an f32 resolves,
+        //      the seconds since the first sample are not
+        let time = (time - *self.first_time.get_or_insert(time)) as f32;
+        if !self.cadence_checked {
+            self.times.push_back(time);
+            if self.times.len() >= CADENCE_SAMPLES {
+                self.check_cadence(sw, template_rate, policy);
+            }
+        }
+
+        if self.refused {
+            return Vec::new();
+        }
+
+        match self.resampler.as_mut() {
+            Some(resampler) => resampler.push(time, val),
+            None => vec![val],
+        }
+    }
+
+    fn check_cadence(
+        &mut self,
+        sw: &mut SWStar,
+        template_rate: Option<i32>,
+        policy: CadenceMismatch,
+    ) {
+        let log = log::get_root_logger();
+        let times = self.times.drain(..).collect::<Vec<f32>>();
+        self.cadence_checked = true;
+
+        let cadence = match estimate_cadence(&times) {
+            Some(cadence) => cadence,
+            None => {
+                warn!(log, "Could not estimate cadence of star, timestamps do not advance.";
+                      "star"=>&sw.star.uid);
+                return;
+            }
+        };
+        sw.star.sample_rate = cadence.round() as i32;
+
+        let template_rate = match template_rate {
+            Some(template_rate) if !cadences_match(cadence, template_rate as f32) => template_rate,
+            _ => return,
+        };
+
+        warn!(log, "Star cadence does not match the templates.";
+              "star"=>&sw.star.uid,
+              "cadence"=>cadence,
+              "template_cadence"=>template_rate,
+              "policy"=>policy.to_string());
+
+        // NOTE the window so far is at the wrong cadence
+        sw.reset();
+        match policy {
+            CadenceMismatch::Refuse => self.refused = true,
+            CadenceMismatch::Resample => {
+                self.resampler = Some(Resampler::new(template_rate as f32));
+                sw.star.sample_rate = template_rate;
+            }
+        }
+    }
+}
+
+/// Handles the stars of a camera missing from its latest frame and evicts
+/// the ones not seen for more than `evict_after` frames.
+///
+/// Returns the uids of the evicted stars.
+fn handle_missing_stars(
+    stars: &mut Vec<SWStar>,
+    live_stars: &mut Vec<LiveStar>,
+    name_to_pos: &mut HashMap<(String, String), usize>,
+    camera: usize,
+    frame_num: usize,
+    policy: MissingFramePolicy,
+    evict_after: Option<usize>,
+) -> Vec<String> {
+    let mut evict = Vec::new();
+    for (pos, live) in live_stars.iter().enumerate() {
+        if live.camera != camera || live.last_seen == frame_num {
+            continue;
+        }
+
+        match evict_after {
+            Some(evict_after) if frame_num - live.last_seen > evict_after => evict.push(pos),
+            // NOTE refused stars are only kept around to be evicted
+            _ if live.refused => (),
+            _ => stars[pos].tick_missing(policy),
+        }
+    }
+
+    // NOTE remove from the back so the star swapped into a removed
+    //      position is never one still waiting to be removed
+    let mut evicted = Vec::with_capacity(evict.len());
+    for pos in evict.into_iter().rev() {
+        evicted.push(stars.swap_remove(pos).star.uid);
+        let removed = live_stars.swap_remove(pos);
+        name_to_pos.remove(&removed.key);
+        if pos < live_stars.len() {
+            name_to_pos.insert(live_stars[pos].key.clone(), pos);
+        }
+    }
+
+    evicted
+}
+
+/// Immutable copy of the windows of the stars ready at a tick.
+pub struct WindowSnapshot {
+    /// ticks so far (starting at 1 for the first tick)
+    pub tick: usize,
+    /// one entry per star and window scale (the scales of a star are adjacent)
+    pub names: Vec<String>,
+    pub windows: Vec<Vec<f32>>,
+    pub window_lens: Vec<usize>,
+    pub positions: HashMap<String, StarPosition>,
+    /// next sample predicted by the plugin model of a star (only stars with one)
+    pub predictions: HashMap<String, f32>,
+    /// filtering is skipped to catch up with live data (stars were still ticked)
+    pub skip_filter: bool,
+    /// live stars evicted since the last snapshot (their per star state can go)
+    pub evicted: Vec<String>,
+}
+
+/// Advances the stars and publishes a window snapshot every tick.
+///
+/// The ticker owns the stars so ingestion never waits on the filter,
+/// the bounded snapshot channel limits how far the detector can lag.
 pub struct Ticker {
-    stars: Lock<Vec<SWStar>>,
-    computation_end: TwinBarrier,
-    tick_end: TwinBarrier,
+    stars: Vec<SWStar>,
+    snapshot_tx: Sender<WindowSnapshot>,
     iterations_chan_tx: Sender<usize>,
-    gwac_rx_chan: Option<Receiver<GWACFrame>>,
+    gwac_rx_chan: Option<Receiver<GWACMessage>>,
+    // NOTE indexed by GWACMessage::camera
+    gwac_cameras: Vec<String>,
     detector_opts: DetectorOpts,
-    #[allow(unused)]
-    is_offline: bool,
     info_handler: Arc<InformationHandler>,
+    scheduler: Arc<FilterScheduler>,
 }
 
 impl Ticker {
     pub fn new(
-        computation_end: TwinBarrier,
-        tick_end: TwinBarrier,
-        stars: Lock<Vec<SWStar>>,
-        gwac_rx_chan: Option<Receiver<GWACFrame>>,
+        snapshot_tx: Sender<WindowSnapshot>,
+        stars: Vec<SWStar>,
+        gwac_rx_chan: Option<Receiver<GWACMessage>>,
+        gwac_cameras: Vec<String>,
         detector_opts: DetectorOpts,
         info_handler: Arc<InformationHandler>,
+        scheduler: Arc<FilterScheduler>,
     ) -> Ticker {
         let iterations_chan_tx = info_handler.get_iterations_sender();
-        let is_offline = info_handler.is_offline;
         Ticker {
-            computation_end,
-            tick_end,
             stars,
+            scheduler,
+            snapshot_tx,
             detector_opts,
             gwac_rx_chan,
+            gwac_cameras,
             info_handler,
             iterations_chan_tx,
-            is_offline,
         }
     }
 
-    pub async fn tick(&mut self) {
-        let log = log::get_root_logger();
-        let sd_rx = self.info_handler.get_shutdown_receiver();
-        let mut name_to_pos: HashMap<String, usize> = HashMap::new();
-        loop {
-            match self.computation_end.wait().await {
-                Err(msg) => {
-                    if *sd_rx.get_ref() {
-                        info!(log, "Ticker received finished signal...");
-                        return;
-                    } else {
-                        panic!(msg)
-                    }
+    // NOTE scales longer than the window so far collapse into it
+    fn window_lens(&self, cur_window_len: usize) -> Vec<usize> {
+        let mut lens = self
+            .detector_opts
+            .window_scales
+            .iter()
+            .map(|scale| (*scale).min(cur_window_len))
+            .collect::<Vec<usize>>();
+        lens.dedup();
+
+        lens
+    }
+
+    /// Snapshot of the stars picked this tick (without predictions) and
+    /// the plugin predictions to make for them.
+    fn snapshot(
+        &self,
+        tick: usize,
+        skip_filter: bool,
+        evicted: Vec<String>,
+    ) -> (WindowSnapshot, Vec<String>, Vec<PredictRequest>) {
+        // NOTE watch levels are updated before the stars of the tick are picked
+        let score_watched = self.scheduler.score_watched();
+        let recent_len = self.detector_opts.window_length.0;
+        let candidates = self
+            .stars
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, sw)| {
+                let mut watched = score_watched.contains(&sw.star.uid);
+                if !watched && (sw.is_watched() || sw.is_ready()) {
+                    watched = sw.with_recent(recent_len, |recent| self.scheduler.is_variable(recent));
                 }
-                _ => ()
-            };
-            {
-                let mut stars_l = self.stars.lock().await;
-                let mut iterations = 0;
-
-                if let Some(gwac_rx_chan) = self.gwac_rx_chan.as_mut() {
-                    // NOTE online data handling
-                    let mut in_frame = false;
-                    let mut tot_stars = 0;
-                    loop {
-                        // If sender end closes, then file is done for the night
-                        // thus, we should shutdown the program gracefully
-                        let data = match gwac_rx_chan.recv().await {
-                            Some(val) => val,
-                            None => {
-                                self.info_handler.trigger_shutdown();
-                                return;
-                            }
-                        };
-
-                        match data {
-                            GWACFrame::Start => {
-                                in_frame = true;
-                            }
-                            GWACFrame::End => break,
-                            // NOTE for now do nothing with file name
-                            GWACFrame::Filename(_filename) => continue,
-                            GWACFrame::Star(star) => {
-                                if !in_frame {
-                                    continue;
-                                }
-
-                                if !name_to_pos.contains_key(&star.star_id) {
-                                    name_to_pos.insert(
-                                        star.star_id.clone(),
-                                        stars_l.len(),
-                                    );
-
-                                    let star = Star {
-                                        id: star.star_id.clone(),
-                                        uid: star.star_id.clone(),
-                                        star_type: StarType::Unknown,
-                                        model_type: StarModelType::None,
-                                        model: parse_model(
-                                            StarModelType::None,
-                                            "".to_string(),
-                                        ),
-                                        sample_rate: 15,
-                                        samples: None,
-                                        samples_tick_index: RefCell::new(0),
-                                    };
-
-                                    stars_l.push(
-                                        SWStar::new()
-                                            .set_star(star)
-                                            .set_availables(
-                                                self.detector_opts.fragment,
-                                                self.detector_opts.skip_delta,
-                                            )
-                                            .set_max_buffer_len(100)
-                                            .set_window_lens(
-                                                self.detector_opts
-                                                    .window_length
-                                                    .0
-                                                    as u32,
-                                                self.detector_opts
-                                                    .window_length
-                                                    .1
-                                                    as u32,
-                                            )
-                                            .build(),
-                                    );
-                                }
-
-                                stars_l[name_to_pos[&star.star_id]]
-                                    .tick(star.mag);
-                                tot_stars += 1;
-                            }
-                        }
-                    }
+                sw.set_watched(watched);
 
-                    debug!(log, ""; "tot_stars_this_read"=>tot_stars.to_string());
+                if !sw.is_ready() {
+                    return None;
+                }
+
+                Some(Candidate {
+                    idx,
+                    watched,
+                    overdue: sw.overdue(),
+                    ops: self.window_lens(sw.cur_window_len() as usize).len(),
+                })
+            })
+            .collect::<Vec<Candidate>>();
+        let (selected, filter_status) = self.scheduler.schedule(candidates);
+        self.info_handler.set_filter_status(filter_status);
+
+        // NOTE names and windows are collected together as a ready
+        //      star can still hold back its window (missing frames)
+        let mut names = Vec::new();
+        let mut windows = Vec::new();
+        let mut window_lens = Vec::new();
+        let mut positions = HashMap::new();
+        let mut predict_names = Vec::new();
+        let mut predict_reqs = Vec::new();
+        selected.into_iter().for_each(|idx| {
+            let sw = &self.stars[idx];
+            if let Some(window) = sw.window() {
+                if let Some(position) = sw.star.position {
+                    positions.insert(sw.star.uid.clone(), position);
+                }
+
+                if let Some(predictor) = sw.star.model.plugin_predictor() {
+                    predict_names.push(sw.star.uid.clone());
+                    predict_reqs.push(PredictRequest {
+                        predictor,
+                        look_backs: vec![window.to_vec()],
+                        times: vec![tick as f32],
+                    });
+                }
+
+                // NOTE the baseline is fit on the whole window, every
+                //      scale is then the newest part of the residual
+                let residual = if self.detector_opts.residual {
+                    sw.star.model.baseline(&window).map(|baseline| {
+                        window
+                            .iter()
+                            .zip(baseline)
+                            .map(|(observed, predicted)| observed - predicted)
+                            .collect::<Vec<f32>>()
+                    })
                 } else {
-                    // NOTE offline data handling
-                    stars_l.iter().for_each(|sw| {
-                        if let Some(samps) = sw.star.samples.as_ref() {
-                            let tick_index =
-                                { *sw.star.samples_tick_index.borrow() };
-
-                            if tick_index < samps.len() {
-                                sw.tick(samps[tick_index]);
-                                iterations += 1;
-                                sw.star
-                                    .samples_tick_index
-                                    .replace(tick_index + 1);
-                            }
-                        }
+                    None
+                };
+
+                for len in self.window_lens(window.len()) {
+                    names.push(sw.star.uid.clone());
+                    // NOTE the only copy, the snapshot outlives the ring buffer view
+                    windows.push(match residual.as_ref() {
+                        Some(residual) => residual[residual.len() - len..].to_vec(),
+                        None => window.scale(len).to_vec(),
                     });
-                    match self.iterations_chan_tx.send(iterations).await {
-                        _ => (), // NOTE for now ignore err b/c non-essential
-                    };
+                    window_lens.push(len);
                 }
             }
+        });
 
+        let snapshot = WindowSnapshot {
+            tick,
+            predictions: HashMap::new(),
+            names,
+            windows,
+            window_lens,
+            positions,
+            skip_filter,
+            evicted,
+        };
+
+        (snapshot, predict_names, predict_reqs)
+    }
+
+    // NOTE offline data handling
+    fn tick_offline(&mut self) -> usize {
+        let mut iterations = 0;
+        self.stars.iter().for_each(|sw| {
+            if let Some(samps) = sw.star.samples.as_ref() {
+                let tick_index = sw.star.samples_tick_index.load(Ordering::Relaxed);
+
+                if tick_index < samps.len() {
+                    sw.tick(samps[tick_index]);
+                    iterations += 1;
+                    sw.star.samples_tick_index.store(tick_index + 1, Ordering::Relaxed);
+                }
+            }
+        });
+
+        iterations
+    }
+
+    /// Runs until the data runs out, the detector stops or the program shuts down.
+    ///
+    /// Dropping the snapshot sender on return lets the detector finish
+    /// the snapshots still queued.
+    pub async fn tick(&mut self) {
+        let log = log::get_root_logger();
+        let sd_rx = self.info_handler.get_shutdown_receiver();
+        // NOTE star ids are only unique within a ccd
+        let mut name_to_pos: HashMap<(String, String), usize> = HashMap::new();
+        let mut live_stars: Vec<LiveStar> = Vec::new();
+        // NOTE frames ticked so far per camera
+        let mut camera_frames = vec![0; self.gwac_cameras.len()];
+        let mut lagging = false;
+        let mut tick = 0;
+
+        let frame_queue = self.gwac_rx_chan.take().map(|gwac_rx| {
+            let frame_queue = Arc::new(FrameQueue::new(
+                self.detector_opts.buffer_policy,
+                self.detector_opts.max_queued_frames,
+            ));
+            {
+                let frame_queue = frame_queue.clone();
+                let cameras = self.gwac_cameras.clone();
+                let info_handler = self.info_handler.clone();
+                tokio::spawn(async move {
+                    frame_queue.fill(gwac_rx, cameras, info_handler).await;
+                });
+            }
+            frame_queue
+        });
+        let mut pushed_rx = frame_queue.as_ref().map(|frame_queue| frame_queue.subscribe());
+        loop {
             if *sd_rx.get_ref() {
                 info!(log, "Ticker received finished signal...");
                 return;
             }
 
-            // FIXME Ending early with a Ctrl-C might cause
-            // a crash after going through all the produced plots
-            // b/c it is waiting here. We could fix this be temporary
-            // un-polling this wait and checking sd_rx. For now we will
-            // ignore this. It will work properly for a non-forced shutdown.
-            match self.tick_end.wait().await {
-                Err(msg) => {
-                    if *sd_rx.get_ref() {
+            let (skip_filter, evicted) = if let (Some(frame_queue), Some(pushed_rx)) =
+                (frame_queue.as_ref(), pushed_rx.as_mut())
+            {
+                // NOTE online data handling
+                // NOTE a tick takes one frame of every camera off the queue,
+                //      or ends early when a camera's next frame comes up
+                //      before the others caught up (so a lagging or
+                //      disconnected camera does not hold up the rest)
+                let mut frame_done = vec![false; self.gwac_cameras.len()];
+                let mut skip_filter = false;
+                let mut tot_stars = 0;
+                let mut evicted = Vec::new();
+                loop {
+                    let next = match with_shutdown(
+                        frame_queue.next_frame(&frame_done, &mut *pushed_rx),
+                        sd_rx.clone(),
+                    )
+                    .await
+                    {
+                        Some(next) => next,
+                        None => {
+                            info!(log, "Ticker received finished signal...");
+                            return;
+                        }
+                    };
+
+                    let (frame, status) = match next {
+                        NextFrame::Frame(frame, status) => (frame, status),
+                        NextFrame::CameraAhead => break,
+                        // NOTE all cameras are done for the night
+                        NextFrame::Closed => {
+                            info!(log, "GWAC sources closed, ticker finished...");
+                            return;
+                        }
+                    };
+                    frame_done[frame.camera] = true;
+
+                    if status.frames_behind > self.detector_opts.lag_warn_frames {
+                        if !lagging {
+                            warn!(log, "Detector is falling behind the GWAC cameras.";
+                                  "frames_behind"=>status.frames_behind,
+                                  "lag"=>format!("{:?}", status.lag));
+                        }
+                        lagging = true;
+                    } else if lagging {
+                        info!(log, "Detector caught up with the GWAC cameras.");
+                        lagging = false;
+                    }
+                    skip_filter |= frame_queue.should_skip_filter(&status);
+                    self.info_handler.set_queue_status(status);
+
+                    camera_frames[frame.camera] += 1;
+                    let frame_num = camera_frames[frame.camera];
+
+                    for star in frame.stars {
+                        let key = (star.ccd_num.clone(), star.star_id.clone());
+                        if !name_to_pos.contains_key(&key) {
+                            name_to_pos.insert(key.clone(), self.stars.len());
+
+                            let star = Star {
+                                id: star.star_id.clone(),
+                                uid: format!("{},{}", star.star_id, star.ccd_num),
+                                star_type: StarType::Unknown,
+                                model_type: StarModelType::None,
+                                model: parse_model(
+                                    StarModelType::None,
+                                    "".to_string(),
+                                ),
+                                // NOTE estimated from the timestamps later
+                                sample_rate: GWAC_SAMPLE_RATE,
+                                samples: None,
+                                samples_tick_index: AtomicUsize::new(0),
+                                position: None,
+                            };
+
+                            self.stars.push(
+                                SWStar::new()
+                                    .set_star(star)
+                                    .set_availables(
+                                        self.detector_opts.fragment,
+                                        self.detector_opts.skip_delta,
+                                    )
+                                    .set_max_buffer_len(
+                                        self.detector_opts.window_length.1 as u32,
+                                    )
+                                    .set_window_lens(
+                                        self.detector_opts
+                                            .window_length
+                                            .0
+                                            as u32,
+                                        self.detector_opts
+                                            .window_length
+                                            .1
+                                            as u32,
+                                    )
+                                    .build(),
+                            );
+                            live_stars.push(LiveStar::new(
+                                key.clone(),
+                                frame.camera,
+                                frame_num,
+                            ));
+                        }
+
+                        let pos = name_to_pos[&key];
+                        self.stars[pos].star.position = Some(StarPosition {
+                            ra: star.ra,
+                            dec: star.dec,
+                            xpix: star.xpix,
+                            ypix: star.ypix,
+                        });
+                        live_stars[pos].last_seen = frame_num;
+                        live_stars[pos]
+                            .samples(
+                                &mut self.stars[pos],
+                                star.timestamp,
+                                star.mag,
+                                self.detector_opts.template_sample_rate,
+                                self.detector_opts.cadence_mismatch,
+                            )
+                            .into_iter()
+                            .for_each(|val| self.stars[pos].tick(val));
+                        tot_stars += 1;
+                    }
+
+                    evicted.extend(handle_missing_stars(
+                        &mut self.stars,
+                        &mut live_stars,
+                        &mut name_to_pos,
+                        frame.camera,
+                        frame_num,
+                        self.detector_opts.missing_frame_policy,
+                        self.detector_opts.evict_after,
+                    ));
+
+                    if frame_done.iter().all(|done| *done) {
+                        break;
+                    }
+                }
+
+                if skip_filter {
+                    debug!(log, "Skipping filtering of lagging frame.");
+                }
+
+                debug!(log, "";
+                       "tot_stars_this_read"=>tot_stars.to_string(),
+                       "evicted_stars"=>evicted.len(),
+                       "live_stars"=>self.stars.len());
+                match self.iterations_chan_tx.send(tot_stars).await {
+                    _ => (), // NOTE for now ignore err b/c non-essential
+                };
+
+                (skip_filter, evicted)
+            } else {
+                let iterations = self.tick_offline();
+                if iterations == 0 {
+                    info!(log, "Stars out of samples, ticker finished...");
+                    return;
+                }
+
+                match self.iterations_chan_tx.send(iterations).await {
+                    _ => (), // NOTE for now ignore err b/c non-essential
+                };
+
+                (false, Vec::new())
+            };
+
+            tick += 1;
+            let (mut snapshot, predict_names, predict_reqs) = self.snapshot(tick, skip_filter, evicted);
+
+            // NOTE one batch (round trip per plugin) for all the stars of the tick,
+            //      on the blocking pool as the plugins answer over blocking channels
+            if let Some(host) = python::plugin_host().filter(|_| !predict_reqs.is_empty()) {
+                let preds = blocking::run(move || host.predict_batch(predict_reqs));
+                match with_shutdown(preds, sd_rx.clone()).await {
+                    Some(preds) => snapshot.predictions = predict_names.into_iter().zip(preds).collect(),
+                    None => {
                         info!(log, "Ticker received finished signal...");
                         return;
-                    } else {
-                        panic!(msg)
                     }
                 }
-                _ => ()
-            };
+            }
+
+            // NOTE blocks while the detector is the full lag behind
+            match with_shutdown(self.snapshot_tx.send(snapshot), sd_rx.clone()).await {
+                Some(Ok(_)) => (),
+                Some(Err(_)) => {
+                    info!(log, "Detector stopped, ticker finished...");
+                    return;
+                }
+                None => {
+                    info!(log, "Ticker received finished signal...");
+                    return;
+                }
+            }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gwac_reader::parse_star_line;
+
+    fn live_sw_star() -> SWStar {
+        let star = Star {
+            id: "star_a".to_string(),
+            uid: "star_a,rnd_ccd".to_string(),
+            star_type: StarType::Unknown,
+            model_type: StarModelType::None,
+            model: parse_model(StarModelType::None, "".to_string()),
+            sample_rate: GWAC_SAMPLE_RATE,
+            samples: None,
+            samples_tick_index: AtomicUsize::new(0),
+            position: None,
+        };
+
+        SWStar::new()
+            .set_star(star)
+            .set_availables(0, 1)
+            .set_window_lens(5, 10)
+            .set_max_buffer_len(10)
+            .build()
+    }
+
+    #[test]
+    fn test_mock_server_cadence() {
+        let mut sw = live_sw_star();
+        let mut live = LiveStar::new(("rnd_ccd".to_string(), "star_a".to_string()), 0, 1);
+
+        // NOTE star lines as sent by scripts/gwac_mock_server.py,
+        //      epoch timestamps every GWAC_SAMPLE_RATE seconds
+        let start = 1_760_800_000.25;
+        for i in 0..2 * CADENCE_SAMPLES {
+            let line = format!(
+                "64.1 63.2 62.3 61.4 zone_defense star_a {} {:.3} 59.6 rnd_ccd",
+                i as f32 * 0.5,
+                start + (i as i32 * GWAC_SAMPLE_RATE) as f64,
+            );
+            let data = parse_star_line(&line).unwrap();
+
+            let samples = live.samples(
+                &mut sw,
+                data.timestamp,
+                data.mag,
+                Some(GWAC_SAMPLE_RATE),
+                CadenceMismatch::Refuse,
+            );
+            assert_eq!(samples, vec![data.mag]);
+        }
+
+        assert!(live.cadence_checked);
+        assert!(!live.refused);
+        assert_eq!(sw.star.sample_rate, GWAC_SAMPLE_RATE);
+    }
+}
@@ -1,14 +1,26 @@
 use crate::star::{parse_model, Star, StarModelType, StarType};
 use crate::utils;
+use std::collections::HashMap;
+use std::path::Path;
 use std::{fs, io::Read};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 pub struct StarToml {
     pub id: String,
     pub star_type: String,
     pub samples: String,
     pub sample_rate: i32,
     pub arima_model_file: String,
+    /// baseline model of the star, only "arima" (reads arima_model_file),
+    /// none (or the plugin predictor) by default
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub model_type: Option<String>,
+    /// predictor of a python plugin (see mf_config.toml) modelling the star
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub predictor: Option<String>,
+    /// arguments for building the predictor (arima_model_file is always passed)
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub predictor_args: HashMap<String, String>,
 }
 
 pub fn parse_star_file(star_file: &str) -> Star {
@@ -39,14 +51,71 @@ pub fn parse_star_file(star_file: &str) -> Star {
             .expect("Failed to deserialize Star samples file")
     };
 
+    let arima_model_file =
+        utils::normalize_local_data_paths(&star_file, &star_toml.arima_model_file);
+    let (model_type, model) = match (star_toml.model_type.as_deref(), star_toml.predictor) {
+        (Some("arima"), _) => (
+            StarModelType::Arima,
+            parse_model(StarModelType::Arima, arima_model_file),
+        ),
+        (Some(other), _) => panic!(
+            "Unknown model_type {} of {} (expected arima)",
+            other, star_file
+        ),
+        (None, Some(predictor)) => {
+            let model = parse_model(StarModelType::Plugin, predictor);
+            let mut args = star_toml.predictor_args;
+            args.insert("arima_model_file".to_string(), arima_model_file);
+            if let Err(err) = model.init(args) {
+                panic!("Failed to init star model of {}: {:?}", star_file, err);
+            }
+
+            (StarModelType::Plugin, model)
+        }
+        (None, None) => (
+            StarModelType::None,
+            parse_model(StarModelType::None, "".to_string()),
+        ),
+    };
+
     Star {
         id: star_toml.id.clone(),
         uid: star_toml.id + "," + &star_file.to_string(),
         samples: Some(samples),
-        samples_tick_index: std::cell::RefCell::new(0),
+        samples_tick_index: std::sync::atomic::AtomicUsize::new(0),
+        position: None,
         star_type,
-        model_type: StarModelType::None,
-        model: parse_model(StarModelType::None, "".to_string()),
+        model_type,
+        model,
         sample_rate: star_toml.sample_rate,
     }
 }
+
+/// Writes a star as a star file (`<id>.toml`) and its samples (`<id>.mpk`)
+/// into the directory.
+///
+/// NOTE star models are not written, the star is written without a model
+pub fn write_star_file(star: &Star, dir: &Path) {
+    let name = utils::star_file_name(&star.id);
+    let samples_file = format!("{}.mpk", name);
+
+    let star_toml = StarToml {
+        id: star.id.clone(),
+        star_type: star.star_type.as_str().to_string(),
+        samples: samples_file.clone(),
+        sample_rate: star.sample_rate,
+        arima_model_file: "".to_string(),
+        model_type: None,
+        predictor: None,
+        predictor_args: HashMap::new(),
+    };
+
+    let samples = rmp_serde::to_vec(star.samples.as_ref().expect("Star has no samples to write"))
+        .expect("Failed to serialize Star samples");
+    fs::write(dir.join(&samples_file), samples)
+        .unwrap_or_else(|_| panic!("Failed to write Star samples file of {}", star.id));
+
+    let contents = toml::to_string(&star_toml).expect("Failed to serialize Star TOML file");
+    fs::write(dir.join(format!("{}.toml", name)), contents)
+        .unwrap_or_else(|_| panic!("Failed to write Star TOML file of {}", star.id));
+}
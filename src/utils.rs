@@ -153,3 +153,9 @@ pub fn normalize_local_data_paths(star_file: &str, data_file: &str) -> String {
         None => data_file.to_string(),
     }
 }
+
+// file name (without extension) a star is written to, star ids can
+// contain path separators (ex. the path of a .dat file)
+pub fn star_file_name(id: &str) -> String {
+    id.replace(|c: char| c == '/' || c == '\\', "_")
+}
@@ -0,0 +1,321 @@
+use crate::cyclic_queue::{CyclicQueue, CyclicQueueInterface};
+use std::collections::HashMap;
+use std::fmt;
+
+arg_enum! {
+    #[derive(Clone, Copy)]
+    pub enum VetoImps {
+        NoneVeto,
+        DerivativeVeto,
+    }
+}
+
+/// Reason a candidate was vetoed (used for logging).
+pub enum VetoReason {
+    /// window contains a jump that is immediately reversed
+    SingleFrameArtifact { sigma: f32 },
+    /// newest sample alone explains the jump in the filter output
+    NewestFrameArtifact { sigma: f32, score_jump: f32 },
+    /// sharp rise followed by a peak and decay
+    FlareRise { sigma: f32, step_fraction: f32 },
+}
+
+impl fmt::Display for VetoReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VetoReason::SingleFrameArtifact { sigma } => {
+                write!(f, "single frame artifact (jump of {:.2} sigma reversed next frame)", sigma)
+            }
+            VetoReason::NewestFrameArtifact { sigma, score_jump } => {
+                write!(f, "newest frame artifact (jump of {:.2} sigma, score jump of {:.2} sigma)",
+                       sigma, score_jump)
+            }
+            VetoReason::FlareRise { sigma, step_fraction } => {
+                write!(f, "flare like rise (step of {:.2} sigma, {:.0}% of window range)",
+                       sigma, step_fraction * 100.0)
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct VetoOpts {
+    /// Size (in noise sigmas) of a single frame jump to be considered an artifact.
+    pub spike_sigma: f32,
+    /// Size (in noise sigmas) of a single step to be considered a flare rise.
+    pub flare_sigma: f32,
+    /// Fraction of the window range a single step must account for to be a flare rise.
+    pub flare_step_fraction: f32,
+    /// Number of previous filter values kept per star for the score features.
+    pub score_history_len: usize,
+}
+
+impl Default for VetoOpts {
+    fn default() -> VetoOpts {
+        VetoOpts {
+            spike_sigma: 8.0,
+            flare_sigma: 5.0,
+            flare_step_fraction: 0.5,
+            score_history_len: 8,
+        }
+    }
+}
+
+/// Derivative based features of a star window.
+///
+/// All sigma values are relative to the noise level of the window
+/// estimated from the median absolute first difference.
+#[derive(Debug)]
+pub struct WindowFeatures {
+    /// largest single frame jump that is reversed in the next frame
+    pub max_reversed_jump: f32,
+    /// jump into the newest sample
+    pub newest_jump: f32,
+    /// largest single step
+    pub max_step: f32,
+    /// index of the largest single step (into the first difference)
+    pub max_step_pos: usize,
+    /// fraction of the window range accounted for by the largest step
+    pub step_fraction: f32,
+    /// index of the window maximum
+    pub peak_pos: usize,
+}
+
+impl WindowFeatures {
+    pub fn new(window: &[f32]) -> Option<WindowFeatures> {
+        if window.len() < 4 {
+            return None;
+        }
+
+        let diffs = window
+            .windows(2)
+            .map(|pair| pair[1] - pair[0])
+            .collect::<Vec<f32>>();
+
+        let noise = {
+            let mut abs_diffs = diffs.iter().map(|diff| diff.abs()).collect::<Vec<f32>>();
+            // NOTE total order so a NaN sample (sorted last) does not panic
+            abs_diffs.sort_unstable_by(|a, b| a.total_cmp(b));
+            // NOTE keep away from zero for flat (simulated) windows
+            abs_diffs[abs_diffs.len() / 2].max(std::f32::EPSILON)
+        };
+
+        // NOTE a single frame artifact is a jump immediately followed by
+        //      a jump of the opposite sign that undoes most of it
+        //      (second difference spike), flares decay much slower
+        let max_reversed_jump = diffs
+            .windows(2)
+            .map(|pair| {
+                let smaller = pair[0].abs().min(pair[1].abs());
+                let larger = pair[0].abs().max(pair[1].abs());
+                if pair[0].signum() != pair[1].signum() && smaller >= 0.5 * larger {
+                    smaller
+                } else {
+                    0.0
+                }
+            })
+            .fold(0.0f32, f32::max);
+
+        let (max_step_pos, max_step) = diffs.iter().cloned().enumerate().fold(
+            (0, std::f32::NEG_INFINITY),
+            |acc, (i, diff)| if diff > acc.1 { (i, diff) } else { acc },
+        );
+
+        let (peak_pos, peak) = window.iter().cloned().enumerate().fold(
+            (0, std::f32::NEG_INFINITY),
+            |acc, (i, val)| if val > acc.1 { (i, val) } else { acc },
+        );
+        let min = window.iter().cloned().fold(std::f32::INFINITY, f32::min);
+        let range = (peak - min).max(std::f32::EPSILON);
+
+        Some(WindowFeatures {
+            max_reversed_jump: max_reversed_jump / noise,
+            newest_jump: diffs[diffs.len() - 1] / noise,
+            max_step: max_step / noise,
+            max_step_pos,
+            step_fraction: max_step / range,
+            peak_pos,
+        })
+    }
+}
+
+/// Jump of the newest filter value over the previous ones (in their sigmas).
+pub fn score_jump(prev_scores: &[f32], val: f32) -> f32 {
+    if prev_scores.len() < 2 {
+        return 0.0;
+    }
+
+    let len = prev_scores.len() as f32;
+    let mean = prev_scores.iter().sum::<f32>() / len;
+    let var = prev_scores.iter().map(|x| (x - mean).powf(2.0)).sum::<f32>() / (len - 1.0);
+
+    (val - mean) / var.sqrt().max(std::f32::EPSILON)
+}
+
+/// Post filter stage that can veto candidates before they reach the detector trigger.
+///
+/// Called with every filter value so that implementations can keep
+/// histories, but only values above the threshold (candidates) can be vetoed.
+pub trait Veto {
+    fn check(&mut self, star: &str, window: &[f32], val: f32, threshold: f32)
+             -> Option<VetoReason>;
+
+    /// Drops the history kept for a star that is gone (evicted live star).
+    fn forget(&mut self, star: &str);
+}
+
+pub struct NoneVeto {}
+
+impl Veto for NoneVeto {
+    fn check(&mut self, _star: &str, _window: &[f32], _val: f32, _threshold: f32)
+             -> Option<VetoReason> {
+        None
+    }
+
+    fn forget(&mut self, _star: &str) {}
+}
+
+/// Vetoes candidates using the first differences of the star window
+/// and the filter output (see docs/images/1st-2nd-derivative-ml-and-flare).
+///
+/// - microlensing events rise smoothly over many frames
+/// - flares rise in one or two frames and then decay
+/// - single frame artifacts jump and fall back in the next frame
+pub struct DerivativeVeto {
+    opts: VetoOpts,
+    star_scores: HashMap<String, CyclicQueue<f32>>,
+}
+
+impl DerivativeVeto {
+    pub fn new(opts: VetoOpts) -> DerivativeVeto {
+        DerivativeVeto {
+            opts,
+            star_scores: HashMap::new(),
+        }
+    }
+
+    fn judge(&self, features: &WindowFeatures, score_jump: f32) -> Option<VetoReason> {
+        if features.max_reversed_jump > self.opts.spike_sigma {
+            return Some(VetoReason::SingleFrameArtifact {
+                sigma: features.max_reversed_jump,
+            });
+        }
+
+        // NOTE can not see if the newest frame is reversed so look for the
+        //      filter output jumping on the same frame instead
+        if features.newest_jump > self.opts.spike_sigma && score_jump > self.opts.spike_sigma {
+            return Some(VetoReason::NewestFrameArtifact {
+                sigma: features.newest_jump,
+                score_jump,
+            });
+        }
+
+        // NOTE peak right after the step means the rise was not gradual
+        if features.max_step > self.opts.flare_sigma
+            && features.step_fraction > self.opts.flare_step_fraction
+            && features.peak_pos <= features.max_step_pos + 2
+        {
+            return Some(VetoReason::FlareRise {
+                sigma: features.max_step,
+                step_fraction: features.step_fraction,
+            });
+        }
+
+        None
+    }
+}
+
+impl Veto for DerivativeVeto {
+    fn check(&mut self, star: &str, window: &[f32], val: f32, threshold: f32)
+             -> Option<VetoReason> {
+        let history_len = self.opts.score_history_len;
+        let scores = self.star_scores.entry(star.to_string())
+            .or_insert_with(|| CyclicQueue::new(history_len));
+
+        let prev_scores = (0..scores.len())
+            .map(|i| {
+                *scores.get_relative(i)
+                    .expect("length check and range should make this impossible")
+            })
+            .collect::<Vec<f32>>();
+        scores.push(val);
+
+        if val <= threshold {
+            return None;
+        }
+
+        let features = WindowFeatures::new(window)?;
+
+        self.judge(&features, score_jump(&prev_scores, val))
+    }
+
+    fn forget(&mut self, star: &str) {
+        self.star_scores.remove(star);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // NOTE small alternating noise so the noise level is not zero
+    fn noisy(vals: Vec<f32>) -> Vec<f32> {
+        vals.into_iter()
+            .enumerate()
+            .map(|(i, val)| if i % 2 == 0 { val + 0.1 } else { val - 0.1 })
+            .collect()
+    }
+
+    fn veto_reason(window: Vec<f32>) -> Option<VetoReason> {
+        let veto = DerivativeVeto::new(VetoOpts::default());
+        let features = WindowFeatures::new(&noisy(window)).unwrap();
+
+        veto.judge(&features, 0.0)
+    }
+
+    #[test]
+    fn test_gradual_rise_passes() {
+        let window = (0..20).map(|i| i as f32 * 0.5).collect();
+
+        assert!(veto_reason(window).is_none());
+    }
+
+    #[test]
+    fn test_single_frame_artifact() {
+        let mut window = vec![0.0; 20];
+        window[10] = 10.0;
+
+        match veto_reason(window) {
+            Some(VetoReason::SingleFrameArtifact { .. }) => (),
+            _ => panic!("Expected a single frame artifact veto."),
+        }
+    }
+
+    #[test]
+    fn test_flare_rise() {
+        let mut window = vec![0.0; 20];
+        for i in 12..20 {
+            window[i] = 10.0 * (0.7f32).powi(i as i32 - 12);
+        }
+
+        match veto_reason(window) {
+            Some(VetoReason::FlareRise { .. }) => (),
+            _ => panic!("Expected a flare rise veto."),
+        }
+    }
+
+    #[test]
+    fn test_nan_window() {
+        let mut window = noisy(vec![1.0; 20]);
+        window[5] = std::f32::NAN;
+
+        assert!(WindowFeatures::new(&window).is_some());
+    }
+
+    #[test]
+    fn test_score_jump() {
+        assert_eq!(score_jump(&[1.0], 10.0), 0.0);
+        assert!(score_jump(&[1.0, 1.1, 0.9, 1.0], 10.0) > 8.0);
+        assert!(score_jump(&[1.0, 2.0, 3.0, 4.0], 5.0) < 8.0);
+    }
+}